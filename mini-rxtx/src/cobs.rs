@@ -0,0 +1,117 @@
+//! Consistent Overhead Byte Stuffing
+//!
+//! The encoded data never contains a zero byte, so zero is used as the frame
+//! delimiter. Both functions work in place to avoid a second buffer on the
+//! device.
+
+/// The maximum number of bytes added when encoding `len` bytes.
+#[inline]
+pub fn max_overhead(len: usize) -> usize {
+    1 + len / 254
+}
+
+/// Encode `len` bytes starting at `buf[src]` into the start of `buf`.
+///
+/// `src` must be at least `max_overhead(len)`. Returns the number of encoded
+/// bytes.
+pub fn encode_in_place(buf: &mut [u8], src: usize, len: usize) -> usize {
+    debug_assert!(src >= max_overhead(len));
+    let mut code_idx = 0;
+    let mut write = 1;
+    let mut code: u8 = 1;
+    for read in src..(src+len) {
+        let byte = buf[read];
+        if byte == 0 {
+            buf[code_idx] = code;
+            code_idx = write;
+            write += 1;
+            code = 1;
+        } else {
+            buf[write] = byte;
+            write += 1;
+            code += 1;
+            if code == 0xFF {
+                buf[code_idx] = code;
+                code_idx = write;
+                write += 1;
+                code = 1;
+            }
+        }
+    }
+    buf[code_idx] = code;
+    write
+}
+
+/// Decode `buf` (without the delimiter) in place.
+///
+/// Returns the number of decoded bytes or `None` if the data is not valid
+/// COBS.
+pub fn decode_in_place(buf: &mut [u8]) -> Option<usize> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        if code == 0 {
+            return None;
+        }
+        read += 1;
+        let end = read + code - 1;
+        if end > buf.len() {
+            return None;
+        }
+        buf.copy_within(read..end, write);
+        write += end - read;
+        read = end;
+        if code != 0xFF && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+    Some(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) {
+        let mut buf = [0xAA; 1100];
+        let offset = max_overhead(data.len());
+        buf[offset..offset+data.len()].copy_from_slice(data);
+        let n_encoded = encode_in_place(&mut buf, offset, data.len());
+        let encoded = &mut buf[..n_encoded];
+        assert!(encoded.iter().all(|b| *b != 0));
+        let n_decoded = decode_in_place(encoded).unwrap();
+        assert_eq!(&encoded[..n_decoded], data);
+    }
+
+    #[test]
+    fn test_known_encoding() {
+        let mut buf = [0, 0, 0x11, 0x22, 0x00, 0x33];
+        let n = encode_in_place(&mut buf, 2, 4);
+        assert_eq!(&buf[..n], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(&[]);
+        roundtrip(&[0]);
+        roundtrip(&[0, 0]);
+        roundtrip(&[1, 2, 3]);
+        let mut long = [0u8; 1000];
+        for (i, byte) in long.iter_mut().enumerate() {
+            *byte = (i % 255 + 1) as u8;
+        }
+        roundtrip(&long);
+        let mut mixed = [0u8; 1000];
+        for (i, byte) in mixed.iter_mut().enumerate() {
+            *byte = (i % 7) as u8;
+        }
+        roundtrip(&mixed);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(decode_in_place(&mut [0x05, 0x01]), None);
+    }
+}
@@ -0,0 +1,35 @@
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF).
+///
+/// This is computed bitwise rather than with a lookup table to keep the
+/// flash footprint small. At serial data rates the speed is irrelevant.
+pub const INITIAL: u16 = 0xFFFF;
+
+/// Update a running checksum with more bytes.
+#[inline]
+pub fn update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for byte in bytes.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Compute the checksum of a complete buffer.
+#[inline]
+pub fn checksum(bytes: &[u8]) -> u16 {
+    update(INITIAL, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_check_value() {
+        assert_eq!(super::checksum(b"123456789"), 0x29B1);
+    }
+}
@@ -17,6 +17,7 @@ pub enum Decoded<T> {
 pub struct StdDecoder {
     buf: Vec<u8>,
     state: FramedReaderState,
+    dropped_frames: u32,
 }
 
 #[cfg(feature="std")]
@@ -24,7 +25,8 @@ impl StdDecoder {
     pub fn new(sz: usize) -> Self {
         Self {
             buf: vec![0; sz],
-            state: FramedReaderState::Empty,
+            state: FramedReaderState::initial(),
+            dropped_frames: 0,
         }
     }
 
@@ -34,8 +36,16 @@ impl StdDecoder {
     {
         let (new_state, decoded) = consume_inner(&mut self.state, &mut self.buf, byte);
         self.state = new_state;
+        if let Decoded::Error(_) = decoded {
+            self.dropped_frames = self.dropped_frames.wrapping_add(1);
+        }
         decoded
     }
+
+    /// The number of frames which were dropped because of errors.
+    pub fn dropped_frames(&self) -> u32 {
+        self.dropped_frames
+    }
 }
 
 /// A struct for decoding bytes.
@@ -45,13 +55,15 @@ impl StdDecoder {
 pub struct Decoder<'a> {
     buf: &'a mut [u8],
     state: FramedReaderState,
+    dropped_frames: u32,
 }
 
 impl<'a> Decoder<'a> {
     pub fn new(buf: &'a mut [u8]) -> Self {
         Self {
             buf,
-            state: FramedReaderState::Empty,
+            state: FramedReaderState::initial(),
+            dropped_frames: 0,
         }
     }
 
@@ -61,10 +73,93 @@ impl<'a> Decoder<'a> {
     {
         let (new_state, decoded) = consume_inner(&mut self.state, &mut self.buf, byte);
         self.state = new_state;
+        if let Decoded::Error(_) = decoded {
+            self.dropped_frames = self.dropped_frames.wrapping_add(1);
+        }
         decoded
     }
+
+    /// The number of frames which were dropped because of errors.
+    pub fn dropped_frames(&self) -> u32 {
+        self.dropped_frames
+    }
 }
 
+/// Decode frames which start with `SYNC_MARKER` and end with a checksum.
+///
+/// The whole frame, starting with the sync marker, is kept in the buffer
+/// until it is complete. When a frame is dropped, only its first byte is
+/// discarded and the following bytes are searched again for the start of the
+/// next frame. This way a corrupted length does not also lose the frames it
+/// swallowed. At most one frame is returned per call, so frames found in the
+/// buffered bytes are returned with the next bytes received.
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[inline]
+fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte: u8) -> (FramedReaderState, Decoded<T>)
+    where
+        for<'de> T: serde::de::Deserialize<'de>,
+{
+    let mut n_bytes = self_state.n_bytes;
+    debug_assert!(n_bytes < self_buf.len());
+    self_buf[n_bytes] = byte;
+    n_bytes += 1;
+
+    // Skip bytes until the start of a frame is found.
+    let start = find_sync_marker(&self_buf[0..n_bytes]);
+    self_buf.copy_within(start..n_bytes, 0);
+    n_bytes -= start;
+
+    if n_bytes < crate::HEADER_LEN {
+        return (FramedReaderState { n_bytes }, Decoded::FrameNotYetComplete);
+    }
+    let payload_len = ::byteorder::LittleEndian::read_u16(&self_buf[2..crate::HEADER_LEN]);
+    let frame_len = crate::HEADER_LEN + payload_len as usize + crate::TRAILER_LEN;
+    if frame_len > self_buf.len() {
+        let n_bytes = drop_sync_marker(self_buf, n_bytes);
+        return (FramedReaderState { n_bytes }, Decoded::Error(crate::Error::TooLong));
+    }
+    if n_bytes < frame_len {
+        return (FramedReaderState { n_bytes }, Decoded::FrameNotYetComplete);
+    }
+
+    match check_frame(&self_buf[0..frame_len]) {
+        Ok(payload) => {
+            let decoded = to_decoded(Ok(Some(payload)));
+            // Keep any bytes after the frame for the next call.
+            self_buf.copy_within(frame_len..n_bytes, 0);
+            (FramedReaderState { n_bytes: n_bytes - frame_len }, decoded)
+        }
+        Err(e) => {
+            let n_bytes = drop_sync_marker(self_buf, n_bytes);
+            (FramedReaderState { n_bytes }, Decoded::Error(e))
+        }
+    }
+}
+
+/// The index of the first possible start of a frame in `buf`.
+///
+/// A `SYNC_MARKER[0]` at the very end counts because the next byte may
+/// complete the marker. Returns `buf.len()` if there is none.
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[inline]
+fn find_sync_marker(buf: &[u8]) -> usize {
+    (0..buf.len())
+        .find(|&i| buf[i] == crate::SYNC_MARKER[0]
+            && (i+1 == buf.len() || buf[i+1] == crate::SYNC_MARKER[1]))
+        .unwrap_or(buf.len())
+}
+
+/// Discard the first byte of a bad frame so that the remaining bytes are
+/// searched for the next frame. Returns the number of bytes left.
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[inline]
+fn drop_sync_marker(buf: &mut [u8], n_bytes: usize) -> usize {
+    buf.copy_within(1..n_bytes, 0);
+    n_bytes - 1
+}
+
+/// Decode frames with a length prefix.
+#[cfg(not(any(feature="crc", feature="cobs")))]
 #[inline]
 fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte: u8) -> (FramedReaderState, Decoded<T>)
     where
@@ -74,11 +169,13 @@ fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte
         FramedReaderState::Empty => (FramedReaderState::ReadingHeader(byte), Ok(None)),
         FramedReaderState::ReadingHeader(byte0) => {
             let buf: [u8; 2] = [*byte0, byte];
-            let len = ::byteorder::LittleEndian::read_u16(&buf);
-            if (len as usize) > self_buf.len() {
+            let len = ::byteorder::LittleEndian::read_u16(&buf) as usize;
+            if len > self_buf.len() {
+                // Without a sync marker, the start of the next frame cannot
+                // be found, so the error is permanent.
                 (FramedReaderState::Error, Err(crate::Error::TooLong))
             } else {
-                let rms = ReadingMessageState { len: len, idx: 0 };
+                let rms = ReadingMessageState { len: len as u16, idx: 0 };
                 (FramedReaderState::ReadingMessage(rms), Ok(None))
             }
         }
@@ -93,8 +190,7 @@ fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte
                 };
                 (FramedReaderState::ReadingMessage(rms), Ok(None))
             } else if idx == msg_len {
-                let result = &self_buf[0..(idx as usize)];
-                (FramedReaderState::Empty, Ok(Some(result)))
+                (FramedReaderState::initial(), Ok(Some(&self_buf[0..(idx as usize)])))
             } else {
                 // Frame langer than expected.
                 // Theoretically it is impossible to get here, so we panic.
@@ -103,7 +199,15 @@ fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte
         }
         FramedReaderState::Error => (FramedReaderState::Error, Err(crate::Error::PreviousError)),
     };
-    let decoded = match result {
+    (new_state, to_decoded(result))
+}
+
+#[inline]
+fn to_decoded<T>(result: Result<Option<&[u8]>, crate::Error>) -> Decoded<T>
+    where
+        for<'de> T: serde::de::Deserialize<'de>,
+{
+    match result {
         Ok(Some(buf)) => {
             match ssmarshal::deserialize(buf) {
                 Ok((msg, _nbytes)) => Decoded::Msg(msg),
@@ -116,18 +220,130 @@ fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte
         Err(e) => {
             Decoded::Error(e)
         }
-    };
-    (new_state, decoded)
+    }
 }
 
+/// Verify the checksum of a complete frame and return its payload.
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[inline]
+fn check_frame(frame: &[u8]) -> Result<&[u8], crate::Error> {
+    let crc_start = frame.len() - crate::TRAILER_LEN;
+    // The checksum covers the length and the payload.
+    let crc = crate::crc16::checksum(&frame[2..crc_start]);
+    if crc == ::byteorder::LittleEndian::read_u16(&frame[crc_start..]) {
+        Ok(&frame[crate::HEADER_LEN..crc_start])
+    } else {
+        Err(crate::Error::BadChecksum)
+    }
+}
+
+#[cfg(all(feature="crc", not(feature="cobs")))]
+struct FramedReaderState {
+    /// The number of bytes in the buffer, starting with a (possibly
+    /// incomplete) sync marker.
+    n_bytes: usize,
+}
+
+#[cfg(all(feature="crc", not(feature="cobs")))]
+impl FramedReaderState {
+    /// The state when waiting for a new frame.
+    #[inline]
+    fn initial() -> Self {
+        FramedReaderState { n_bytes: 0 }
+    }
+}
+
+#[cfg(not(any(feature="crc", feature="cobs")))]
 struct ReadingMessageState {
     len: u16, // the length when full
     idx: u16, // the current length
 }
 
+#[cfg(not(any(feature="crc", feature="cobs")))]
 enum FramedReaderState {
     Empty,
     ReadingHeader(u8),
     ReadingMessage(ReadingMessageState),
     Error,
 }
+
+#[cfg(not(any(feature="crc", feature="cobs")))]
+impl FramedReaderState {
+    /// The state when waiting for a new frame.
+    #[inline]
+    fn initial() -> Self {
+        FramedReaderState::Empty
+    }
+}
+
+/// Decode COBS encoded frames which end with `DELIMITER`.
+///
+/// A reader joining mid-stream or seeing a corrupted frame drops the bytes up
+/// to the next delimiter and continues with the following frame.
+#[cfg(feature="cobs")]
+#[inline]
+fn consume_inner<T>(self_state: &mut FramedReaderState, self_buf: &mut[u8], byte: u8) -> (FramedReaderState, Decoded<T>)
+    where
+        for<'de> T: serde::de::Deserialize<'de>,
+{
+    let (new_state, result) = match self_state {
+        FramedReaderState::Accumulating(idx) => {
+            let idx = *idx;
+            if byte == crate::DELIMITER {
+                if idx == 0 {
+                    // Nothing between two delimiters.
+                    (FramedReaderState::initial(), Ok(None))
+                } else {
+                    let result = check_frame(&mut self_buf[0..idx]);
+                    (FramedReaderState::initial(), result.map(Some))
+                }
+            } else if idx < self_buf.len() {
+                self_buf[idx] = byte;
+                (FramedReaderState::Accumulating(idx+1), Ok(None))
+            } else {
+                (FramedReaderState::Overflow, Err(crate::Error::TooLong))
+            }
+        }
+        FramedReaderState::Overflow => {
+            if byte == crate::DELIMITER {
+                (FramedReaderState::initial(), Ok(None))
+            } else {
+                (FramedReaderState::Overflow, Ok(None))
+            }
+        }
+    };
+    (new_state, to_decoded(result))
+}
+
+/// Decode a complete frame in place, verify its checksum and return its
+/// payload.
+#[cfg(feature="cobs")]
+#[inline]
+fn check_frame(frame: &mut [u8]) -> Result<&[u8], crate::Error> {
+    let n_bytes = crate::cobs::decode_in_place(frame).ok_or(crate::Error::BadEncoding)?;
+    if n_bytes < crate::TRAILER_LEN {
+        return Err(crate::Error::BadEncoding);
+    }
+    let (payload, trailer) = frame[0..n_bytes].split_at(n_bytes - crate::TRAILER_LEN);
+    if crate::crc16::checksum(payload) != ::byteorder::LittleEndian::read_u16(trailer) {
+        return Err(crate::Error::BadChecksum);
+    }
+    Ok(payload)
+}
+
+#[cfg(feature="cobs")]
+enum FramedReaderState {
+    /// Reading bytes until the delimiter. Holds the number of bytes read.
+    Accumulating(usize),
+    /// The frame does not fit in the buffer. Skipping to the next delimiter.
+    Overflow,
+}
+
+#[cfg(feature="cobs")]
+impl FramedReaderState {
+    /// The state when waiting for a new frame.
+    #[inline]
+    fn initial() -> Self {
+        FramedReaderState::Accumulating(0)
+    }
+}
@@ -1,15 +1,42 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
 mod decoder;
+#[cfg(feature="crc")]
+mod crc16;
+#[cfg(feature="cobs")]
+mod cobs;
 
 pub use crate::decoder::{Decoder, Decoded};
 #[cfg(feature="std")]
 pub use crate::decoder::StdDecoder;
 
-use heapless::consts::U128;
+use heapless::consts::{U128, U512};
 use heapless::spsc::Queue;
 use byteorder::ByteOrder;
 
+/// Marker which precedes every frame when the `crc` feature is enabled.
+///
+/// The decoder searches for this marker to find the start of the next frame
+/// after a corrupted or truncated one.
+#[cfg(all(feature="crc", not(feature="cobs")))]
+pub const SYNC_MARKER: [u8; 2] = [0xA5, 0x5A];
+
+/// Delimiter which ends every frame when the `cobs` feature is enabled.
+#[cfg(feature="cobs")]
+pub const DELIMITER: u8 = 0x00;
+
+/// Number of bytes before the payload (sync marker and length).
+#[cfg(all(feature="crc", not(feature="cobs")))]
+pub(crate) const HEADER_LEN: usize = 4;
+#[cfg(not(any(feature="crc", feature="cobs")))]
+pub(crate) const HEADER_LEN: usize = 2;
+
+/// Number of bytes after the payload (checksum).
+#[cfg(feature="crc")]
+pub(crate) const TRAILER_LEN: usize = 2;
+#[cfg(not(feature="crc"))]
+pub(crate) const TRAILER_LEN: usize = 0;
+
 #[derive(Debug)]
 pub enum Error {
     SerializeError(ssmarshal::Error),
@@ -17,6 +44,8 @@ pub enum Error {
     PreviousError,
     Incomplete,
     ExtraCharactersFound,
+    BadChecksum,
+    BadEncoding,
 }
 
 impl From<ssmarshal::Error> for Error {
@@ -29,8 +58,9 @@ pub struct MiniTxRx<RX,TX> {
     rx: RX,
     tx: TX,
     in_bytes: Queue<u8, U128>,
-    tx_queue: Queue<u8, U128>,
+    tx_queue: Queue<u8, U512>,
     held_byte: Option<u8>,
+    rx_errors: u32,
 }
 
 impl<RX,TX> MiniTxRx<RX,TX>
@@ -49,6 +79,7 @@ impl<RX,TX> MiniTxRx<RX,TX>
             in_bytes: Queue::new(),
             tx_queue: Queue::new(),
             held_byte: None,
+            rx_errors: 0,
         }
     }
 
@@ -63,16 +94,36 @@ impl<RX,TX> MiniTxRx<RX,TX>
         self.in_bytes.dequeue()
     }
 
+    /// Queue a message for sending.
+    ///
+    /// Nothing is queued if the whole frame does not fit. On error, returns
+    /// the first byte of the frame.
     #[inline]
     pub fn send_msg(&mut self, m: SerializedMsg) ->Result<(), u8> {
         // Called with lock.
         let frame = &m.buf[0..m.total_bytes];
+        if frame.len() > self.tx_space() {
+            return Err(frame[0]);
+        }
         for byte in frame.iter() {
             self.tx_queue.enqueue(*byte)?;
         }
         Ok(())
     }
 
+    /// The number of bytes which can currently be queued for sending.
+    #[inline]
+    pub fn tx_space(&self) -> usize {
+        self.tx_queue.capacity() - self.tx_queue.len()
+    }
+
+    /// The number of receive errors (serial errors or bytes lost because the
+    /// input queue was full) since startup.
+    #[inline]
+    pub fn rx_errors(&self) -> u32 {
+        self.rx_errors
+    }
+
     // inner function called by pump_sender
     fn send_byte(&mut self, byte: u8) {
         debug_assert!(self.held_byte.is_none());
@@ -83,7 +134,10 @@ impl<RX,TX> MiniTxRx<RX,TX>
         }
     }
 
-    fn pump_sender(&mut self) {
+    /// Send the next queued byte if the serial port is ready, without
+    /// taking a received byte like `pump` does.
+    #[inline]
+    pub fn pump_sender(&mut self) {
         if let Some(byte) = self.held_byte.take() {
             self.send_byte(byte)
         }
@@ -104,11 +158,15 @@ impl<RX,TX> MiniTxRx<RX,TX>
         match self.rx.read() {
             Ok(byte) => {
                 // iprintln!(&mut resources.ITM.stim[0], "serial got byte {}", byte);
-                self.in_bytes.enqueue(byte).expect("failed to enqueue byte");
+                if self.in_bytes.enqueue(byte).is_err() {
+                    // The byte is lost. The decoder will drop the frame.
+                    self.rx_errors = self.rx_errors.wrapping_add(1);
+                }
             },
             Err(nb::Error::WouldBlock) => {}, // do nothing, probably task called because of Txe event
             Err(nb::Error::Other(_e)) => {
-                // We have a real error. We should do something here. But what?
+                // Count it, the decoder will drop the damaged frame.
+                self.rx_errors = self.rx_errors.wrapping_add(1);
             },
         }
 
@@ -130,14 +188,49 @@ impl<'a> SerializedMsg<'a> {
 ///
 /// This is not part of MiniTxRx itself because we do not want to require
 /// access to resources when encoding bytes.
+#[cfg(not(feature="cobs"))]
 #[inline]
 pub fn serialize_msg<'a,T: serde::ser::Serialize>(msg: &T, buf: &'a mut [u8]) -> Result<SerializedMsg<'a>,Error> {
-    let n_bytes = ssmarshal::serialize(&mut buf[2..], msg)?;
+    let payload_end = buf.len() - TRAILER_LEN;
+    let n_bytes = ssmarshal::serialize(&mut buf[HEADER_LEN..payload_end], msg)?;
     if n_bytes > u16::max_value() as usize {
         return Err(Error::TooLong);
     }
-    byteorder::LittleEndian::write_u16(&mut buf[0..2], n_bytes as u16);
-    Ok(SerializedMsg { buf, total_bytes: n_bytes+2 })
+    byteorder::LittleEndian::write_u16(&mut buf[HEADER_LEN-2..HEADER_LEN], n_bytes as u16);
+    #[cfg(feature="crc")]
+    {
+        buf[0..2].copy_from_slice(&SYNC_MARKER);
+        // The checksum covers the length and the payload.
+        let crc_start = HEADER_LEN+n_bytes;
+        let crc = crc16::checksum(&buf[HEADER_LEN-2..crc_start]);
+        byteorder::LittleEndian::write_u16(&mut buf[crc_start..crc_start+TRAILER_LEN], crc);
+    }
+    Ok(SerializedMsg { buf, total_bytes: HEADER_LEN+n_bytes+TRAILER_LEN })
+}
+
+/// Encode messages into a byte buffer.
+///
+/// The payload and checksum are COBS encoded and followed by `DELIMITER`.
+///
+/// This is not part of MiniTxRx itself because we do not want to require
+/// access to resources when encoding bytes.
+#[cfg(feature="cobs")]
+#[inline]
+pub fn serialize_msg<'a,T: serde::ser::Serialize>(msg: &T, buf: &'a mut [u8]) -> Result<SerializedMsg<'a>,Error> {
+    // Leave room at the start for the encoding overhead so that the
+    // encoding can be done in place.
+    let offset = cobs::max_overhead(buf.len());
+    if buf.len() < offset + TRAILER_LEN + 1 {
+        return Err(Error::TooLong);
+    }
+    let payload_end = buf.len() - TRAILER_LEN - 1;
+    let n_bytes = ssmarshal::serialize(&mut buf[offset..payload_end], msg)?;
+    let crc_start = offset+n_bytes;
+    let crc = crc16::checksum(&buf[offset..crc_start]);
+    byteorder::LittleEndian::write_u16(&mut buf[crc_start..crc_start+TRAILER_LEN], crc);
+    let n_encoded = cobs::encode_in_place(buf, offset, n_bytes+TRAILER_LEN);
+    buf[n_encoded] = DELIMITER;
+    Ok(SerializedMsg { buf, total_bytes: n_encoded+1 })
 }
 
 /// Encode messages into `Vec<u8>`
@@ -35,3 +35,198 @@ fn test_roundtrip_nostd() {
     let msg_actual = deserialize_owned_borrowed(&buf,&mut decode_buf).unwrap(); // requires cargo feature "std"
     assert_eq!(msg_orig, msg_actual);
 }
+
+#[cfg(any(feature="crc", feature="cobs"))]
+fn decode_all(buf: &[u8]) -> (Vec<MsgType>, u32) {
+    let mut decode_buf = [0; 1024];
+    let mut decoder = Decoder::new(&mut decode_buf);
+    let mut msgs = Vec::new();
+    for byte in buf.iter() {
+        if let Decoded::Msg(msg) = decoder.consume::<MsgType>(*byte) {
+            msgs.push(msg);
+        }
+    }
+    (msgs, decoder.dropped_frames())
+}
+
+#[cfg(any(feature="crc", feature="cobs"))]
+fn encode(a: u32) -> Vec<u8> {
+    let mut dest = vec![0; 1024];
+    serialize_msg(&MsgType{a},&mut dest).unwrap().framed_slice().to_vec()
+}
+
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[test]
+fn test_crc_skips_garbage() {
+    let mut buf = vec![0x01, SYNC_MARKER[0], 0x02, 0xff];
+    buf.extend(encode(1));
+    buf.extend(encode(2));
+
+    let (msgs, dropped) = decode_all(&buf);
+    assert_eq!(msgs, vec![MsgType{a: 1}, MsgType{a: 2}]);
+    assert_eq!(dropped, 0);
+}
+
+#[cfg(feature="crc")]
+#[test]
+fn test_crc_drops_corrupted_frame() {
+    let mut corrupted = encode(1);
+    let n = corrupted.len();
+    corrupted[n-3] ^= 0x10; // flip a payload bit
+
+    let mut buf = corrupted;
+    buf.extend(encode(2));
+    buf.extend(encode(3));
+
+    let (msgs, dropped) = decode_all(&buf);
+    assert_eq!(msgs, vec![MsgType{a: 2}, MsgType{a: 3}]);
+    assert_eq!(dropped, 1);
+}
+
+#[cfg(feature="crc")]
+#[test]
+fn test_crc_resyncs_after_truncated_frame() {
+    let mut truncated = encode(1);
+    truncated.truncate(5);
+
+    let mut buf = truncated;
+    for a in 2..10 {
+        buf.extend(encode(a));
+    }
+
+    // The truncated frame swallows the start of the next frame, which is
+    // found again when the checksum fails.
+    #[cfg(not(feature="cobs"))]
+    let first = 2;
+    // Without its delimiter, the truncated frame runs into the next one.
+    #[cfg(feature="cobs")]
+    let first = 3;
+    let (msgs, dropped) = decode_all(&buf);
+    assert_eq!(msgs, (first..10).map(|a| MsgType{a}).collect::<Vec<_>>());
+    assert_eq!(dropped, 1);
+}
+
+#[cfg(all(feature="crc", not(feature="cobs")))]
+#[test]
+fn test_crc_resyncs_after_corrupted_length() {
+    let mut corrupted = encode(1);
+    corrupted[2] = 60; // claims a payload longer than the next frames
+
+    let mut buf = corrupted;
+    for a in 2..10 {
+        buf.extend(encode(a));
+    }
+
+    // The complete frames swallowed by the bad length are not lost.
+    let (msgs, dropped) = decode_all(&buf);
+    assert_eq!(msgs, (2..10).map(|a| MsgType{a}).collect::<Vec<_>>());
+    assert_eq!(dropped, 1);
+}
+
+#[cfg(feature="cobs")]
+#[test]
+fn test_cobs_no_zero_before_delimiter() {
+    let buf = encode(0);
+    let (last, body) = buf.split_last().unwrap();
+    assert_eq!(*last, DELIMITER);
+    assert!(body.iter().all(|b| *b != DELIMITER));
+}
+
+#[cfg(feature="cobs")]
+#[test]
+fn test_cobs_join_mid_stream() {
+    let mut stream = Vec::new();
+    for a in 1..4 {
+        stream.extend(encode(a));
+    }
+    let first_len = encode(1).len();
+
+    // Start reading somewhere inside the first frame.
+    let (msgs, dropped) = decode_all(&stream[first_len/2..]);
+    assert_eq!(msgs, vec![MsgType{a: 2}, MsgType{a: 3}]);
+    assert!(dropped <= 1);
+}
+
+#[cfg(feature="cobs")]
+#[test]
+fn test_cobs_recovers_after_lost_byte() {
+    let mut lossy = encode(1);
+    lossy.remove(2);
+
+    let mut buf = lossy;
+    buf.extend(encode(2));
+    buf.extend(encode(3));
+
+    let (msgs, _dropped) = decode_all(&buf);
+    assert_eq!(&msgs[msgs.len()-2..], &[MsgType{a: 2}, MsgType{a: 3}]);
+}
+
+#[cfg(feature="cobs")]
+#[test]
+fn test_cobs_ignores_empty_frames() {
+    let mut buf = vec![DELIMITER, DELIMITER];
+    buf.extend(encode(1));
+    buf.push(DELIMITER);
+    buf.extend(encode(2));
+
+    let (msgs, dropped) = decode_all(&buf);
+    assert_eq!(msgs, vec![MsgType{a: 1}, MsgType{a: 2}]);
+    assert_eq!(dropped, 0);
+}
+
+/// Receives the given bytes, then reports errors.
+struct MockRx(std::vec::IntoIter<u8>);
+
+impl embedded_hal::serial::Read<u8> for MockRx {
+    type Error = ();
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.0.next().ok_or(nb::Error::Other(()))
+    }
+}
+
+/// Never accepts a byte, so everything stays queued.
+struct BlockedTx;
+
+impl embedded_hal::serial::Write<u8> for BlockedTx {
+    type Error = ();
+    fn write(&mut self, _byte: u8) -> nb::Result<(), ()> {
+        Err(nb::Error::WouldBlock)
+    }
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+#[test]
+fn test_send_msg_full_queue() {
+    let mut rxtx = MiniTxRx::new(BlockedTx, MockRx(vec![].into_iter()));
+    let mut dest = vec![0; 1024];
+    let msg = serialize_msg(&MsgType{a: 1}, &mut dest).unwrap();
+    let frame_len = msg.framed_slice().len();
+    let capacity = rxtx.tx_space();
+
+    let mut n_sent = 0;
+    while rxtx.send_msg(serialize_msg(&MsgType{a: 1}, &mut dest).unwrap()).is_ok() {
+        n_sent += 1;
+    }
+    assert!(n_sent > 0);
+    // A frame which does not fit is not partially queued.
+    assert!(rxtx.tx_space() < frame_len);
+    assert_eq!(rxtx.tx_space(), capacity - n_sent*frame_len);
+}
+
+#[test]
+fn test_rx_errors_counted() {
+    let n_bytes = 200; // more than fit in the input queue
+    let mut rxtx = MiniTxRx::new(BlockedTx, MockRx(vec![1; n_bytes].into_iter()));
+    for _ in 0..n_bytes+1 {
+        rxtx.on_interrupt();
+    }
+
+    let mut n_received = 0;
+    while rxtx.pump().is_some() {
+        n_received += 1;
+    }
+    // One byte for each failed enqueue and one for the serial error at the end.
+    assert_eq!(rxtx.rx_errors() as usize, n_bytes - n_received + 1);
+}
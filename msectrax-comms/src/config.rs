@@ -0,0 +1,206 @@
+//! Storage format of the `SetDeviceState` and headstage id saved in flash.
+//!
+//! The stored image is a header, the ssmarshal encoded `SetDeviceState` and a
+//! CRC-32 over both:
+//!
+//! | bytes | content                                     |
+//! |-------|---------------------------------------------|
+//! | 4     | `CONFIG_MAGIC`, little endian               |
+//! | 2     | `DATATYPES_VERSION` when saved, little endian |
+//! | 2     | payload length, little endian               |
+//! | 2     | headstage id, little endian                 |
+//! | n     | payload                                     |
+//! | 4     | CRC-32 of header and payload, little endian |
+//!
+//! A config saved by firmware with a different `DATATYPES_VERSION` is
+//! rejected because the encoding of `SetDeviceState` may have changed. The
+//! headstage id is still used (see `decode_headstage_id`), so a headstage
+//! keeps its id when new firmware is flashed.
+
+use crate::{SetDeviceState, DATATYPES_VERSION};
+
+/// Marks the start of a saved config. Erased flash reads as `0xFFFF_FFFF`.
+pub const CONFIG_MAGIC: u32 = 0x4d53_4354; // "MSCT"
+
+const HEADER_LEN: usize = 10;
+const CRC_LEN: usize = 4;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConfigError {
+    /// No config was saved (or the page was erased).
+    BadMagic = 1,
+    /// The config was saved by firmware with another `DATATYPES_VERSION`.
+    WrongVersion = 2,
+    /// The payload length does not fit the buffer.
+    BadLength = 3,
+    /// The stored data is corrupt.
+    BadChecksum = 4,
+    /// The payload is not a valid `SetDeviceState`.
+    BadPayload = 5,
+    /// The buffer is too small to encode the config.
+    BufferTooSmall = 6,
+}
+
+/// Encode `state` and `headstage_id` into `buf`, returning the number of bytes
+/// used.
+///
+/// `crate::MAX_MSG_SIZE` bytes are always enough.
+pub fn encode(state: &SetDeviceState, headstage_id: u16, buf: &mut [u8]) -> Result<usize, ConfigError> {
+    if buf.len() < HEADER_LEN + CRC_LEN {
+        return Err(ConfigError::BufferTooSmall);
+    }
+    let payload_end = buf.len() - CRC_LEN;
+    let n_payload = ssmarshal::serialize(&mut buf[HEADER_LEN..payload_end], state)
+        .map_err(|_| ConfigError::BufferTooSmall)?;
+    if n_payload > u16::MAX as usize {
+        return Err(ConfigError::BufferTooSmall);
+    }
+    buf[0..4].copy_from_slice(&CONFIG_MAGIC.to_le_bytes());
+    buf[4..6].copy_from_slice(&DATATYPES_VERSION.to_le_bytes());
+    buf[6..8].copy_from_slice(&(n_payload as u16).to_le_bytes());
+    buf[8..10].copy_from_slice(&headstage_id.to_le_bytes());
+    let crc_start = HEADER_LEN + n_payload;
+    let crc = crc32(&buf[..crc_start]);
+    buf[crc_start..crc_start + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+    Ok(crc_start + CRC_LEN)
+}
+
+/// Check and decode a config. Bytes after the stored image are ignored, so
+/// `buf` can be the whole flash page.
+pub fn decode(buf: &[u8]) -> Result<SetDeviceState, ConfigError> {
+    let payload = check(buf)?;
+    if u16::from_le_bytes([buf[4], buf[5]]) != DATATYPES_VERSION {
+        return Err(ConfigError::WrongVersion);
+    }
+    match ssmarshal::deserialize(payload) {
+        Ok((state, n_bytes)) if n_bytes == payload.len() => Ok(state),
+        _ => Err(ConfigError::BadPayload),
+    }
+}
+
+/// The headstage id of a stored config, also if it was saved by firmware
+/// with another `DATATYPES_VERSION`.
+pub fn decode_headstage_id(buf: &[u8]) -> Result<u16, ConfigError> {
+    check(buf)?;
+    Ok(u16::from_le_bytes([buf[8], buf[9]]))
+}
+
+/// Check the magic, length and checksum of a config and return its payload.
+fn check(buf: &[u8]) -> Result<&[u8], ConfigError> {
+    if buf.len() < HEADER_LEN + CRC_LEN {
+        return Err(ConfigError::BadLength);
+    }
+    if u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != CONFIG_MAGIC {
+        return Err(ConfigError::BadMagic);
+    }
+    let n_payload = u16::from_le_bytes([buf[6], buf[7]]) as usize;
+    let crc_start = HEADER_LEN + n_payload;
+    if crc_start + CRC_LEN > buf.len() {
+        return Err(ConfigError::BadLength);
+    }
+    let stored_crc = u32::from_le_bytes([buf[crc_start], buf[crc_start + 1],
+        buf[crc_start + 2], buf[crc_start + 3]]);
+    if crc32(&buf[..crc_start]) != stored_crc {
+        return Err(ConfigError::BadChecksum);
+    }
+    Ok(&buf[HEADER_LEN..crc_start])
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib).
+///
+/// Computed bitwise to keep the flash footprint small, this only runs when
+/// saving or loading.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceMode, ClosedLoopMode, MAX_MSG_SIZE};
+
+    fn test_state() -> SetDeviceState {
+        SetDeviceState {
+            mode: DeviceMode::ClosedLoop(ClosedLoopMode::Proportional),
+            dac1_initial: 123,
+            dac2_max: 20_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_config_roundtrip() {
+        let orig = test_state();
+        // Followed by erased flash.
+        let mut buf = [0xFF; 1024];
+        let n_bytes = encode(&orig, 2, &mut buf).unwrap();
+        assert!(n_bytes <= MAX_MSG_SIZE);
+        assert_eq!(decode(&buf[..n_bytes]), Ok(orig.clone()));
+        assert_eq!(decode(&buf), Ok(orig));
+        assert_eq!(decode_headstage_id(&buf), Ok(2));
+    }
+
+    #[test]
+    fn test_config_erased() {
+        assert_eq!(decode(&[0xFF; 1024]), Err(ConfigError::BadMagic));
+        assert_eq!(decode(&[0; 1024]), Err(ConfigError::BadMagic));
+        assert_eq!(decode(&[]), Err(ConfigError::BadLength));
+        assert_eq!(decode_headstage_id(&[0xFF; 1024]), Err(ConfigError::BadMagic));
+    }
+
+    #[test]
+    fn test_config_corrupt() {
+        let mut buf = [0xFF; 1024];
+        let n_bytes = encode(&test_state(), 2, &mut buf).unwrap();
+        for i in 8..n_bytes {
+            let mut corrupt = buf;
+            corrupt[i] ^= 0x01;
+            assert_eq!(decode(&corrupt), Err(ConfigError::BadChecksum), "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn test_config_wrong_version() {
+        let mut buf = [0xFF; 1024];
+        let n_bytes = encode(&test_state(), 3, &mut buf).unwrap();
+        buf[4..6].copy_from_slice(&(DATATYPES_VERSION - 1).to_le_bytes());
+        // As saved by the other firmware, with a valid checksum.
+        let crc = crc32(&buf[..n_bytes - CRC_LEN]);
+        buf[n_bytes - CRC_LEN..n_bytes].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(decode(&buf), Err(ConfigError::WrongVersion));
+        // The headstage id is kept across firmware updates.
+        assert_eq!(decode_headstage_id(&buf), Ok(3));
+    }
+
+    #[test]
+    fn test_config_bad_length() {
+        let mut buf = [0xFF; 1024];
+        let n_bytes = encode(&test_state(), 2, &mut buf).unwrap();
+        // Truncated.
+        assert_eq!(decode(&buf[..n_bytes - 1]), Err(ConfigError::BadLength));
+        buf[6..8].copy_from_slice(&2000u16.to_le_bytes());
+        assert_eq!(decode(&buf), Err(ConfigError::BadLength));
+    }
+
+    #[test]
+    fn test_config_buffer_too_small() {
+        let mut buf = [0; 8];
+        assert_eq!(encode(&test_state(), 2, &mut buf), Err(ConfigError::BufferTooSmall));
+    }
+}
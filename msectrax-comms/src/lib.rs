@@ -4,24 +4,109 @@
 extern crate serde_derive;
 extern crate serde;
 
+pub mod config;
+
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
 
 pub const BPS_HZ: u32 = 115_200; // faster seems to work on linux, but not mac
 
-pub const DATATYPES_VERSION: u16 = 5; // increment this when you change definitions below
+pub const DATATYPES_VERSION: u16 = 29; // increment this when you change definitions below
+
+/// Id of messages which the device sends without a request.
+pub const UNSOLICITED_ID: u16 = 0;
+
+/// Number of samples in each `SampleBatch`.
+pub const SAMPLE_BATCH_SIZE: usize = 8;
+
+/// Number of samples in each `CaptureChunk`.
+pub const CAPTURE_CHUNK_SIZE: usize = 8;
+
+/// Size of the buffers for encoding and decoding messages.
+pub const MAX_MSG_SIZE: usize = 512;
+
+/// Number of grid points along each axis of a `LutCalibration`.
+pub const LUT_SIZE: usize = 5;
+
+/// Largest `SetDeviceState::loop_rate_hz`. Faster, the control loop leaves
+/// the firmware too little time to handle the serial protocol.
+pub const MAX_LOOP_RATE_HZ: u32 = 20_000;
+
+/// Largest `SetDeviceState::adc_oversampling`.
+pub const MAX_ADC_OVERSAMPLING: u8 = 64;
+
+/// Capacity of the device's table for `WaveShape::Table`.
+pub const WAVE_TABLE_SIZE: usize = 256;
+
+/// Number of values in each `WriteWaveTable` message.
+pub const WAVE_TABLE_CHUNK_SIZE: usize = 32;
+
+/// Largest number of frequencies in a `SweepParams`.
+pub const MAX_SWEEP_POINTS: usize = 16;
+
+/// A message to the device with an id for matching the reply.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ToDeviceEnvelope {
+    /// Chosen by the host, should not be `UNSOLICITED_ID`.
+    pub id: u16,
+    pub msg: ToDevice,
+}
+
+/// A message from the device.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FromDeviceEnvelope {
+    /// The id of the `ToDeviceEnvelope` this replies to or `UNSOLICITED_ID`.
+    pub id: u16,
+    pub msg: FromDevice,
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[repr(C)] // <--- required for ssmarshal
+// `SetState` is much larger than the other variants, but there is no `Box`
+// without `std` and messages are only kept briefly on the stack.
+#[allow(clippy::large_enum_variant)]
 pub enum ToDevice {
     EchoRequest8((u8,u8,u8,u8,u8,u8,u8,u8)), // -> EchoResponse8
     SetState(SetDeviceState), // -> Empty
     QueryState, // -> EchoState
     QueryAnalog, // -> EchoAnalog
     QueryDatatypesVersion, // -> EchoDatatypesVersion,
-    /// changes the device mode to SampleAdc
+    /// changes the device mode to SampleAdc, replies `ErrorCode::Parked`
+    /// while parked
     SetGalvos((i16,i16)), // -> Empty
+    /// stream every `decimation`-th sample as unsolicited `SampleBatch` messages
+    ///
+    /// A batch is about 125 bytes framed, so at `BPS_HZ` the link carries
+    /// about 700 samples per second, less the replies. Batches which do not
+    /// fit are dropped and reported as `ErrorCode::TxQueueFull`. At the
+    /// default 10 kHz loop rate, use a `decimation` of at least 16.
+    StartStream { decimation: core::num::NonZeroU16 }, // -> Empty
+    StopStream, // -> Empty
+    /// record samples into the device RAM buffer when the trigger fires
+    ArmCapture(CaptureConfig), // -> Empty
+    QueryCaptureStatus, // -> EchoCaptureStatus
+    /// read captured samples starting at `offset` (0 is the oldest sample)
+    ReadCapture { offset: u16 }, // -> EchoCaptureChunk
+    /// control loop timing statistics since the previous `QueryTiming`
+    QueryTiming, // -> EchoTiming
+    QueryDeviceInfo, // -> EchoDeviceInfo
+    /// store the current `SetDeviceState` in flash, loaded at boot
+    SaveConfig, // -> Empty
+    /// apply the `SetDeviceState` stored in flash
+    LoadConfig, // -> Empty
+    /// remove the stored `SetDeviceState` and headstage id, the defaults are used at boot
+    EraseConfig, // -> Empty
+    /// write `values` to the table for `WaveShape::Table`, starting at `offset`
+    WriteWaveTable { offset: u16, values: [i16; WAVE_TABLE_CHUNK_SIZE] }, // -> Empty
+    /// keep the device from parking the galvos, see `HeartbeatConfig`
+    Heartbeat, // -> Empty
+    /// set `AuxConfig::dac3` without changing the mode
+    SetAux(i16), // -> Empty
+    /// the results of `DeviceMode::FrequencySweep` so far
+    QuerySweep, // -> EchoSweep
+    /// set `DeviceInfo::headstage_id`, stored in flash by `SaveConfig`
+    SetHeadstageId(u16), // -> Empty
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -29,69 +114,777 @@ pub enum ToDevice {
 pub enum FromDevice {
     EchoResponse8((u8,u8,u8,u8,u8,u8,u8,u8)),
     EchoState(DeviceState),
-    EchoAnalog((i16,i16)),
+    EchoAnalog(AnalogReading),
     EchoDatatypesVersion(u16),
     Empty,
+    /// sent with `UNSOLICITED_ID` while streaming
+    SampleBatch(SampleBatch),
+    EchoCaptureStatus(CaptureStatus),
+    EchoCaptureChunk(CaptureChunk),
+    EchoTiming(TimingStats),
+    EchoDeviceInfo(DeviceInfo),
+    /// A request failed, or (with `UNSOLICITED_ID`) something went wrong
+    /// outside of a request. The device keeps running.
+    Error { code: ErrorCode, detail: u32 },
+    /// sent with `UNSOLICITED_ID` for each edge on the trigger input, see
+    /// `SyncConfig::trigger_edge`
+    TriggerEvent(TriggerEvent),
+    /// sent with `UNSOLICITED_ID` for each sync output pulse if
+    /// `SyncConfig::report_pulses`
+    SyncPulse(SyncPulse),
+    EchoSweep(SweepResult),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum ErrorCode {
+    /// A frame from the host was dropped. `detail` is the number of dropped
+    /// frames since startup.
+    BadFrame,
+    /// Bytes from the host were lost. `detail` is the number of receive
+    /// errors since startup.
+    RxError,
+    /// A message did not fit in the transmit queue and was not sent.
+    /// `detail` is the size of the message in bytes.
+    TxQueueFull,
+    /// A message could not be encoded.
+    EncodeError,
+    /// Writing the DACs failed.
+    SpiError,
+    /// Reading the ADCs failed.
+    AdcError,
+    /// Erasing or programming the flash failed. `detail` is the flash status
+    /// register.
+    FlashError,
+    /// There is no valid saved configuration. `detail` is the
+    /// `config::ConfigError` as a number.
+    NoConfig,
+    /// A request addressed data beyond the end of a buffer, or a setting is
+    /// out of range. `detail` is the offset or the setting's value.
+    OutOfRange,
+    /// No frame arrived from the host within `HeartbeatConfig::timeout_ms`,
+    /// the galvos were parked.
+    HeartbeatTimeout,
+    /// The device was reset by its watchdog, because the firmware hung.
+    WatchdogReset,
+    /// `SetGalvos` was ignored because the galvos are parked, see
+    /// `DeviceState::parked`.
+    Parked,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct SetDeviceState {
     pub mode: DeviceMode,
-    pub cl_period: core::num::NonZeroU32,
+    /// Rate of the timer driven control loop (ADC sampling, control and DAC
+    /// output), at most `MAX_LOOP_RATE_HZ`.
+    pub loop_rate_hz: core::num::NonZeroU32,
+    /// Period of the closed loop updates. Rounded to a whole number of control
+    /// loop ticks, at least one.
+    pub cl_period_us: core::num::NonZeroU32,
+    /// Number of ADC conversions averaged for each control loop tick, from 1
+    /// to `MAX_ADC_OVERSAMPLING` (0 counts as 1). The conversions are spread
+    /// evenly over the tick. Fewer are used if the ADCs cannot convert that
+    /// fast at `loop_rate_hz`, see `DeviceState::adc_oversampling`.
+    pub adc_oversampling: u8,
     pub dac1_initial: i16,
     pub dac2_initial: i16,
-    pub dac1_angle_func: AdcToAngleCalibration,
-    pub dac2_angle_func: AdcToAngleCalibration,
+    pub dac1_angle_func: AngleCalibration,
+    pub dac2_angle_func: AngleCalibration,
     pub dac1_angle_gain: f32,
     pub dac2_angle_gain: f32,
+    /// Scale `adc1` and `adc2` by the QPD sum before `dac*_angle_func`.
+    pub sum_normalization: Option<SumNormalization>,
     pub dac1_min: i16,
     pub dac1_max: i16,
     pub dac2_min: i16,
     pub dac2_max: i16,
+    pub target_loss: TargetLossConfig,
+    pub output_limits: OutputLimits,
+    pub heartbeat: HeartbeatConfig,
+    pub aux: AuxConfig,
+    pub sync: SyncConfig,
+}
+
+/// What happens when the host stops talking to the device.
+///
+/// If no valid frame arrives for `timeout_ms`, the device stops running its
+/// mode, moves the galvos to the park position and sets
+/// `DeviceState::parked`. The `SetDeviceState` is not changed. The host
+/// should send `ToDevice::Heartbeat` when it has nothing else to send.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HeartbeatConfig {
+    /// Zero disables the timeout. Rounded to a whole number of control loop
+    /// ticks.
+    pub timeout_ms: u32,
+    pub dac1_park: i16,
+    pub dac2_park: i16,
+    /// The slew limit (see `OutputLimits`) while moving to the park position,
+    /// if lower than the configured one. Zero keeps the configured one.
+    pub park_slew: u16,
+}
+
+/// The third DAC714 channel, for example for laser power or focus control.
+///
+/// The channel is not clipped or slew limited.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AuxConfig {
+    pub mode: AuxMode,
+    /// The output value, also set by `ToDevice::SetAux`.
+    pub dac3: i16,
+    /// The output in `AuxMode::LockGated` while not locked.
+    pub dac3_off: i16,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum AuxMode {
+    /// Only two DACs are written, for boards without the third DAC714.
+    Disabled,
+    /// Always output `dac3`.
+    Manual,
+    /// Output `dac3` while the closed loop tracks the target (see
+    /// `CaptureTrigger::LockLoss`) and `dac3_off` otherwise.
+    LockGated,
+}
+
+/// The trigger input (PA0, Arduino A0) and the sync output (PA1, Arduino A1),
+/// for example to line up the samples with camera frames.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SyncConfig {
+    /// Report these edges on the trigger input as `TriggerEvent`s. `None`
+    /// disables the input.
+    pub trigger_edge: Option<Edge>,
+    /// When the sync output pulses high.
+    pub pulse_on: SyncTrigger,
+    /// Length of the output pulse. Rounded to a whole number of control loop
+    /// ticks, at least one.
+    pub pulse_us: u32,
+    /// Send a `SyncPulse` for every output pulse. Frequent periodic pulses
+    /// may not fit on the serial link.
+    pub report_pulses: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum SyncTrigger {
+    /// The output stays low.
+    Never,
+    /// When the closed loop starts tracking the target (see
+    /// `CaptureTrigger::LockLoss`).
+    LockAcquired,
+    /// When the closed loop stops tracking the target.
+    LockLost,
+    /// At the first control loop tick and every this many ticks after it.
+    EveryNTicks(core::num::NonZeroU32),
+}
+
+/// Why the sync output pulsed.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum SyncCause {
+    LockAcquired,
+    LockLost,
+    Periodic,
+}
+
+/// An edge on the trigger input.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TriggerEvent {
+    /// Edges since boot, including any which could not be reported.
+    pub count: u32,
+    /// The sample counter (see `SampleBatch`) of the control loop tick which
+    /// took the edge, normally the first one starting after it.
+    pub counter: u32,
+    /// The device clock at the edge, see `timestamp_diff_us`.
+    pub timestamp_us: u32,
+    /// The CPU cycle counter at the edge, for more resolution. It wraps, so
+    /// only differences are meaningful; divide by `TimingStats::cpu_hz` to
+    /// convert to seconds.
+    pub cycles: u32,
+}
+
+/// A pulse of the sync output.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SyncPulse {
+    /// Pulses since boot, including unreported ones.
+    pub count: u32,
+    /// The sample counter (see `SampleBatch`) and `DeviceState::timestamp_us`
+    /// of the tick at which the output went high.
+    pub counter: u32,
+    pub timestamp_us: u32,
+    pub cause: SyncCause,
+}
+
+/// Makes the error angle independent of the laser power and the target's
+/// reflectance.
+///
+/// Each of `adc1` and `adc2` is replaced by
+/// `zero + (adc - zero)*sum_reference/(adc_sum - sum_zero)`, rounded, before
+/// the angle calibration. The calibration should therefore be measured with
+/// the same normalization, for example from the `adc_sum` of the recorded
+/// samples.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SumNormalization {
+    /// The ADC values with no light on the QPD.
+    pub adc1_zero: i16,
+    pub adc2_zero: i16,
+    pub sum_zero: i16,
+    /// The sum to which the values are scaled.
+    pub sum_reference: u16,
+    /// Below this `adc_sum - sum_zero`, the values are used as they are
+    /// rather than amplifying the noise.
+    pub min_sum: u16,
+}
+
+/// Limits on how fast the DAC outputs change, applied after clipping to
+/// `dac*_min/max` in every mode. Zero means no limit.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct OutputLimits {
+    /// Counts per control loop tick.
+    pub dac1_max_slew: u16,
+    pub dac2_max_slew: u16,
+    /// Change of the slew in counts per control loop tick, per tick.
+    pub dac1_max_accel: u16,
+    pub dac2_max_accel: u16,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct DeviceState {
     pub inner: SetDeviceState,
+    /// Control loop ticks in closed loop mode.
     pub cl_cycles: u32,
+    /// The device clock when the ADC values were read, see
+    /// `timestamp_diff_us`.
+    pub timestamp_us: u32,
     pub adc1: i16,
     pub adc2: i16,
+    /// The QPD sum signal.
+    pub adc_sum: i16,
+    /// The number of ADC conversions actually averaged per tick.
+    pub adc_oversampling: u8,
+    /// The rate at which each ADC channel is actually sampled. Both channels
+    /// are sampled at the same time.
+    pub adc_sample_rate_hz: u32,
+    /// The DAC values commanded by the current mode.
     pub dac1: i16,
     pub dac2: i16,
+    /// The DAC values actually output, following `dac1` and `dac2` within
+    /// `SetDeviceState::output_limits`.
+    pub dac1_out: i16,
+    pub dac2_out: i16,
+    /// The value output on the third DAC, see `AuxConfig`. Zero while
+    /// disabled.
+    pub dac3_out: i16,
     pub dac1_f32: f32,
     pub dac2_f32: f32,
+    /// The target's velocity estimated by the closed loop prediction, in DAC
+    /// counts per closed loop update. Zero without prediction.
+    pub dac1_target_velocity: f32,
+    pub dac2_target_velocity: f32,
+    /// The signal quality metric, `|adc1| + |adc2|`.
+    pub signal: u32,
+    pub target: TargetStatus,
+    /// The current point of the search pattern in search mode.
+    pub search_index: u32,
+    /// Where the last search locked onto the target.
+    pub lock_point: Option<LockPoint>,
+    /// The galvos were parked because the host stopped sending, see
+    /// `HeartbeatConfig`. Cleared by `SetState`.
+    pub parked: bool,
+}
+
+/// When the target counts as lost and what the closed loop does then.
+///
+/// The target is lost when `DeviceState::signal` stays below `threshold` for
+/// `lost_after_us`. It is found again as soon as the signal is back at or
+/// above `threshold`, and the closed loop resumes tracking.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TargetLossConfig {
+    /// Zero disables the detection.
+    pub threshold: u32,
+    /// Rounded to a whole number of control loop ticks.
+    pub lost_after_us: u32,
+    pub action: LostAction,
+}
+
+/// What the closed loop does while the target is lost.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum LostAction {
+    /// Keep the DACs where they were.
+    Hold,
+    /// Move the DACs to `dac*_initial` and reset the controller.
+    ReturnToInitial,
+    /// Switch to `DeviceMode::Search`.
+    Search(SearchParams),
+}
+
+/// Sweep the galvos over a square grid of points until the target is found,
+/// then switch to `DeviceMode::ClosedLoop`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SearchParams {
+    pub pattern: SearchPattern,
+    pub dac1_center: i16,
+    pub dac2_center: i16,
+    /// Distance between neighbouring points in DAC counts.
+    pub step: core::num::NonZeroU16,
+    /// Number of steps from the centre to the edge of the grid, which has
+    /// `(2*radius + 1)^2` points. The pattern repeats until a lock.
+    pub radius: u16,
+    /// Time at each point. Rounded to a whole number of control loop ticks,
+    /// at least one.
+    pub dwell_us: u32,
+    /// Lock when `DeviceState::signal` reaches this. Should be above
+    /// `TargetLossConfig::threshold`.
+    pub lock_threshold: u32,
+    /// The closed loop mode used after the lock, starting from the lock point.
+    pub lock_mode: ClosedLoopMode,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum SearchPattern {
+    /// Outwards from the centre, ring by ring.
+    Spiral,
+    /// Row by row from the low corner, alternating direction.
+    Raster,
+}
+
+/// The search point at which the signal reached `SearchParams::lock_threshold`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+pub struct LockPoint {
+    /// Position in the search pattern.
+    pub index: u32,
+    pub dac1: i16,
+    pub dac2: i16,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum TargetStatus {
+    /// The signal is at or above the threshold.
+    Present,
+    /// The signal is below the threshold, but not for long enough to count
+    /// as lost.
+    Weak,
+    /// The signal has been below the threshold for `lost_after_us`.
+    Lost,
+}
+
+/// The ADC values of the last control loop tick.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AnalogReading {
+    pub adc1: i16,
+    pub adc2: i16,
+    pub adc_sum: i16,
+    /// See `DeviceState::timestamp_us`.
+    pub timestamp_us: u32,
+}
+
+/// A single sample of the analog inputs and outputs.
+///
+/// The DAC values are `DeviceState::dac1_out` and `dac2_out`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct StoredSample {
+    /// See `DeviceState::timestamp_us`. Zero for missing samples.
+    pub timestamp_us: u32,
+    pub adc1: i16,
+    pub adc2: i16,
+    pub adc_sum: i16,
+    pub dac1: i16,
+    pub dac2: i16,
+}
+
+/// Consecutive streamed samples.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SampleBatch {
+    /// The sample counter of `samples[0]`. Counts every control loop
+    /// iteration, so consecutive samples differ by `decimation`.
+    pub counter: u32,
+    pub decimation: u16,
+    pub samples: [StoredSample; SAMPLE_BATCH_SIZE],
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CaptureConfig {
+    pub trigger: CaptureTrigger,
+    /// Number of samples to keep from before the trigger.
+    pub pre_trigger: u16,
+    /// Record every `decimation`-th control loop iteration.
+    pub decimation: core::num::NonZeroU16,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum CaptureTrigger {
+    /// Trigger on the first sample after arming.
+    Immediate,
+    /// Trigger when the device mode changes.
+    ModeChange,
+    /// Trigger when the closed loop stops tracking, either by leaving the
+    /// closed loop mode, by losing the target (see `TargetLossConfig`) or by
+    /// an output reaching its `dac*_min/max` limit.
+    LockLoss,
+    AdcThreshold(AdcThreshold),
+}
+
+/// Trigger when an ADC value crosses `level`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AdcThreshold {
+    pub channel: AdcChannel,
+    pub level: i16,
+    pub edge: Edge,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum AdcChannel {
+    Adc1,
+    Adc2,
+    Sum,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum CaptureState {
+    /// Nothing has been captured since power on.
+    Idle,
+    /// Waiting for the trigger.
+    Armed,
+    /// Recording the samples after the trigger.
+    Triggered,
+    /// The buffer is complete and can be read.
+    Done,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CaptureStatus {
+    pub state: CaptureState,
+    /// Number of samples which can be read.
+    pub n_samples: u16,
+    /// Position of the trigger sample when reading.
+    pub trigger_index: u16,
+    /// The sample counter (see `SampleBatch`) at the trigger.
+    pub trigger_counter: u32,
+    /// Capacity of the RAM buffer in samples.
+    pub buffer_size: u16,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CaptureChunk {
+    pub offset: u16,
+    /// Samples beyond `CaptureStatus::n_samples` are zero.
+    pub samples: [StoredSample; CAPTURE_CHUNK_SIZE],
+}
+
+/// Control loop timing, measured with the CPU cycle counter.
+///
+/// Durations are from the start to the end of one control loop iteration.
+/// Divide by `cpu_hz` to convert to seconds.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct TimingStats {
+    /// Iterations counted in the last complete one second window.
+    pub iterations_per_second: u32,
+    /// Iterations since the previous `QueryTiming`.
+    pub n_iterations: u32,
+    pub min_cycles: u32,
+    pub mean_cycles: u32,
+    pub max_cycles: u32,
+    /// Iterations which took longer than the loop period, since boot.
+    pub missed_deadlines: u32,
+    pub cpu_hz: u32,
+}
+
+/// Identity and capabilities of the device.
+///
+/// Text fields are UTF-8 padded with zeros, see `fixed_str()`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct DeviceInfo {
+    /// Version of the firmware crate.
+    pub firmware_version: [u8; 16],
+    /// Git commit of the firmware source.
+    pub git_hash: [u8; 16],
+    /// Build time in seconds since the Unix epoch.
+    pub build_time: u64,
+    pub board: Board,
+    /// Identifies the physical headstage which is connected. Set with
+    /// `SetHeadstageId` and kept in flash, so that one firmware image can be
+    /// used for all headstages.
+    pub headstage_id: u16,
+    /// Unique id of the microcontroller.
+    pub mcu_uid: [u32; 3],
+    /// Capacity of the capture RAM buffer in samples.
+    pub buffer_size: u16,
+    pub modes: SupportedModes,
+    pub n_adc_channels: u8,
+    /// 2, or 3 if the third DAC714 is used by the current `AuxConfig::mode`.
+    pub n_dac_channels: u8,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum Board {
+    NucleoF103RB,
+}
+
+/// The `DeviceMode` and `ClosedLoopMode` variants the firmware implements.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SupportedModes {
+    pub sawtooth_test: bool,
+    pub sample_adc: bool,
+    pub closed_loop: bool,
+    pub proportional: bool,
+    pub proportional_integral: bool,
+    pub proportional_integral_derivative: bool,
+    pub search: bool,
+    pub waveform: bool,
+    pub frequency_sweep: bool,
+}
+
+/// Every `timestamp_us` is the device clock: microseconds since boot from a
+/// hardware timer, wrapping after about 71.6 minutes.
+///
+/// Returns the time from `earlier` to `later`, for times less than about 35.8
+/// minutes apart. Negative if `later` is before `earlier`.
+pub fn timestamp_diff_us(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+/// Copy `text` into a zero padded buffer, truncating it if needed.
+pub fn to_fixed_str(text: &str) -> [u8; 16] {
+    let mut buf = [0; 16];
+    let n = text.len().min(buf.len());
+    buf[..n].copy_from_slice(&text.as_bytes()[..n]);
+    buf
+}
+
+/// The text in a zero padded buffer.
+pub fn fixed_str(buf: &[u8]) -> &str {
+    let n = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    match core::str::from_utf8(&buf[..n]) {
+        Ok(text) => text,
+        // Truncation may have split a character.
+        Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or(""),
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[repr(C)] // <--- required for ssmarshal
 pub enum ClosedLoopMode {
+    /// Adds `dac*_angle_gain` times the error to the output every update.
     Proportional,
-    // ProportionalIntegral,
+    ProportionalIntegral(PiParams),
+    ProportionalIntegralDerivative(PidParams),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PiParams {
+    pub dac1: PiGains,
+    pub dac2: PiGains,
+    pub prediction: Option<PredictionParams>,
+}
+
+/// Gains of a PI controller for one axis.
+///
+/// The output is `dac*_initial + kp*error + integrator`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PiGains {
+    pub kp: f32,
+    /// Added to the integrator every update, times the error.
+    pub ki: f32,
+    pub integrator_min: f32,
+    pub integrator_max: f32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PidParams {
+    pub dac1: PidGains,
+    pub dac2: PidGains,
+    pub prediction: Option<PredictionParams>,
+}
+
+/// Gains of a PID controller for one axis.
+///
+/// The output is `dac*_initial + kp*error + integrator + derivative`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PidGains {
+    pub kp: f32,
+    /// Added to the integrator every update, times the error.
+    pub ki: f32,
+    /// Times the change of the error since the previous update.
+    pub kd: f32,
+    pub integrator_min: f32,
+    pub integrator_max: f32,
+    /// Low pass filter on the derivative term, from 0.0 (no filtering)
+    /// towards 1.0 (heavy filtering).
+    pub derivative_filter: f32,
+}
+
+/// Feedforward of the target's estimated motion, added to a PI(D) controller.
+///
+/// Every update, the target position is measured as the DAC output plus the
+/// error converted to DAC counts, and a filter estimates the target's
+/// velocity from these measurements. The velocity (in DAC counts per
+/// update) is added to the integrator so that the loop follows a moving
+/// target without lagging behind.
+///
+/// `ClosedLoopMode::Proportional` works like `ProportionalIntegral` with
+/// `kp` zero and `ki` equal to `dac*_angle_gain`, use that to add
+/// prediction to it.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PredictionParams {
+    pub filter: PredictionFilter,
+    /// DAC counts per unit of error angle.
+    pub dac1_counts_per_error: f32,
+    pub dac2_counts_per_error: f32,
+    /// Fraction of the estimated velocity added to the integrator every
+    /// update, usually 1.0.
+    pub velocity_gain: f32,
+    /// Aim this many updates ahead of the target, to make up for the delay
+    /// from the ADCs to the galvos.
+    pub lead_updates: f32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum PredictionFilter {
+    /// Fixed gains, from 0.0 to 1.0. Higher values follow changes of the
+    /// target's motion faster but let through more noise.
+    AlphaBeta { alpha: f32, beta: f32 },
+    /// A constant velocity Kalman filter. `process_noise` is the variance of
+    /// the target's acceleration (in counts per update squared) and
+    /// `measurement_noise` the variance of the measured position (in counts
+    /// squared).
+    Kalman { process_noise: f32, measurement_noise: f32 },
 }
 
 /// Result of calibration to calculate error angle from the adcs
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum AngleCalibration {
+    Linear(AdcToAngleCalibration),
+    Polynomial(PolynomialCalibration),
+    LookupTable(LutCalibration),
+}
+
+/// angle = `adc1_gain*adc1 + adc2_gain*adc2 + offset`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct AdcToAngleCalibration {
     pub adc1_gain: f32,
     pub adc2_gain: f32,
     pub offset: f32,
 }
 
+/// A third order polynomial in both ADC values, including cross terms.
+///
+/// With `x = adc1 - adc1_center` and `y = adc2 - adc2_center`, the angle is
+/// the sum of `coeffs` multiplied by `[1, x, y, x^2, x*y, y^2, x^3, x^2*y,
+/// x*y^2, y^3]`. For a second order polynomial, set the last four to zero.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PolynomialCalibration {
+    pub adc1_center: f32,
+    pub adc2_center: f32,
+    pub coeffs: [f32; 10],
+}
+
+/// Angles on a regular grid of ADC values, bilinearly interpolated.
+///
+/// `angles[i][j]` is the angle at `adc1 = adc1_start + i*adc1_step` and
+/// `adc2 = adc2_start + j*adc2_step`. Outside the grid, the nearest grid cell
+/// is extrapolated.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct LutCalibration {
+    pub adc1_start: i16,
+    pub adc1_step: core::num::NonZeroU16,
+    pub adc2_start: i16,
+    pub adc2_step: core::num::NonZeroU16,
+    pub angles: [[i16; LUT_SIZE]; LUT_SIZE],
+}
+
 impl Default for SetDeviceState {
     fn default() -> Self {
         Self {
             mode: DeviceMode::default(),
-            cl_period: core::num::NonZeroU32::new(1).unwrap(),
+            loop_rate_hz: core::num::NonZeroU32::new(10_000).unwrap(),
+            cl_period_us: core::num::NonZeroU32::new(100).unwrap(),
+            adc_oversampling: 1,
             dac1_initial: 0,
             dac2_initial: 0,
-            dac1_angle_func: AdcToAngleCalibration::default(),
-            dac2_angle_func: AdcToAngleCalibration::default(),
+            dac1_angle_func: AngleCalibration::default(),
+            dac2_angle_func: AngleCalibration::default(),
             dac1_angle_gain: 1e-3,
             dac2_angle_gain: 1e-3,
+            sum_normalization: None,
             dac1_min: i16::min_value(),
             dac1_max: i16::max_value(),
             dac2_min: i16::min_value(),
             dac2_max: i16::max_value(),
+            target_loss: TargetLossConfig::default(),
+            output_limits: OutputLimits::default(),
+            heartbeat: HeartbeatConfig::default(),
+            aux: AuxConfig::default(),
+            sync: SyncConfig::default(),
+        }
+    }
+}
+
+impl SetDeviceState {
+    /// Check the settings which the firmware cannot run with, returning the
+    /// `ErrorCode` and detail to reply with.
+    pub fn check(&self) -> Result<(), (ErrorCode, u32)> {
+        let loop_rate_hz = self.loop_rate_hz.get();
+        if loop_rate_hz > MAX_LOOP_RATE_HZ {
+            return Err((ErrorCode::OutOfRange, loop_rate_hz));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            trigger_edge: None,
+            pulse_on: SyncTrigger::Never,
+            pulse_us: 100,
+            report_pulses: false,
+        }
+    }
+}
+
+impl Default for AuxConfig {
+    fn default() -> Self {
+        Self {
+            mode: AuxMode::Disabled,
+            dac3: 0,
+            dac3_off: 0,
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 1000,
+            dac1_park: 0,
+            dac2_park: 0,
+            park_slew: 100,
+        }
+    }
+}
+
+impl Default for TargetLossConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0,
+            lost_after_us: 10_000,
+            action: LostAction::Hold,
         }
     }
 }
@@ -102,16 +895,100 @@ impl Default for DeviceState {
         Self {
             inner: SetDeviceState::default(),
             cl_cycles: 0,
+            timestamp_us: 0,
             adc1: 0,
             adc2: 0,
+            adc_sum: 0,
+            adc_oversampling: 1,
+            adc_sample_rate_hz: 0,
             dac1: 0,
             dac2: 0,
+            dac1_out: 0,
+            dac2_out: 0,
+            dac3_out: 0,
             dac1_f32: 0.0,
             dac2_f32: 0.0,
+            dac1_target_velocity: 0.0,
+            dac2_target_velocity: 0.0,
+            signal: 0,
+            target: TargetStatus::Present,
+            search_index: 0,
+            lock_point: None,
+            parked: false,
+        }
+    }
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self {
+            pattern: SearchPattern::Spiral,
+            dac1_center: 0,
+            dac2_center: 0,
+            step: core::num::NonZeroU16::new(500).unwrap(),
+            radius: 10,
+            dwell_us: 1000,
+            lock_threshold: 1000,
+            lock_mode: ClosedLoopMode::Proportional,
+        }
+    }
+}
+
+impl Default for PiGains {
+    fn default() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 1e-3,
+            integrator_min: i16::MIN as f32,
+            integrator_max: i16::MAX as f32,
+        }
+    }
+}
+
+impl Default for PidGains {
+    fn default() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 1e-3,
+            kd: 0.0,
+            integrator_min: i16::MIN as f32,
+            integrator_max: i16::MAX as f32,
+            derivative_filter: 0.0,
         }
     }
 }
 
+impl Default for PredictionParams {
+    fn default() -> Self {
+        Self {
+            filter: PredictionFilter::AlphaBeta { alpha: 0.5, beta: 0.1 },
+            dac1_counts_per_error: 1.0,
+            dac2_counts_per_error: 1.0,
+            velocity_gain: 1.0,
+            lead_updates: 1.0,
+        }
+    }
+}
+
+impl From<PiGains> for PidGains {
+    fn from(orig: PiGains) -> Self {
+        Self {
+            kp: orig.kp,
+            ki: orig.ki,
+            kd: 0.0,
+            integrator_min: orig.integrator_min,
+            integrator_max: orig.integrator_max,
+            derivative_filter: 0.0,
+        }
+    }
+}
+
+impl Default for AngleCalibration {
+    fn default() -> Self {
+        AngleCalibration::Linear(AdcToAngleCalibration::default())
+    }
+}
+
 impl Default for AdcToAngleCalibration {
     fn default() -> Self {
         Self {
@@ -128,6 +1005,109 @@ pub enum DeviceMode {
     SawtoothTest,
     SampleAdc,
     ClosedLoop(ClosedLoopMode),
+    Search(SearchParams),
+    Waveform(WaveformParams),
+    FrequencySweep(SweepParams),
+}
+
+/// Periodic test patterns on both axes. A phase difference between the axes
+/// gives Lissajous patterns.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct WaveformParams {
+    pub dac1: AxisWaveform,
+    pub dac2: AxisWaveform,
+}
+
+/// The DAC value is `offset + amplitude*shape(phase)`, with shapes between
+/// -1 and 1.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AxisWaveform {
+    pub shape: WaveShape,
+    pub amplitude: i16,
+    pub offset: i16,
+    pub frequency_hz: f32,
+    /// Phase at the start, in degrees.
+    pub phase_deg: f32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum WaveShape {
+    Sine,
+    /// Rises from zero like the sine.
+    Triangle,
+    /// 1 for the first half period, then -1.
+    Square,
+    /// Rises from zero to 1 during the first half period, then from -1.
+    Sawtooth,
+    /// The first `len` values written with `WriteWaveTable`, linearly
+    /// interpolated. `i16::MAX` is 1.
+    Table { len: u16 },
+}
+
+/// Measure the frequency response from one galvo to one ADC channel.
+///
+/// The swept axis outputs `dac*_initial + amplitude*sin(2*pi*f*t)` at each
+/// frequency in turn, while the other axis stays at its `dac*_initial`. At
+/// each frequency, the device waits for `settle_periods` periods and then
+/// correlates the DAC output (`DeviceState::dac*_out`) and the `response`
+/// channel with a sine and a cosine over `measure_periods` periods. After the
+/// last frequency, the swept axis returns to its `dac*_initial`. Read the
+/// results with `ToDevice::QuerySweep`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct SweepParams {
+    pub axis: SweepAxis,
+    pub response: AdcChannel,
+    /// In DAC counts.
+    pub amplitude: i16,
+    /// Only the first `n_frequencies` are measured, in order. Frequencies
+    /// must be above zero and below half of `SetDeviceState::loop_rate_hz`.
+    pub frequencies_hz: [f32; MAX_SWEEP_POINTS],
+    pub n_frequencies: u8,
+    /// Rounded to a whole number of control loop ticks.
+    pub settle_periods: u16,
+    /// Rounded to a whole number of control loop ticks, at least one.
+    pub measure_periods: core::num::NonZeroU16,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[repr(C)] // <--- required for ssmarshal
+pub enum SweepAxis {
+    Dac1,
+    Dac2,
+}
+
+/// The frequency response measured by `DeviceMode::FrequencySweep`, kept
+/// until the next `SetState`.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+pub struct SweepResult {
+    /// The number of frequencies measured so far, which is the number of
+    /// valid `points`.
+    pub n_done: u8,
+    /// The number of frequencies in the sweep, zero if there was none.
+    pub n_frequencies: u8,
+    pub points: [SweepPoint; MAX_SWEEP_POINTS],
+}
+
+/// The response at one frequency of a `SweepParams`.
+///
+/// The ADC values of each control loop tick are paired with the DAC output
+/// of the tick before, so the phase does not include the delay of the control
+/// loop itself. Values which could not be measured, for example at a
+/// frequency out of range, are NaN.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct SweepPoint {
+    /// The frequency actually output, which differs slightly from the
+    /// requested one because of the phase resolution.
+    pub frequency_hz: f32,
+    /// Amplitudes at `frequency_hz` in counts, with offsets removed.
+    pub dac_amplitude: f32,
+    pub adc_amplitude: f32,
+    /// `adc_amplitude/dac_amplitude`.
+    pub gain: f32,
+    /// Phase of the ADC signal relative to the DAC output, from -180 to 180
+    /// degrees.
+    pub phase_deg: f32,
 }
 
 impl Default for DeviceMode {
@@ -147,7 +1127,7 @@ impl quickcheck::Arbitrary for ToDevice {
         use rand::{self, Rng};
 
         let rand: u8 = g.gen();
-        let rem = rand % 3;
+        let rem = rand % 20;
         match rem {
             0 => {
                 ToDevice::EchoRequest8((g.gen(), g.gen(), g.gen(), g.gen(),
@@ -166,6 +1146,56 @@ impl quickcheck::Arbitrary for ToDevice {
             4 => {
                 ToDevice::SetGalvos((g.gen(), g.gen()))
             }
+            5 => {
+                let decimation = core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap();
+                ToDevice::StartStream { decimation }
+            }
+            6 => {
+                ToDevice::StopStream
+            }
+            7 => {
+                ToDevice::ArmCapture(CaptureConfig::arbitrary(g))
+            }
+            8 => {
+                ToDevice::QueryCaptureStatus
+            }
+            9 => {
+                ToDevice::ReadCapture { offset: g.gen() }
+            }
+            10 => {
+                ToDevice::QueryTiming
+            }
+            11 => {
+                ToDevice::QueryDeviceInfo
+            }
+            12 => {
+                ToDevice::SaveConfig
+            }
+            13 => {
+                ToDevice::LoadConfig
+            }
+            14 => {
+                ToDevice::EraseConfig
+            }
+            15 => {
+                let mut values = [0; WAVE_TABLE_CHUNK_SIZE];
+                for value in values.iter_mut() {
+                    *value = g.gen();
+                }
+                ToDevice::WriteWaveTable { offset: g.gen(), values }
+            }
+            16 => {
+                ToDevice::Heartbeat
+            }
+            17 => {
+                ToDevice::SetAux(g.gen())
+            }
+            18 => {
+                ToDevice::QuerySweep
+            }
+            19 => {
+                ToDevice::SetHeadstageId(g.gen())
+            }
             _ => {
                 panic!("impossible");
             }
@@ -173,6 +1203,18 @@ impl quickcheck::Arbitrary for ToDevice {
     }
 }
 
+#[cfg(test)]
+impl quickcheck::Arbitrary for ToDeviceEnvelope {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        Self {
+            id: g.gen(),
+            msg: ToDevice::arbitrary(g),
+        }
+    }
+}
+
 #[cfg(test)]
 impl quickcheck::Arbitrary for SetDeviceState {
     fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
@@ -181,11 +1223,24 @@ impl quickcheck::Arbitrary for SetDeviceState {
         let mode = DeviceMode::arbitrary(g);
         Self {
             mode,
-            cl_period: core::num::NonZeroU32::new(g.gen()).unwrap(),
-            dac1_angle_func: AdcToAngleCalibration::arbitrary(g),
-            dac2_angle_func: AdcToAngleCalibration::arbitrary(g),
+            loop_rate_hz: core::num::NonZeroU32::new(g.gen::<u32>().max(1)).unwrap(),
+            cl_period_us: core::num::NonZeroU32::new(g.gen::<u32>().max(1)).unwrap(),
+            adc_oversampling: g.gen(),
+            dac1_angle_func: AngleCalibration::arbitrary(g),
+            dac2_angle_func: AngleCalibration::arbitrary(g),
             dac1_angle_gain: g.gen(),
             dac2_angle_gain: g.gen(),
+            sum_normalization: if g.gen() {
+                Some(SumNormalization {
+                    adc1_zero: g.gen(),
+                    adc2_zero: g.gen(),
+                    sum_zero: g.gen(),
+                    sum_reference: g.gen(),
+                    min_sum: g.gen(),
+                })
+            } else {
+                None
+            },
 
             dac1_initial: g.gen(),
             dac2_initial: g.gen(),
@@ -194,6 +1249,61 @@ impl quickcheck::Arbitrary for SetDeviceState {
             dac1_max: g.gen(),
             dac2_min: g.gen(),
             dac2_max: g.gen(),
+            target_loss: TargetLossConfig::arbitrary(g),
+            output_limits: OutputLimits {
+                dac1_max_slew: g.gen(),
+                dac2_max_slew: g.gen(),
+                dac1_max_accel: g.gen(),
+                dac2_max_accel: g.gen(),
+            },
+            heartbeat: HeartbeatConfig {
+                timeout_ms: g.gen(),
+                dac1_park: g.gen(),
+                dac2_park: g.gen(),
+                park_slew: g.gen(),
+            },
+            aux: AuxConfig {
+                mode: match g.gen::<u8>() % 3 {
+                    0 => AuxMode::Disabled,
+                    1 => AuxMode::Manual,
+                    _ => AuxMode::LockGated,
+                },
+                dac3: g.gen(),
+                dac3_off: g.gen(),
+            },
+            sync: SyncConfig {
+                trigger_edge: match g.gen::<u8>() % 3 {
+                    0 => None,
+                    1 => Some(Edge::Rising),
+                    _ => Some(Edge::Falling),
+                },
+                pulse_on: match g.gen::<u8>() % 4 {
+                    0 => SyncTrigger::Never,
+                    1 => SyncTrigger::LockAcquired,
+                    2 => SyncTrigger::LockLost,
+                    _ => SyncTrigger::EveryNTicks(core::num::NonZeroU32::new(g.gen::<u32>().max(1)).unwrap()),
+                },
+                pulse_us: g.gen(),
+                report_pulses: g.gen(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for TargetLossConfig {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        let action = match g.gen::<u8>() % 3 {
+            0 => LostAction::Hold,
+            1 => LostAction::ReturnToInitial,
+            _ => LostAction::Search(SearchParams::arbitrary(g)),
+        };
+        Self {
+            threshold: g.gen(),
+            lost_after_us: g.gen(),
+            action,
         }
     }
 }
@@ -207,12 +1317,124 @@ impl quickcheck::Arbitrary for DeviceState {
         Self {
             inner,
             cl_cycles: g.gen(),
+            timestamp_us: g.gen(),
             dac1: g.gen(),
             dac2: g.gen(),
+            dac1_out: g.gen(),
+            dac2_out: g.gen(),
+            dac3_out: g.gen(),
             dac1_f32: g.gen(),
             dac2_f32: g.gen(),
+            dac1_target_velocity: g.gen(),
+            dac2_target_velocity: g.gen(),
             adc1: g.gen(),
             adc2: g.gen(),
+            adc_sum: g.gen(),
+            adc_oversampling: g.gen(),
+            adc_sample_rate_hz: g.gen(),
+            signal: g.gen(),
+            target: match g.gen::<u8>() % 3 {
+                0 => TargetStatus::Present,
+                1 => TargetStatus::Weak,
+                _ => TargetStatus::Lost,
+            },
+            search_index: g.gen(),
+            lock_point: if g.gen() {
+                Some(LockPoint { index: g.gen(), dac1: g.gen(), dac2: g.gen() })
+            } else {
+                None
+            },
+            parked: g.gen(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for CaptureConfig {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        let trigger = match g.gen::<u8>() % 4 {
+            0 => CaptureTrigger::Immediate,
+            1 => CaptureTrigger::ModeChange,
+            2 => CaptureTrigger::LockLoss,
+            _ => {
+                CaptureTrigger::AdcThreshold(AdcThreshold {
+                    channel: match g.gen::<u8>() % 3 {
+                        0 => AdcChannel::Adc1,
+                        1 => AdcChannel::Adc2,
+                        _ => AdcChannel::Sum,
+                    },
+                    level: g.gen(),
+                    edge: if g.gen() { Edge::Rising } else { Edge::Falling },
+                })
+            }
+        };
+        Self {
+            trigger,
+            pre_trigger: g.gen(),
+            decimation: core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for SampleBatch {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        let mut samples = [StoredSample::default(); SAMPLE_BATCH_SIZE];
+        for sample in samples.iter_mut() {
+            *sample = StoredSample {
+                timestamp_us: g.gen(),
+                adc1: g.gen(),
+                adc2: g.gen(),
+                adc_sum: g.gen(),
+                dac1: g.gen(),
+                dac2: g.gen(),
+            };
+        }
+        Self {
+            counter: g.gen(),
+            decimation: g.gen(),
+            samples,
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for AngleCalibration {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        match g.gen::<u8>() % 3 {
+            0 => AngleCalibration::Linear(AdcToAngleCalibration::arbitrary(g)),
+            1 => {
+                let mut coeffs = [0.0; 10];
+                for c in coeffs.iter_mut() {
+                    *c = g.gen();
+                }
+                AngleCalibration::Polynomial(PolynomialCalibration {
+                    adc1_center: g.gen(),
+                    adc2_center: g.gen(),
+                    coeffs,
+                })
+            }
+            _ => {
+                let mut angles = [[0; LUT_SIZE]; LUT_SIZE];
+                for row in angles.iter_mut() {
+                    for angle in row.iter_mut() {
+                        *angle = g.gen();
+                    }
+                }
+                AngleCalibration::LookupTable(LutCalibration {
+                    adc1_start: g.gen(),
+                    adc1_step: core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap(),
+                    adc2_start: g.gen(),
+                    adc2_step: core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap(),
+                    angles,
+                })
+            }
         }
     }
 }
@@ -233,8 +1455,153 @@ impl quickcheck::Arbitrary for AdcToAngleCalibration {
 
 #[cfg(test)]
 impl quickcheck::Arbitrary for DeviceMode {
-    fn arbitrary<G: quickcheck::Gen>(_g: &mut G) -> Self {
-        DeviceMode::SawtoothTest
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        match g.gen::<u8>() % 6 {
+            0 => DeviceMode::SawtoothTest,
+            1 => DeviceMode::SampleAdc,
+            2 => DeviceMode::ClosedLoop(ClosedLoopMode::arbitrary(g)),
+            3 => DeviceMode::Search(SearchParams::arbitrary(g)),
+            4 => DeviceMode::Waveform(WaveformParams {
+                dac1: AxisWaveform::arbitrary(g),
+                dac2: AxisWaveform::arbitrary(g),
+            }),
+            _ => {
+                let mut frequencies_hz = [0.0; MAX_SWEEP_POINTS];
+                for frequency_hz in frequencies_hz.iter_mut() {
+                    *frequency_hz = g.gen();
+                }
+                DeviceMode::FrequencySweep(SweepParams {
+                    axis: if g.gen() { SweepAxis::Dac1 } else { SweepAxis::Dac2 },
+                    response: match g.gen::<u8>() % 3 {
+                        0 => AdcChannel::Adc1,
+                        1 => AdcChannel::Adc2,
+                        _ => AdcChannel::Sum,
+                    },
+                    amplitude: g.gen(),
+                    frequencies_hz,
+                    n_frequencies: g.gen(),
+                    settle_periods: g.gen(),
+                    measure_periods: core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for AxisWaveform {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        let shape = match g.gen::<u8>() % 5 {
+            0 => WaveShape::Sine,
+            1 => WaveShape::Triangle,
+            2 => WaveShape::Square,
+            3 => WaveShape::Sawtooth,
+            _ => WaveShape::Table { len: g.gen() },
+        };
+        Self {
+            shape,
+            amplitude: g.gen(),
+            offset: g.gen(),
+            frequency_hz: g.gen(),
+            phase_deg: g.gen(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for SearchParams {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        Self {
+            pattern: if g.gen() { SearchPattern::Spiral } else { SearchPattern::Raster },
+            dac1_center: g.gen(),
+            dac2_center: g.gen(),
+            step: core::num::NonZeroU16::new(g.gen::<u16>().max(1)).unwrap(),
+            radius: g.gen(),
+            dwell_us: g.gen(),
+            lock_threshold: g.gen(),
+            lock_mode: ClosedLoopMode::arbitrary(g),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for ClosedLoopMode {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        match g.gen::<u8>() % 3 {
+            0 => ClosedLoopMode::Proportional,
+            1 => {
+                ClosedLoopMode::ProportionalIntegral(PiParams {
+                    dac1: PiGains::arbitrary(g),
+                    dac2: PiGains::arbitrary(g),
+                    prediction: Option::<PredictionParams>::arbitrary(g),
+                })
+            }
+            _ => {
+                ClosedLoopMode::ProportionalIntegralDerivative(PidParams {
+                    dac1: PidGains::arbitrary(g),
+                    dac2: PidGains::arbitrary(g),
+                    prediction: Option::<PredictionParams>::arbitrary(g),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for PredictionParams {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        let filter = if g.gen() {
+            PredictionFilter::AlphaBeta { alpha: g.gen(), beta: g.gen() }
+        } else {
+            PredictionFilter::Kalman { process_noise: g.gen(), measurement_noise: g.gen() }
+        };
+        Self {
+            filter,
+            dac1_counts_per_error: g.gen(),
+            dac2_counts_per_error: g.gen(),
+            velocity_gain: g.gen(),
+            lead_updates: g.gen(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for PiGains {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        Self {
+            kp: g.gen(),
+            ki: g.gen(),
+            integrator_min: g.gen(),
+            integrator_max: g.gen(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for PidGains {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        use rand::{self, Rng};
+
+        Self {
+            kp: g.gen(),
+            ki: g.gen(),
+            kd: g.gen(),
+            integrator_min: g.gen(),
+            integrator_max: g.gen(),
+            derivative_filter: g.gen(),
+        }
     }
 }
 
@@ -244,7 +1611,18 @@ mod tests {
 
     quickcheck! {
         fn qc_to_device_ssmarshal_roundtrip(orig: crate::ToDevice) -> bool {
-            let mut buf = [0; 256];
+            let mut buf = [0; MAX_MSG_SIZE];
+            let n_bytes = ssmarshal::serialize(&mut buf, &orig).expect("serialize");
+
+            let (decoded, nbytes2) = ssmarshal::deserialize(&buf[0..n_bytes]).expect("deserialize");
+
+            (orig == decoded) && (n_bytes == nbytes2)
+        }
+    }
+
+    quickcheck! {
+        fn qc_to_device_envelope_ssmarshal_roundtrip(orig: crate::ToDeviceEnvelope) -> bool {
+            let mut buf = [0; MAX_MSG_SIZE];
             let n_bytes = ssmarshal::serialize(&mut buf, &orig).expect("serialize");
 
             let (decoded, nbytes2) = ssmarshal::deserialize(&buf[0..n_bytes]).expect("deserialize");
@@ -253,8 +1631,203 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn qc_sample_batch_envelope_ssmarshal_roundtrip(batch: crate::SampleBatch) -> bool {
+            let orig = FromDeviceEnvelope {
+                id: UNSOLICITED_ID,
+                msg: FromDevice::SampleBatch(batch),
+            };
+            let mut buf = [0; MAX_MSG_SIZE];
+            let n_bytes = ssmarshal::serialize(&mut buf, &orig).expect("serialize");
+
+            let (decoded, nbytes2) = ssmarshal::deserialize(&buf[0..n_bytes]).expect("deserialize");
+
+            (orig == decoded) && (n_bytes == nbytes2)
+        }
+    }
+
+    #[test]
+    fn test_capture_replies_roundtrip() {
+        let replies = [
+            FromDevice::EchoCaptureStatus(CaptureStatus {
+                state: CaptureState::Done,
+                n_samples: 1200,
+                trigger_index: 100,
+                trigger_counter: 123456,
+                buffer_size: 1200,
+            }),
+            FromDevice::EchoCaptureChunk(CaptureChunk {
+                offset: 8,
+                samples: [StoredSample { timestamp_us: 100, adc1: 1, adc2: 2, adc_sum: 3, dac1: -3, dac2: -4 }; CAPTURE_CHUNK_SIZE],
+            }),
+        ];
+        for msg in replies.iter() {
+            let orig = FromDeviceEnvelope { id: 1, msg: msg.clone() };
+            let mut buf = [0; MAX_MSG_SIZE];
+            let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+                .expect("serialize");
+
+            let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+                .expect("deserialize");
+
+            assert_eq!(orig,decoded);
+            assert_eq!(n_bytes,nbytes2);
+        }
+    }
+
+    #[test]
+    fn test_set_device_state_check() {
+        let mut state = SetDeviceState::default();
+        assert_eq!(state.check(), Ok(()));
+        state.loop_rate_hz = core::num::NonZeroU32::new(MAX_LOOP_RATE_HZ).unwrap();
+        assert_eq!(state.check(), Ok(()));
+        state.loop_rate_hz = core::num::NonZeroU32::new(MAX_LOOP_RATE_HZ + 1).unwrap();
+        assert_eq!(state.check(), Err((ErrorCode::OutOfRange, MAX_LOOP_RATE_HZ + 1)));
+    }
+
+    #[test]
+    fn test_device_info_roundtrip() {
+        let orig = FromDeviceEnvelope {
+            id: 1,
+            msg: FromDevice::EchoDeviceInfo(DeviceInfo {
+                firmware_version: to_fixed_str("0.1.0"),
+                git_hash: to_fixed_str("abcdef12-dirty"),
+                build_time: 1_565_000_000,
+                board: Board::NucleoF103RB,
+                headstage_id: 2,
+                mcu_uid: [1, 2, 3],
+                buffer_size: 1200,
+                modes: SupportedModes {
+                    sawtooth_test: true,
+                    sample_adc: true,
+                    closed_loop: true,
+                    proportional: true,
+                    proportional_integral: true,
+                    proportional_integral_derivative: false,
+                    search: true,
+                    waveform: true,
+                    frequency_sweep: true,
+                },
+                n_adc_channels: 2,
+                n_dac_channels: 2,
+            }),
+        };
+        let mut buf = [0; MAX_MSG_SIZE];
+        let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+            .expect("serialize");
+
+        let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+            .expect("deserialize");
+
+        assert_eq!(orig,decoded);
+        assert_eq!(n_bytes,nbytes2);
+    }
+
+    #[test]
+    fn test_timestamp_diff() {
+        assert_eq!(timestamp_diff_us(1500, 1000), 500);
+        assert_eq!(timestamp_diff_us(1000, 1500), -500);
+        // across the wrap
+        assert_eq!(timestamp_diff_us(10, u32::MAX - 9), 20);
+        assert_eq!(timestamp_diff_us(u32::MAX - 9, 10), -20);
+    }
+
+    #[test]
+    fn test_fixed_str() {
+        assert_eq!(fixed_str(&to_fixed_str("0.1.0")), "0.1.0");
+        assert_eq!(fixed_str(&to_fixed_str("")), "");
+        assert_eq!(fixed_str(&to_fixed_str("0123456789abcdefXYZ")), "0123456789abcdef");
+        // a two byte character split by truncation
+        assert_eq!(fixed_str(&to_fixed_str("0123456789abcde\u{e9}")), "0123456789abcde");
+    }
+
+    #[test]
+    fn test_error_roundtrip() {
+        let orig = FromDeviceEnvelope {
+            id: UNSOLICITED_ID,
+            msg: FromDevice::Error { code: ErrorCode::TxQueueFull, detail: 42 },
+        };
+        let mut buf = [0; 32];
+        let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+            .expect("serialize");
+
+        let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+            .expect("deserialize");
+
+        assert_eq!(orig,decoded);
+        assert_eq!(n_bytes,nbytes2);
+    }
+
+    #[test]
+    fn test_sync_events_roundtrip() {
+        let events = [
+            FromDevice::TriggerEvent(TriggerEvent { count: 7, counter: 123456, timestamp_us: 12_345_678, cycles: 0xdead_beef }),
+            FromDevice::SyncPulse(SyncPulse { count: 3, counter: 654321, timestamp_us: 65_432_100, cause: SyncCause::LockLost }),
+        ];
+        for msg in events.iter() {
+            let orig = FromDeviceEnvelope { id: UNSOLICITED_ID, msg: msg.clone() };
+            let mut buf = [0; 32];
+            let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+                .expect("serialize");
+
+            let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+                .expect("deserialize");
+
+            assert_eq!(orig,decoded);
+            assert_eq!(n_bytes,nbytes2);
+        }
+    }
+
+    #[test]
+    fn test_sweep_reply_roundtrip() {
+        let mut result = SweepResult {
+            n_done: 2,
+            n_frequencies: MAX_SWEEP_POINTS as u8,
+            ..SweepResult::default()
+        };
+        result.points[0] = SweepPoint { frequency_hz: 10.0, dac_amplitude: 1000.0, adc_amplitude: 500.0, gain: 0.5, phase_deg: -7.2 };
+        result.points[1] = SweepPoint { frequency_hz: 20.0, dac_amplitude: 1000.0, adc_amplitude: 250.0, gain: 0.25, phase_deg: -14.4 };
+        let orig = FromDeviceEnvelope { id: 1, msg: FromDevice::EchoSweep(result) };
+        let mut buf = [0; MAX_MSG_SIZE];
+        let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+            .expect("serialize");
+
+        let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+            .expect("deserialize");
+
+        assert_eq!(orig,decoded);
+        assert_eq!(n_bytes,nbytes2);
+        // leave room for framing
+        assert!(n_bytes + 16 <= MAX_MSG_SIZE);
+    }
+
+    #[test]
+    fn test_timing_reply_roundtrip() {
+        let orig = FromDeviceEnvelope {
+            id: 1,
+            msg: FromDevice::EchoTiming(TimingStats {
+                iterations_per_second: 10_000,
+                n_iterations: 12345,
+                min_cycles: 1800,
+                mean_cycles: 1900,
+                max_cycles: 4200,
+                missed_deadlines: 2,
+                cpu_hz: 64_000_000,
+            }),
+        };
+        let mut buf = [0; MAX_MSG_SIZE];
+        let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+            .expect("serialize");
+
+        let (decoded, nbytes2): (FromDeviceEnvelope, _) = ssmarshal::deserialize(&buf[0..n_bytes])
+            .expect("deserialize");
+
+        assert_eq!(orig,decoded);
+        assert_eq!(n_bytes,nbytes2);
+    }
+
     fn check_set_device_state(orig: &SetDeviceState) {
-        let mut buf = [0; 256];
+        let mut buf = [0; MAX_MSG_SIZE];
         let n_bytes = ssmarshal::serialize(&mut buf, orig)
             .expect("serialize");
 
@@ -276,14 +1849,105 @@ mod tests {
         dev_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::Proportional);
         check_set_device_state(&dev_state);
 
-        // // check PI controller
-        // dev_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegral);
-        // check_set_device_state(&dev_state);
+        // check PI controller
+        dev_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: PiGains::default(),
+            dac2: PiGains::default(),
+            prediction: None,
+        }));
+        check_set_device_state(&dev_state);
+
+        // check PID controller
+        dev_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegralDerivative(PidParams {
+            dac1: PidGains::default(),
+            dac2: PidGains::default(),
+            prediction: None,
+        }));
+        check_set_device_state(&dev_state);
+
+        // check prediction
+        dev_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegralDerivative(PidParams {
+            dac1: PidGains::default(),
+            dac2: PidGains::default(),
+            prediction: Some(PredictionParams {
+                filter: PredictionFilter::Kalman { process_noise: 0.1, measurement_noise: 4.0 },
+                ..PredictionParams::default()
+            }),
+        }));
+        check_set_device_state(&dev_state);
+
+        // check polynomial calibration
+        dev_state.dac1_angle_func = AngleCalibration::Polynomial(PolynomialCalibration {
+            adc1_center: 2048.0,
+            adc2_center: 2048.0,
+            coeffs: [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0],
+        });
+        check_set_device_state(&dev_state);
+
+        // check lookup table calibration
+        dev_state.dac2_angle_func = AngleCalibration::LookupTable(LutCalibration {
+            adc1_start: 0,
+            adc1_step: core::num::NonZeroU16::new(1024).unwrap(),
+            adc2_start: 0,
+            adc2_step: core::num::NonZeroU16::new(1024).unwrap(),
+            angles: [[-100, -50, 0, 50, 100]; LUT_SIZE],
+        });
+        check_set_device_state(&dev_state);
+    }
+
+    #[test]
+    fn test_largest_state_fits() {
+        let lut = AngleCalibration::LookupTable(LutCalibration {
+            adc1_start: 0,
+            adc1_step: core::num::NonZeroU16::new(1024).unwrap(),
+            adc2_start: 0,
+            adc2_step: core::num::NonZeroU16::new(1024).unwrap(),
+            angles: [[0; LUT_SIZE]; LUT_SIZE],
+        });
+        let search = SearchParams {
+            lock_mode: ClosedLoopMode::ProportionalIntegralDerivative(PidParams {
+                dac1: PidGains::default(),
+                dac2: PidGains::default(),
+                prediction: Some(PredictionParams::default()),
+            }),
+            ..SearchParams::default()
+        };
+        let inner = SetDeviceState {
+            mode: DeviceMode::Search(search.clone()),
+            dac1_angle_func: lut.clone(),
+            dac2_angle_func: lut,
+            target_loss: TargetLossConfig {
+                action: LostAction::Search(search),
+                ..TargetLossConfig::default()
+            },
+            sum_normalization: Some(SumNormalization {
+                adc1_zero: 0,
+                adc2_zero: 0,
+                sum_zero: 0,
+                sum_reference: 0,
+                min_sum: 0,
+            }),
+            ..SetDeviceState::default()
+        };
+        let orig = FromDeviceEnvelope {
+            id: 1,
+            msg: FromDevice::EchoState(DeviceState {
+                inner,
+                lock_point: Some(LockPoint { index: 0, dac1: 0, dac2: 0 }),
+                ..DeviceState::default()
+            }),
+        };
+        let mut buf = [0; MAX_MSG_SIZE];
+        let n_bytes = ssmarshal::serialize(&mut buf, &orig)
+            .expect("serialize");
+
+        // leave room for framing
+        assert!(n_bytes + 16 <= MAX_MSG_SIZE);
     }
 
 
     fn check_device_state(orig: &DeviceState) {
-        let mut buf = [0; 256];
+        let mut buf = [0; MAX_MSG_SIZE];
         let n_bytes = ssmarshal::serialize(&mut buf, orig)
             .expect("serialize");
 
@@ -0,0 +1,1711 @@
+//! The tracking control law.
+//!
+//! This is used by the firmware, and kept free of hardware access so that it
+//! can be tested on the host.
+#![no_std]
+
+use core::num::NonZeroU16;
+
+mod waveform;
+mod prediction;
+mod sweep;
+
+use msectrax_comms::{DeviceState, DeviceMode, ClosedLoopMode, PidGains,
+    SetDeviceState, AngleCalibration, AdcToAngleCalibration,
+    PolynomialCalibration, LutCalibration, LostAction, TargetStatus,
+    SearchParams, SearchPattern, LockPoint, PredictionParams, SumNormalization,
+    AuxMode, SyncTrigger, SyncCause, SyncPulse, SweepResult, LUT_SIZE};
+
+/// Calculate error angle based on the current ADC values and the calibration data
+pub fn to_angle( p: &AngleCalibration, adc1: i16, adc2: i16 ) -> f32 {
+    match p {
+        AngleCalibration::Linear(p) => linear_angle(p, adc1, adc2),
+        AngleCalibration::Polynomial(p) => polynomial_angle(p, adc1, adc2),
+        AngleCalibration::LookupTable(p) => lut_angle(p, adc1, adc2),
+    }
+}
+
+/// The ADC values scaled by the QPD sum, see `SumNormalization`.
+pub fn normalize( p: &SumNormalization, adc1: i16, adc2: i16, adc_sum: i16 ) -> (i16, i16) {
+    let sum = adc_sum as i32 - p.sum_zero as i32;
+    if sum < p.min_sum.max(1) as i32 {
+        return (adc1, adc2);
+    }
+    let scale = p.sum_reference as f32 / sum as f32;
+    let scaled = |adc: i16, zero: i16| {
+        let value = zero as f32 + (adc as i32 - zero as i32) as f32*scale;
+        // Round to the nearest value, the cast saturates at the limits.
+        (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i16
+    };
+    (scaled(adc1, p.adc1_zero), scaled(adc2, p.adc2_zero))
+}
+
+fn linear_angle( p: &AdcToAngleCalibration, adc1: i16, adc2: i16 ) -> f32 {
+    let adc1 = adc1 as f32;
+    let adc2 = adc2 as f32;
+    adc1*p.adc1_gain + adc2*p.adc2_gain + p.offset
+}
+
+fn polynomial_angle( p: &PolynomialCalibration, adc1: i16, adc2: i16 ) -> f32 {
+    let x = adc1 as f32 - p.adc1_center;
+    let y = adc2 as f32 - p.adc2_center;
+    let c = &p.coeffs;
+    c[0] +
+        x*(c[1] + x*(c[3] + x*c[6])) +
+        y*(c[2] + y*(c[5] + y*c[9])) +
+        x*y*(c[4] + x*c[7] + y*c[8])
+}
+
+/// The grid cell containing `adc` and the position within it (0 to 1).
+///
+/// Outside the grid, the nearest cell is returned and the position is
+/// outside 0 to 1.
+fn lut_cell( adc: i16, start: i16, step: NonZeroU16 ) -> (usize, f32) {
+    let step = step.get() as i32;
+    let offset = adc as i32 - start as i32;
+    let cell = clip(offset.div_euclid(step), 0, LUT_SIZE as i32 - 2);
+    let frac = (offset - cell*step) as f32 / step as f32;
+    (cell as usize, frac)
+}
+
+fn lut_angle( p: &LutCalibration, adc1: i16, adc2: i16 ) -> f32 {
+    let (i, u) = lut_cell(adc1, p.adc1_start, p.adc1_step);
+    let (j, v) = lut_cell(adc2, p.adc2_start, p.adc2_step);
+    let angle = |i: usize, j: usize| p.angles[i][j] as f32;
+    angle(i, j)*(1.0 - u)*(1.0 - v) +
+        angle(i + 1, j)*u*(1.0 - v) +
+        angle(i, j + 1)*(1.0 - u)*v +
+        angle(i + 1, j + 1)*u*v
+}
+
+/// Internal state of the PI(D) controller for one axis
+#[derive(Default)]
+pub struct PidState {
+    integrator: f32,
+    last_error: f32,
+    derivative: f32,
+    target: prediction::TargetEstimate,
+}
+
+/// Internal state of the closed loop controllers
+#[derive(Default)]
+pub struct ControllerState {
+    dac1: PidState,
+    dac2: PidState,
+    /// Consecutive ticks with the signal below the target loss threshold.
+    weak_ticks: u32,
+    /// Ticks spent at the current search point.
+    search_ticks: u32,
+    dac1_wave: waveform::WavePhase,
+    dac2_wave: waveform::WavePhase,
+    sweep: sweep::Sweep,
+}
+
+impl ControllerState {
+    /// The results of `DeviceMode::FrequencySweep` so far.
+    pub fn sweep_result( &self ) -> &SweepResult {
+        self.sweep.result()
+    }
+}
+
+/// Position and velocity of one DAC output.
+#[derive(Default)]
+struct AxisOutput {
+    position: i32,
+    velocity: i32,
+}
+
+/// State of the output stage, which follows the commanded DAC values within
+/// the `OutputLimits`.
+///
+/// Unlike `ControllerState`, this is kept when the mode changes so that the
+/// outputs move smoothly to the new mode's values.
+#[derive(Default)]
+pub struct OutputState {
+    dac1: AxisOutput,
+    dac2: AxisOutput,
+}
+
+impl AxisOutput {
+    /// Move towards `target`, at most `max_slew` counts per tick and with
+    /// the velocity changing by at most `max_accel` per tick. Zero means no
+    /// limit.
+    fn update( &mut self, target: i16, max_slew: u16, max_accel: u16, min: i16, max: i16 ) -> i16 {
+        let distance = target as i32 - self.position;
+        let mut max_speed = if max_slew > 0 { max_slew as i32 } else { i32::MAX };
+        if max_accel > 0 {
+            // The fastest speed from which braking by `max_accel` per tick
+            // still stops at the target, from `distance = v*(v + a)/(2*a)`.
+            let a = max_accel as u64;
+            let stop_speed = (isqrt(a*a + 8*a*distance.unsigned_abs() as u64) - a) / 2;
+            max_speed = max_speed.min(stop_speed as i32);
+        }
+        let mut velocity = clip(distance, -max_speed, max_speed);
+        if max_accel > 0 {
+            let a = max_accel as i32;
+            velocity = clip(velocity, self.velocity - a, self.velocity + a);
+        }
+        // Overshooting after a sudden change of the target must still stay
+        // within the limits.
+        let position = clip(self.position + velocity, min as i32, max as i32);
+        self.velocity = position - self.position;
+        self.position = position;
+        position as i16
+    }
+}
+
+/// Update `dac1_out` and `dac2_out` from the commanded `dac1` and `dac2`,
+/// and `dac3_out` from the `AuxConfig`. Called once per control loop tick
+/// after `calculate_next_dac_values`.
+pub fn update_outputs( dev_state: &mut DeviceState, output: &mut OutputState ) {
+    let inner = &dev_state.inner;
+    let limits = &inner.output_limits;
+    let (dac1_max_slew, dac2_max_slew) = if dev_state.parked {
+        let park_slew = inner.heartbeat.park_slew;
+        (park_slew_limit(limits.dac1_max_slew, park_slew), park_slew_limit(limits.dac2_max_slew, park_slew))
+    } else {
+        (limits.dac1_max_slew, limits.dac2_max_slew)
+    };
+    dev_state.dac1_out = output.dac1.update(dev_state.dac1, dac1_max_slew,
+        limits.dac1_max_accel, inner.dac1_min, inner.dac1_max);
+    dev_state.dac2_out = output.dac2.update(dev_state.dac2, dac2_max_slew,
+        limits.dac2_max_accel, inner.dac2_min, inner.dac2_max);
+    let aux = &inner.aux;
+    dev_state.dac3_out = match aux.mode {
+        AuxMode::Disabled => 0,
+        AuxMode::Manual => aux.dac3,
+        AuxMode::LockGated => if is_locked(dev_state) { aux.dac3 } else { aux.dac3_off },
+    };
+}
+
+/// The slew limit while parked, see `HeartbeatConfig::park_slew`.
+fn park_slew_limit( max_slew: u16, park_slew: u16 ) -> u16 {
+    if park_slew == 0 {
+        max_slew
+    } else if max_slew == 0 {
+        park_slew
+    } else {
+        max_slew.min(park_slew)
+    }
+}
+
+/// Whether the closed loop is tracking the target: in closed loop mode and
+/// not parked, the target not lost and neither output at its
+/// `dac*_min/max` limit.
+pub fn is_locked( dev_state: &DeviceState ) -> bool {
+    let inner = &dev_state.inner;
+    match inner.mode {
+        DeviceMode::ClosedLoop(_) if !dev_state.parked => {
+            dev_state.target != TargetStatus::Lost &&
+                inner.dac1_min < dev_state.dac1 && dev_state.dac1 < inner.dac1_max &&
+                inner.dac2_min < dev_state.dac2 && dev_state.dac2 < inner.dac2_max
+        }
+        _ => false,
+    }
+}
+
+/// Counts the control loop ticks since the last frame from the host, see
+/// `HeartbeatConfig`.
+#[derive(Default)]
+pub struct HeartbeatMonitor {
+    ticks: u32,
+}
+
+impl HeartbeatMonitor {
+    /// Call for every valid frame from the host.
+    pub fn reset( &mut self ) {
+        self.ticks = 0;
+    }
+
+    /// Call once per control loop tick, before `calculate_next_dac_values`.
+    ///
+    /// Parks the galvos when the heartbeat times out, returning true then.
+    /// `dev_state.inner` is not changed, so that `QueryState` and
+    /// `SaveConfig` still see the configuration from the host.
+    pub fn tick( &mut self, dev_state: &mut DeviceState ) -> bool {
+        let config = &dev_state.inner.heartbeat;
+        if config.timeout_ms == 0 || dev_state.parked {
+            return false;
+        }
+        self.ticks = self.ticks.saturating_add(1);
+        let timeout_ticks = config.timeout_ms as u64 * dev_state.inner.loop_rate_hz.get() as u64 / 1000;
+        if (self.ticks as u64) < timeout_ticks.max(1) {
+            return false;
+        }
+
+        dev_state.dac1 = config.dac1_park;
+        dev_state.dac2 = config.dac2_park;
+        dev_state.parked = true;
+        true
+    }
+}
+
+/// Apply `ToDevice::SetGalvos`, switching to `DeviceMode::SampleAdc`.
+///
+/// Ignored while parked, returning false, because the park position is held
+/// until `SetState`.
+pub fn set_galvos( dev_state: &mut DeviceState, dac1: i16, dac2: i16 ) -> bool {
+    if dev_state.parked {
+        return false;
+    }
+    dev_state.inner.mode = DeviceMode::SampleAdc;
+    dev_state.dac1 = dac1;
+    dev_state.dac2 = dac2;
+    true
+}
+
+/// Decides when the sync output pulses, see `SyncConfig`.
+#[derive(Default)]
+pub struct SyncOutput {
+    was_locked: bool,
+    /// Ticks since the last periodic pulse.
+    ticks: u32,
+    /// Remaining ticks of the current pulse.
+    high_ticks: u32,
+    count: u32,
+}
+
+impl SyncOutput {
+    /// Call once per control loop tick after `update_outputs`, with the
+    /// sample counter of the tick. Returns the pulse which starts at this
+    /// tick, if any.
+    ///
+    /// A new pulse during the previous one extends it.
+    pub fn tick( &mut self, dev_state: &DeviceState, counter: u32 ) -> Option<SyncPulse> {
+        let config = &dev_state.inner.sync;
+        let locked = is_locked(dev_state);
+        let cause = match config.pulse_on {
+            SyncTrigger::Never => None,
+            SyncTrigger::LockAcquired if locked && !self.was_locked => Some(SyncCause::LockAcquired),
+            SyncTrigger::LockLost if !locked && self.was_locked => Some(SyncCause::LockLost),
+            SyncTrigger::LockAcquired | SyncTrigger::LockLost => None,
+            SyncTrigger::EveryNTicks(n) => {
+                let phase = self.ticks % n.get();
+                self.ticks = phase + 1;
+                if phase == 0 { Some(SyncCause::Periodic) } else { None }
+            }
+        };
+        self.was_locked = locked;
+
+        self.high_ticks = self.high_ticks.saturating_sub(1);
+        let cause = cause?;
+        self.high_ticks = us_to_ticks(&dev_state.inner, config.pulse_us);
+        self.count = self.count.wrapping_add(1);
+        Some(SyncPulse { count: self.count, counter, timestamp_us: dev_state.timestamp_us, cause })
+    }
+
+    /// Whether the output is high after the last `tick`.
+    pub fn is_high( &self ) -> bool {
+        self.high_ticks > 0
+    }
+}
+
+/// Calculate the next PID output for one axis.
+///
+/// `feedforward` is added to the integrator together with the integral term.
+/// The integrator is not updated while the output is clipped at `min` or
+/// `max` and the increment would drive it further into the limit
+/// (anti-windup).
+pub fn pid_update( gains: &PidGains, state: &mut PidState, error: f32, feedforward: f32, initial: i16, min: i16, max: i16 ) -> f32 {
+    let raw_derivative = gains.kd*(error - state.last_error);
+    state.derivative = gains.derivative_filter*state.derivative + (1.0 - gains.derivative_filter)*raw_derivative;
+    state.last_error = error;
+
+    let proportional = gains.kp*error;
+    let increment = gains.ki*error + feedforward;
+    let integrator = clip(state.integrator + increment, gains.integrator_min, gains.integrator_max);
+    let unclipped = initial as f32 + proportional + integrator + state.derivative;
+    let winding_up = (unclipped > max as f32 && increment > 0.0) ||
+        (unclipped < min as f32 && increment < 0.0);
+    if !winding_up {
+        state.integrator = integrator;
+    }
+
+    initial as f32 + proportional + state.integrator + state.derivative
+}
+
+/// Estimate the target's velocity, before `pid_update`. Returns the
+/// feedforward for the integrator and the lead to add to the PID output.
+fn feed_forward( params: &PredictionParams, counts_per_error: f32, state: &mut PidState, error: f32, dac_out: i16 ) -> (f32, f32) {
+    let velocity = state.target.update(&params.filter, dac_out as f32 + counts_per_error*error);
+    (params.velocity_gain*velocity, params.lead_updates*velocity)
+}
+
+/// The signal quality metric, see `DeviceState::signal`.
+pub fn signal_level( adc1: i16, adc2: i16 ) -> u32 {
+    (adc1 as i32).unsigned_abs() + (adc2 as i32).unsigned_abs()
+}
+
+/// Update `signal` and `target` from the current ADC values.
+fn update_target_status( dev_state: &mut DeviceState, controller: &mut ControllerState ) {
+    let config = &dev_state.inner.target_loss;
+    dev_state.signal = signal_level(dev_state.adc1, dev_state.adc2);
+    if dev_state.signal >= config.threshold {
+        controller.weak_ticks = 0;
+        dev_state.target = TargetStatus::Present;
+    } else {
+        controller.weak_ticks = controller.weak_ticks.saturating_add(1);
+        dev_state.target = if controller.weak_ticks >= us_to_ticks(&dev_state.inner, config.lost_after_us) {
+            TargetStatus::Lost
+        } else {
+            TargetStatus::Weak
+        };
+    }
+}
+
+/// Largest integer whose square is at most `n`.
+fn isqrt( n: u64 ) -> u64 {
+    let mut root = 0u64;
+    let mut bit = 1u64 << 31;
+    while bit > 0 {
+        let candidate = root | bit;
+        if candidate*candidate <= n {
+            root = candidate;
+        }
+        bit >>= 1;
+    }
+    root
+}
+
+/// Grid position of point `index` of a square spiral, starting at the centre.
+fn spiral_point( index: u32 ) -> (i32, i32) {
+    if index == 0 {
+        return (0, 0);
+    }
+    // Ring `k` holds the points from `(2k-1)^2` up to `(2k+1)^2`, starting
+    // next to the end of the previous ring.
+    let k = isqrt(index as u64).div_ceil(2) as i32;
+    let m = (index - (2*k as u32 - 1).pow(2)) as i32;
+    let side = 2*k;
+    match m / side {
+        0 => (k, -k + 1 + m),
+        1 => (k - 1 - (m - side), k),
+        2 => (-k, k - 1 - (m - 2*side)),
+        _ => (-k + 1 + (m - 3*side), -k),
+    }
+}
+
+/// Grid position of point `index` of a raster, alternating direction.
+fn raster_point( index: u32, radius: u16 ) -> (i32, i32) {
+    let width = 2*radius as u32 + 1;
+    let row = index / width;
+    let mut col = index % width;
+    if row % 2 == 1 {
+        col = width - 1 - col;
+    }
+    (col as i32 - radius as i32, row as i32 - radius as i32)
+}
+
+/// Number of points in the search pattern.
+pub fn search_len( params: &SearchParams ) -> u64 {
+    let width = 2*params.radius as u64 + 1;
+    width*width
+}
+
+/// The DAC values of point `index` of the search pattern, which repeats.
+pub fn search_point( params: &SearchParams, index: u32 ) -> (i16, i16) {
+    let index = (index as u64 % search_len(params)) as u32;
+    let (x, y) = match params.pattern {
+        SearchPattern::Spiral => spiral_point(index),
+        SearchPattern::Raster => raster_point(index, params.radius),
+    };
+    let step = params.step.get() as i32;
+    let dac = |center: i16, offset: i32| {
+        clip(center as i32 + offset*step, i16::MIN as i32, i16::MAX as i32) as i16
+    };
+    (dac(params.dac1_center, x), dac(params.dac2_center, y))
+}
+
+/// Switch modes from within the control loop.
+///
+/// The closed loop starts tracking from the current DAC values.
+fn enter_mode( dev_state: &mut DeviceState, cl_next_update_cycle: &mut u32, controller: &mut ControllerState, mode: DeviceMode ) {
+    match &mode {
+        DeviceMode::ClosedLoop(_) => {
+            let inner = &dev_state.inner;
+            dev_state.dac1_f32 = dev_state.dac1 as f32;
+            dev_state.dac2_f32 = dev_state.dac2 as f32;
+            controller.dac1 = PidState {
+                integrator: (dev_state.dac1 as i32 - inner.dac1_initial as i32) as f32,
+                ..PidState::default()
+            };
+            controller.dac2 = PidState {
+                integrator: (dev_state.dac2 as i32 - inner.dac2_initial as i32) as f32,
+                ..PidState::default()
+            };
+            controller.weak_ticks = 0;
+            dev_state.target = TargetStatus::Present;
+            dev_state.cl_cycles = 0;
+        }
+        DeviceMode::Search(_) => {
+            dev_state.search_index = 0;
+            controller.search_ticks = 0;
+        }
+        DeviceMode::SawtoothTest | DeviceMode::SampleAdc | DeviceMode::Waveform(_) |
+            DeviceMode::FrequencySweep(_) => {}
+    }
+    dev_state.inner.mode = mode;
+    *cl_next_update_cycle = calc_next_update(dev_state);
+}
+
+/// update galvos, called once per control loop tick
+///
+/// `wave_table` holds the values for `WaveShape::Table`.
+pub fn calculate_next_dac_values( dev_state: &mut DeviceState, cl_next_update_cycle: &mut u32, controller: &mut ControllerState, wave_table: &[i16] ) {
+
+    update_target_status(dev_state, controller);
+
+    if dev_state.parked {
+        // Hold the park position set by `HeartbeatMonitor` until `SetState`.
+        return;
+    }
+
+    let mut next_mode = None;
+    match &dev_state.inner.mode {
+        DeviceMode::SawtoothTest => {
+            dev_state.dac1 = dev_state.dac1.wrapping_add(10);
+            dev_state.dac2 = dev_state.dac2.wrapping_add(20);
+        }
+        DeviceMode::SampleAdc => {}
+        DeviceMode::Waveform(params) => {
+            let loop_rate_hz = dev_state.inner.loop_rate_hz.get();
+            dev_state.dac1 = controller.dac1_wave.next_value(&params.dac1, loop_rate_hz, wave_table);
+            dev_state.dac2 = controller.dac2_wave.next_value(&params.dac2, loop_rate_hz, wave_table);
+        }
+        DeviceMode::FrequencySweep(params) => {
+            let (dac1, dac2) = controller.sweep.next_values(params, dev_state);
+            dev_state.dac1 = dac1;
+            dev_state.dac2 = dac2;
+        }
+        DeviceMode::ClosedLoop(cl_params) => {
+            dev_state.cl_cycles = dev_state.cl_cycles.wrapping_add(1);
+
+            if dev_state.cl_cycles == *cl_next_update_cycle {
+                *cl_next_update_cycle = cl_next_update_cycle.wrapping_add(cl_period_ticks(&dev_state.inner));
+
+                if dev_state.target == TargetStatus::Lost {
+                    // The target may move anywhere until it is found again.
+                    controller.dac1.target = prediction::TargetEstimate::default();
+                    controller.dac2.target = prediction::TargetEstimate::default();
+                    match &dev_state.inner.target_loss.action {
+                        LostAction::Hold => {}
+                        LostAction::ReturnToInitial => {
+                            dev_state.dac1_f32 = dev_state.inner.dac1_initial as f32;
+                            dev_state.dac2_f32 = dev_state.inner.dac2_initial as f32;
+                            dev_state.dac1 = dev_state.inner.dac1_initial;
+                            dev_state.dac2 = dev_state.inner.dac2_initial;
+                            controller.dac1 = PidState::default();
+                            controller.dac2 = PidState::default();
+                        }
+                        LostAction::Search(params) => {
+                            next_mode = Some(DeviceMode::Search(params.clone()));
+                        }
+                    }
+                } else {
+                    let (adc1, adc2) = match &dev_state.inner.sum_normalization {
+                        Some(p) => normalize(p, dev_state.adc1, dev_state.adc2, dev_state.adc_sum),
+                        None => (dev_state.adc1, dev_state.adc2),
+                    };
+                    let azimuth_error = to_angle( &dev_state.inner.dac1_angle_func, adc1, adc2 ) - dev_state.inner.dac1_initial as f32;
+                    let elevation_error = to_angle( &dev_state.inner.dac2_angle_func, adc1, adc2 ) - dev_state.inner.dac2_initial as f32;
+
+                    let pid = match cl_params {
+                        ClosedLoopMode::Proportional => None,
+                        ClosedLoopMode::ProportionalIntegral(params) => Some((
+                            PidGains::from(params.dac1.clone()),
+                            PidGains::from(params.dac2.clone()),
+                            params.prediction.as_ref())),
+                        ClosedLoopMode::ProportionalIntegralDerivative(params) => Some((
+                            params.dac1.clone(),
+                            params.dac2.clone(),
+                            params.prediction.as_ref())),
+                    };
+                    match pid {
+                        None => {
+                            dev_state.dac1_f32 += azimuth_error*dev_state.inner.dac1_angle_gain;
+                            dev_state.dac2_f32 += elevation_error*dev_state.inner.dac2_angle_gain;
+                        },
+                        Some((dac1_gains, dac2_gains, prediction)) => {
+                            let inner = &dev_state.inner;
+                            let ((dac1_ff, dac1_lead), (dac2_ff, dac2_lead)) = match prediction {
+                                Some(p) => (
+                                    feed_forward(p, p.dac1_counts_per_error, &mut controller.dac1,
+                                        azimuth_error, dev_state.dac1_out),
+                                    feed_forward(p, p.dac2_counts_per_error, &mut controller.dac2,
+                                        elevation_error, dev_state.dac2_out),
+                                ),
+                                None => ((0.0, 0.0), (0.0, 0.0)),
+                            };
+                            dev_state.dac1_f32 = pid_update(&dac1_gains, &mut controller.dac1,
+                                azimuth_error, dac1_ff, inner.dac1_initial, inner.dac1_min, inner.dac1_max) + dac1_lead;
+                            dev_state.dac2_f32 = pid_update(&dac2_gains, &mut controller.dac2,
+                                elevation_error, dac2_ff, inner.dac2_initial, inner.dac2_min, inner.dac2_max) + dac2_lead;
+                        },
+                    }
+
+                    dev_state.dac1 = dev_state.dac1_f32 as i16;
+                    dev_state.dac2 = dev_state.dac2_f32 as i16;
+                }
+                dev_state.dac1_target_velocity = controller.dac1.target.velocity();
+                dev_state.dac2_target_velocity = controller.dac2.target.velocity();
+            }
+        }
+        DeviceMode::Search(params) => {
+            // The ADCs see the point set on an earlier tick, so the lock
+            // check waits for one tick at each point.
+            if controller.search_ticks > 0 && dev_state.signal >= params.lock_threshold {
+                dev_state.lock_point = Some(LockPoint {
+                    index: dev_state.search_index,
+                    dac1: dev_state.dac1,
+                    dac2: dev_state.dac2,
+                });
+                next_mode = Some(DeviceMode::ClosedLoop(params.lock_mode.clone()));
+            } else {
+                if controller.search_ticks >= us_to_ticks(&dev_state.inner, params.dwell_us) {
+                    dev_state.search_index = ((dev_state.search_index as u64 + 1) % search_len(params)) as u32;
+                    controller.search_ticks = 0;
+                }
+                let (dac1, dac2) = search_point(params, dev_state.search_index);
+                dev_state.dac1 = dac1;
+                dev_state.dac2 = dac2;
+                controller.search_ticks += 1;
+            }
+        }
+    }
+
+    if let Some(mode) = next_mode {
+        enter_mode(dev_state, cl_next_update_cycle, controller, mode);
+    }
+
+    // clip dac values
+    dev_state.dac1 = clip(dev_state.dac1,
+        dev_state.inner.dac1_min,
+        dev_state.inner.dac1_max);
+    dev_state.dac2 = clip(dev_state.dac2,
+        dev_state.inner.dac2_min,
+        dev_state.inner.dac2_max);
+
+}
+
+/// The device state right after `SetState`, with the DACs at their initial
+/// values.
+pub fn initial_state(inner: SetDeviceState) -> DeviceState {
+    let dac1 = inner.dac1_initial;
+    let dac2 = inner.dac2_initial;
+    DeviceState {
+        inner,
+        cl_cycles: 0,
+        timestamp_us: 0,
+        adc1: 0,
+        adc2: 0,
+        adc_sum: 0,
+        adc_oversampling: 1,
+        adc_sample_rate_hz: 0,
+        dac1,
+        dac2,
+        dac1_out: dac1,
+        dac2_out: dac2,
+        dac3_out: 0,
+        dac1_f32: dac1 as f32,
+        dac2_f32: dac2 as f32,
+        dac1_target_velocity: 0.0,
+        dac2_target_velocity: 0.0,
+        signal: 0,
+        target: TargetStatus::Present,
+        search_index: 0,
+        lock_point: None,
+        parked: false,
+    }
+}
+
+/// The `cl_cycles` value of the first closed loop update after `state`.
+pub fn calc_next_update(state: &DeviceState) -> u32 {
+    state.cl_cycles.wrapping_add(cl_period_ticks(&state.inner))
+}
+
+/// The closed loop update period in control loop ticks, at least one.
+pub fn cl_period_ticks(inner: &SetDeviceState) -> u32 {
+    us_to_ticks(inner, inner.cl_period_us.get())
+}
+
+/// A duration in control loop ticks, at least one.
+fn us_to_ticks(inner: &SetDeviceState, us: u32) -> u32 {
+    let ticks = us as u64 * inner.loop_rate_hz.get() as u64 / 1_000_000;
+    ticks.max(1).min(u32::MAX as u64) as u32
+}
+
+/// Limit `cur` to the range from `min` to `max`.
+pub fn clip<R>(cur: R, min: R, max: R) -> R
+    where
+        R: core::cmp::PartialOrd,
+{
+    if cur < min {
+        return min;
+    }
+    if cur > max {
+        return max;
+    }
+    cur
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU32;
+    use msectrax_comms::{PiGains, PiParams, TargetLossConfig, OutputLimits,
+        WaveformParams, AxisWaveform, WaveShape, PredictionFilter, HeartbeatConfig,
+        AuxConfig, SyncConfig};
+
+    /// A spiral search with a step of 100 and 2 ticks at each point.
+    fn search_params(pattern: SearchPattern) -> SearchParams {
+        SearchParams {
+            pattern,
+            dac1_center: 1000,
+            dac2_center: -1000,
+            step: NonZeroU16::new(100).unwrap(),
+            radius: 3,
+            dwell_us: 200,
+            lock_threshold: 1000,
+            lock_mode: ClosedLoopMode::Proportional,
+        }
+    }
+
+    /// A target which is only visible with the galvos near `target`.
+    fn spot(state: &mut DeviceState, target: (i16, i16)) {
+        let near = (state.dac1 - target.0).abs() < 50 && (state.dac2 - target.1).abs() < 50;
+        state.adc1 = if near { 2000 } else { 0 };
+        state.adc2 = 0;
+    }
+
+    /// A closed loop state where the azimuth angle is ADC1 and the elevation
+    /// angle is ADC2. Updates on every tick unless `cl_period_us` is changed.
+    fn closed_loop_state(mode: ClosedLoopMode) -> SetDeviceState {
+        SetDeviceState {
+            mode: DeviceMode::ClosedLoop(mode),
+            cl_period_us: NonZeroU32::new(100).unwrap(),
+            dac1_angle_func: AngleCalibration::Linear(
+                AdcToAngleCalibration { adc1_gain: 1.0, adc2_gain: 0.0, offset: 0.0 }),
+            dac2_angle_func: AngleCalibration::Linear(
+                AdcToAngleCalibration { adc1_gain: 0.0, adc2_gain: 1.0, offset: 0.0 }),
+            dac1_angle_gain: 0.5,
+            dac2_angle_gain: 0.5,
+            ..SetDeviceState::default()
+        }
+    }
+
+    /// A simple plant: the ADCs see how far the galvos are from `target`,
+    /// offset by the initial DAC values.
+    fn plant(state: &mut DeviceState, target: (i16, i16)) {
+        let adc = |initial: i16, target: i16, dac: i16| {
+            clip(initial as i32 + target as i32 - dac as i32,
+                i16::MIN as i32, i16::MAX as i32) as i16
+        };
+        state.adc1 = adc(state.inner.dac1_initial, target.0, state.dac1);
+        state.adc2 = adc(state.inner.dac2_initial, target.1, state.dac2);
+    }
+
+    /// Run the control loop for `n_ticks` against the plant.
+    fn run(state: &mut DeviceState, next: &mut u32, controller: &mut ControllerState,
+        target: (i16, i16), n_ticks: usize)
+    {
+        for _ in 0..n_ticks {
+            plant(state, target);
+            calculate_next_dac_values(state, next, controller, &[]);
+        }
+    }
+
+    #[test]
+    fn test_clip() {
+        assert_eq!(clip(5, 0, 10), 5);
+        assert_eq!(clip(-5, 0, 10), 0);
+        assert_eq!(clip(15, 0, 10), 10);
+        assert_eq!(clip(1.5, -1.0, 1.0), 1.0);
+        assert_eq!(clip(i16::MIN, -100, 100), -100);
+    }
+
+    #[test]
+    fn test_linear_angle() {
+        let p = AngleCalibration::Linear(
+            AdcToAngleCalibration { adc1_gain: 2.0, adc2_gain: -1.0, offset: 10.0 });
+        assert_eq!(to_angle(&p, 100, 50), 160.0);
+    }
+
+    #[test]
+    fn test_polynomial_angle() {
+        let coeffs = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0];
+        let p = AngleCalibration::Polynomial(PolynomialCalibration {
+            adc1_center: 100.0,
+            adc2_center: 200.0,
+            coeffs,
+        });
+        for &(adc1, adc2) in [(100, 200), (103, 195), (90, 210)].iter() {
+            let x = (adc1 - 100) as f32;
+            let y = (adc2 - 200) as f32;
+            let terms = [1.0, x, y, x*x, x*y, y*y, x*x*x, x*x*y, x*y*y, y*y*y];
+            let expected: f32 = terms.iter().zip(coeffs.iter()).map(|(t, c)| t*c).sum();
+            assert_near(to_angle(&p, adc1, adc2), expected);
+        }
+    }
+
+    fn sum_normalization() -> SumNormalization {
+        SumNormalization {
+            adc1_zero: 2048,
+            adc2_zero: 2000,
+            sum_zero: 100,
+            sum_reference: 1000,
+            min_sum: 50,
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let p = sum_normalization();
+        // at the reference sum
+        assert_eq!(normalize(&p, 2148, 1900, 1100), (2148, 1900));
+        // half the light gives the same values
+        assert_eq!(normalize(&p, 2098, 1950, 600), (2148, 1900));
+        // rounded
+        assert_eq!(normalize(&p, 2049, 2000, 400), (2051, 2000));
+        // too little light
+        assert_eq!(normalize(&p, 2098, 1950, 149), (2098, 1950));
+        assert_eq!(normalize(&p, 2098, 1950, -1000), (2098, 1950));
+        // saturates
+        assert_eq!(normalize(&p, i16::MAX, i16::MIN, 150), (i16::MAX, i16::MIN));
+    }
+
+    #[test]
+    fn test_closed_loop_normalized() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.sum_normalization = Some(SumNormalization {
+            adc1_zero: 0,
+            adc2_zero: 0,
+            sum_zero: 0,
+            sum_reference: 1000,
+            min_sum: 1,
+        });
+        inner.dac1_angle_gain = 1.0;
+        inner.dac2_angle_gain = 1.0;
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        // the same step with twice the reference light
+        state.adc1 = 200;
+        state.adc2 = -100;
+        state.adc_sum = 2000;
+        calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        assert_eq!((state.dac1, state.dac2), (100, -50));
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        let diff = actual - expected;
+        assert!(-1e-2 < diff && diff < 1e-2, "{} != {}", actual, expected);
+    }
+
+    /// A lookup table with angle = `adc1 + 2*adc2`
+    fn linear_lut() -> AngleCalibration {
+        let mut angles = [[0; LUT_SIZE]; LUT_SIZE];
+        for (i, row) in angles.iter_mut().enumerate() {
+            for (j, angle) in row.iter_mut().enumerate() {
+                let adc1 = -100 + 50*i as i16;
+                let adc2 = 1000 + 100*j as i16;
+                *angle = adc1 + 2*adc2;
+            }
+        }
+        AngleCalibration::LookupTable(LutCalibration {
+            adc1_start: -100,
+            adc1_step: NonZeroU16::new(50).unwrap(),
+            adc2_start: 1000,
+            adc2_step: NonZeroU16::new(100).unwrap(),
+            angles,
+        })
+    }
+
+    #[test]
+    fn test_lut_angle() {
+        let p = linear_lut();
+        // grid points, including the last
+        assert_eq!(to_angle(&p, -100, 1000), 1900.0);
+        assert_eq!(to_angle(&p, 0, 1200), 2400.0);
+        assert_eq!(to_angle(&p, 100, 1400), 2900.0);
+        // between grid points
+        assert_eq!(to_angle(&p, -75, 1050), 2025.0);
+        assert_near(to_angle(&p, 33, 1333), 2699.0);
+        // extrapolated beyond the grid
+        assert_eq!(to_angle(&p, -150, 900), 1650.0);
+        assert_eq!(to_angle(&p, 200, 1600), 3400.0);
+    }
+
+    #[test]
+    fn test_lut_bilinear() {
+        let mut angles = [[0; LUT_SIZE]; LUT_SIZE];
+        angles[1][1] = 100;
+        let p = AngleCalibration::LookupTable(LutCalibration {
+            adc1_start: 0,
+            adc1_step: NonZeroU16::new(10).unwrap(),
+            adc2_start: 0,
+            adc2_step: NonZeroU16::new(10).unwrap(),
+            angles,
+        });
+        assert_eq!(to_angle(&p, 10, 10), 100.0);
+        assert_eq!(to_angle(&p, 5, 5), 25.0);
+        assert_eq!(to_angle(&p, 15, 10), 50.0);
+        assert_eq!(to_angle(&p, 25, 25), 0.0);
+    }
+
+    #[test]
+    fn test_cl_period_ticks() {
+        let mut inner = SetDeviceState {
+            loop_rate_hz: NonZeroU32::new(10_000).unwrap(),
+            cl_period_us: NonZeroU32::new(1000).unwrap(),
+            ..SetDeviceState::default()
+        };
+        assert_eq!(cl_period_ticks(&inner), 10);
+
+        // shorter than one tick
+        inner.cl_period_us = NonZeroU32::new(1).unwrap();
+        assert_eq!(cl_period_ticks(&inner), 1);
+
+        // longer than fits in u32
+        inner.loop_rate_hz = NonZeroU32::new(u32::MAX).unwrap();
+        inner.cl_period_us = NonZeroU32::new(u32::MAX).unwrap();
+        assert_eq!(cl_period_ticks(&inner), u32::MAX);
+    }
+
+    #[test]
+    fn test_cl_cycles_wraparound() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.cl_period_us = NonZeroU32::new(200).unwrap(); // 2 ticks
+        let mut state = initial_state(inner);
+        state.cl_cycles = u32::MAX - 1;
+        let mut next = calc_next_update(&state);
+        assert_eq!(next, 0);
+        let mut controller = ControllerState::default();
+
+        // no update at u32::MAX
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 1);
+        assert_eq!(state.cl_cycles, u32::MAX);
+        assert_eq!(state.dac1, 0);
+
+        // update after wrapping to zero
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 1);
+        assert_eq!(state.cl_cycles, 0);
+        assert_eq!(state.dac1, 500);
+        assert_eq!(next, 2);
+    }
+
+    #[test]
+    fn test_cl_period() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.cl_period_us = NonZeroU32::new(300).unwrap(); // 3 ticks
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        let mut n_updates = 0;
+        for _ in 0..9 {
+            let last_dac1 = state.dac1;
+            run(&mut state, &mut next, &mut controller, (10_000, 0), 1);
+            if state.dac1 != last_dac1 {
+                n_updates += 1;
+            }
+        }
+        assert_eq!(n_updates, 3);
+        assert_eq!(state.cl_cycles, 9);
+    }
+
+    #[test]
+    fn test_sawtooth_mode() {
+        let inner = SetDeviceState {
+            mode: DeviceMode::SawtoothTest,
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        state.dac1 = i16::MAX - 5;
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        assert_eq!(state.dac1, i16::MIN + 4);
+        assert_eq!(state.dac2, 20);
+        assert_eq!(state.cl_cycles, 0);
+    }
+
+    #[test]
+    fn test_sample_adc_mode() {
+        let inner = SetDeviceState {
+            mode: DeviceMode::SampleAdc,
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        state.dac1 = 123;
+        state.dac2 = -456;
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 10);
+        assert_eq!((state.dac1, state.dac2), (123, -456));
+        assert_eq!(state.cl_cycles, 0);
+    }
+
+    #[test]
+    fn test_clips_output() {
+        let inner = SetDeviceState {
+            mode: DeviceMode::SampleAdc,
+            dac1_min: -100,
+            dac1_max: 100,
+            dac2_min: -200,
+            dac2_max: 200,
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        state.dac1 = 1000;
+        state.dac2 = -1000;
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        assert_eq!((state.dac1, state.dac2), (100, -200));
+
+        // also in closed loop
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.dac1_max = 100;
+        inner.dac2_min = -200;
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        run(&mut state, &mut next, &mut controller, (1000, -1000), 50);
+        assert_eq!((state.dac1, state.dac2), (100, -200));
+    }
+
+    #[test]
+    fn test_p_loop_converges() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.dac1_initial = -300;
+        inner.dac2_initial = 400;
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        let target = (1000, -2000);
+        run(&mut state, &mut next, &mut controller, target, 100);
+        assert!((state.dac1 - target.0).abs() <= 1, "dac1 {}", state.dac1);
+        assert!((state.dac2 - target.1).abs() <= 1, "dac2 {}", state.dac2);
+    }
+
+    #[test]
+    fn test_pi_loop_converges() {
+        let gains = PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() };
+        let inner = closed_loop_state(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: gains.clone(),
+            dac2: gains,
+            prediction: None,
+        }));
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        let target = (1000, -2000);
+        run(&mut state, &mut next, &mut controller, target, 200);
+        assert!((state.dac1 - target.0).abs() <= 1, "dac1 {}", state.dac1);
+        assert!((state.dac2 - target.1).abs() <= 1, "dac2 {}", state.dac2);
+    }
+
+    #[test]
+    fn test_pi_anti_windup() {
+        let gains = PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() };
+        let mut inner = closed_loop_state(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: gains.clone(),
+            dac2: gains,
+            prediction: None,
+        }));
+        inner.dac1_max = 1000;
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        // a target out of reach keeps the output at its limit...
+        run(&mut state, &mut next, &mut controller, (5000, 0), 500);
+        assert_eq!(state.dac1, 1000);
+
+        // ...without winding up, so it leaves the limit right away
+        run(&mut state, &mut next, &mut controller, (0, 0), 2);
+        assert!(state.dac1 < 1000, "dac1 {}", state.dac1);
+    }
+
+    #[test]
+    fn test_pi_anti_windup_with_prediction() {
+        let gains = PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() };
+        let mut inner = closed_loop_state(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: gains.clone(),
+            dac2: gains,
+            prediction: Some(PredictionParams::default()),
+        }));
+        inner.dac1_max = 1000;
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+
+        // a target moving away keeps the output at its limit...
+        for i in 0..500 {
+            plant(&mut state, (1000 + 20*i, 0));
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+        }
+        assert_eq!(state.dac1, 1000);
+        assert!(state.dac1_target_velocity > 10.0, "velocity {}", state.dac1_target_velocity);
+
+        // ...without the velocity winding up the integrator
+        assert!(controller.dac1.integrator < 1000.0, "integrator {}", controller.dac1.integrator);
+        for _ in 0..2 {
+            plant(&mut state, (0, 0));
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+        }
+        assert!(state.dac1 < 1000, "dac1 {}", state.dac1);
+    }
+
+    /// The largest tracking error seen by the ADCs over the second half of a
+    /// ramp of 20 counts per tick, and the final velocity estimate.
+    fn ramp_lag(prediction: Option<PredictionParams>) -> (i16, f32) {
+        let gains = PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() };
+        let inner = closed_loop_state(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: gains.clone(),
+            dac2: gains,
+            prediction,
+        }));
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+
+        let mut lag = 0;
+        for i in 0..400 {
+            let target = (20*i as i16 - 4000, 0);
+            plant(&mut state, target);
+            if i >= 200 {
+                lag = lag.max(state.adc1.abs());
+            }
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+        }
+        (lag, state.dac1_target_velocity)
+    }
+
+    #[test]
+    fn test_prediction_removes_lag() {
+        let (lag, velocity) = ramp_lag(None);
+        assert!(lag > 50, "lag {}", lag);
+        assert_eq!(velocity, 0.0);
+
+        let filters = [
+            PredictionFilter::AlphaBeta { alpha: 0.5, beta: 0.1 },
+            PredictionFilter::Kalman { process_noise: 0.01, measurement_noise: 1.0 },
+        ];
+        for filter in filters.iter() {
+            let (lag, velocity) = ramp_lag(Some(PredictionParams {
+                filter: filter.clone(),
+                ..PredictionParams::default()
+            }));
+            assert!(lag <= 2, "{:?} lag {}", filter, lag);
+            assert!((velocity - 20.0).abs() < 0.1, "{:?} velocity {}", filter, velocity);
+        }
+    }
+
+    #[test]
+    fn test_prediction_reset_on_target_loss() {
+        let mut inner = target_loss_state(LostAction::Hold);
+        inner.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegral(PiParams {
+            dac1: PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() },
+            dac2: PiGains::default(),
+            prediction: Some(PredictionParams::default()),
+        }));
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+
+        for i in 0..50 {
+            plant(&mut state, (500 + 10*i, -500));
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+        }
+        assert!(state.dac1_target_velocity > 5.0, "velocity {}", state.dac1_target_velocity);
+
+        lose_target(&mut state, &mut next, &mut controller);
+        assert_eq!(state.dac1_target_velocity, 0.0);
+    }
+
+    /// A proportional closed loop which loses the target after 3 ticks with a
+    /// signal below 100.
+    fn target_loss_state(action: LostAction) -> SetDeviceState {
+        SetDeviceState {
+            dac1_initial: 500,
+            dac2_initial: -500,
+            target_loss: TargetLossConfig {
+                threshold: 100,
+                lost_after_us: 300,
+                action,
+            },
+            ..closed_loop_state(ClosedLoopMode::Proportional)
+        }
+    }
+
+    /// Run the control loop with no signal until the target is lost.
+    fn lose_target(state: &mut DeviceState, next: &mut u32, controller: &mut ControllerState) {
+        for expected in [TargetStatus::Weak, TargetStatus::Weak, TargetStatus::Lost].iter() {
+            state.adc1 = 0;
+            state.adc2 = 0;
+            calculate_next_dac_values(state, next, controller, &[]);
+            assert_eq!(state.target, *expected);
+            assert_eq!(state.signal, 0);
+        }
+    }
+
+    #[test]
+    fn test_signal_level() {
+        assert_eq!(signal_level(0, 0), 0);
+        assert_eq!(signal_level(-100, 50), 150);
+        assert_eq!(signal_level(i16::MIN, i16::MIN), 65536);
+    }
+
+    #[test]
+    fn test_target_loss_hold() {
+        let mut state = initial_state(target_loss_state(LostAction::Hold));
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 20);
+        assert_eq!(state.target, TargetStatus::Present);
+
+        lose_target(&mut state, &mut next, &mut controller);
+        let held = (state.dac1, state.dac2);
+        for _ in 0..10 {
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            assert_eq!(state.target, TargetStatus::Lost);
+            assert_eq!((state.dac1, state.dac2), held);
+        }
+
+        // tracking resumes with the signal
+        let target = (1000, 1000);
+        run(&mut state, &mut next, &mut controller, target, 100);
+        assert_eq!(state.target, TargetStatus::Present);
+        assert!((state.dac1 - target.0).abs() <= 1, "dac1 {}", state.dac1);
+        assert!((state.dac2 - target.1).abs() <= 1, "dac2 {}", state.dac2);
+    }
+
+    #[test]
+    fn test_target_loss_return_to_initial() {
+        let mut state = initial_state(target_loss_state(LostAction::ReturnToInitial));
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 20);
+        assert_ne!((state.dac1, state.dac2), (500, -500));
+
+        lose_target(&mut state, &mut next, &mut controller);
+        assert_eq!((state.dac1, state.dac2), (500, -500));
+        assert_eq!((state.dac1_f32, state.dac2_f32), (500.0, -500.0));
+    }
+
+    #[test]
+    fn test_target_loss_disabled() {
+        let mut state = initial_state(closed_loop_state(ClosedLoopMode::Proportional));
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        for _ in 0..1000 {
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            assert_eq!(state.target, TargetStatus::Present);
+        }
+    }
+
+    #[test]
+    fn test_isqrt() {
+        for n in 0..10_000u64 {
+            let root = isqrt(n);
+            assert!(root*root <= n && (root + 1)*(root + 1) > n, "isqrt({}) = {}", n, root);
+        }
+        assert_eq!(isqrt(u32::MAX as u64), 65535);
+        assert_eq!(isqrt(u64::MAX), u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_spiral_pattern() {
+        let params = SearchParams { dac1_center: 0, dac2_center: 0,
+            step: NonZeroU16::new(1).unwrap(), radius: 2, ..search_params(SearchPattern::Spiral) };
+        assert_eq!(search_len(&params), 25);
+        let mut points = [(0, 0); 25];
+        for (i, point) in points.iter_mut().enumerate() {
+            *point = search_point(&params, i as u32);
+        }
+        assert_eq!(points[0], (0, 0));
+        for (i, point) in points.iter().enumerate() {
+            assert!(point.0.abs() <= 2 && point.1.abs() <= 2, "{:?}", point);
+            // every point once
+            assert!(!points[..i].contains(point), "{:?}", point);
+            // moving to a neighbour
+            if i > 0 {
+                let last = points[i - 1];
+                assert_eq!((point.0 - last.0).abs() + (point.1 - last.1).abs(), 1);
+            }
+            // ring by ring
+            let ring = point.0.abs().max(point.1.abs());
+            assert!(i < ((2*ring + 1)*(2*ring + 1)) as usize);
+        }
+        // repeats
+        assert_eq!(search_point(&params, 25), (0, 0));
+    }
+
+    #[test]
+    fn test_raster_pattern() {
+        let params = SearchParams { radius: 1, ..search_params(SearchPattern::Raster) };
+        let mut points = [(0, 0); 10];
+        for (i, point) in points.iter_mut().enumerate() {
+            *point = search_point(&params, i as u32);
+        }
+        assert_eq!(points, [
+            (900, -1100), (1000, -1100), (1100, -1100),
+            (1100, -1000), (1000, -1000), (900, -1000),
+            (900, -900), (1000, -900), (1100, -900),
+            (900, -1100),
+        ]);
+    }
+
+    #[test]
+    fn test_search_point_saturates() {
+        let params = SearchParams { dac1_center: i16::MAX - 50, dac2_center: i16::MIN + 50,
+            radius: 1, ..search_params(SearchPattern::Raster) };
+        assert_eq!(search_point(&params, 0), (i16::MAX - 150, i16::MIN));
+        assert_eq!(search_point(&params, 2), (i16::MAX, i16::MIN));
+    }
+
+    #[test]
+    fn test_search_locks() {
+        for &pattern in [SearchPattern::Spiral, SearchPattern::Raster].iter() {
+            let params = search_params(pattern);
+            let target = (1200, -1100);
+            let expected_index = (0..49).find(|i| search_point(&params, *i) == target).unwrap();
+            let inner = SetDeviceState {
+                mode: DeviceMode::Search(params.clone()),
+                ..closed_loop_state(ClosedLoopMode::Proportional)
+            };
+            let mut state = initial_state(inner);
+            let mut next = calc_next_update(&state);
+            let mut controller = ControllerState::default();
+
+            let mut n_ticks = 0;
+            while let DeviceMode::Search(_) = state.inner.mode {
+                assert!(n_ticks < 2*49 + 2, "no lock");
+                spot(&mut state, target);
+                calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+                n_ticks += 1;
+            }
+            assert_eq!(n_ticks, 2*expected_index + 2);
+            assert_eq!(state.inner.mode, DeviceMode::ClosedLoop(ClosedLoopMode::Proportional));
+            assert_eq!(state.lock_point, Some(LockPoint { index: expected_index, dac1: 1200, dac2: -1100 }));
+            // the closed loop starts from the lock point
+            assert_eq!((state.dac1, state.dac2), target);
+            assert_eq!((state.dac1_f32, state.dac2_f32), (1200.0, -1100.0));
+        }
+    }
+
+    #[test]
+    fn test_search_pi_starts_at_lock_point() {
+        let gains = PiGains { kp: 0.5, ki: 0.2, ..PiGains::default() };
+        let params = SearchParams {
+            lock_mode: ClosedLoopMode::ProportionalIntegral(PiParams {
+                dac1: gains.clone(),
+                dac2: gains,
+                prediction: None,
+            }),
+            ..search_params(SearchPattern::Spiral)
+        };
+        let inner = SetDeviceState {
+            mode: DeviceMode::Search(params),
+            ..closed_loop_state(ClosedLoopMode::Proportional)
+        };
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        // the first point is the centre
+        for _ in 0..2 {
+            spot(&mut state, (1000, -1000));
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        }
+        assert_eq!(state.lock_point.map(|p| p.index), Some(0));
+
+        // no error, so the PI output stays at the lock point
+        state.adc1 = 0;
+        state.adc2 = 0;
+        calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        assert_eq!((state.dac1, state.dac2), (1000, -1000));
+    }
+
+    #[test]
+    fn test_target_loss_search() {
+        let params = search_params(SearchPattern::Spiral);
+        let mut state = initial_state(target_loss_state(LostAction::Search(params.clone())));
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        run(&mut state, &mut next, &mut controller, (1000, 1000), 20);
+        lose_target(&mut state, &mut next, &mut controller);
+        assert_eq!(state.inner.mode, DeviceMode::Search(params));
+        assert_eq!(state.search_index, 0);
+
+        calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+        assert_eq!((state.dac1, state.dac2), (1000, -1000));
+    }
+
+    /// Run the output stage for `n_ticks` towards `target`, checking the
+    /// limits on every tick.
+    fn run_outputs(state: &mut DeviceState, output: &mut OutputState, target: i16, n_ticks: usize) {
+        let limits = state.inner.output_limits.clone();
+        state.dac1 = target;
+        for _ in 0..n_ticks {
+            let last = output.dac1.position;
+            let last_velocity = output.dac1.velocity;
+            update_outputs(state, output);
+            let velocity = state.dac1_out as i32 - last;
+            if limits.dac1_max_slew > 0 {
+                assert!(velocity.abs() <= limits.dac1_max_slew as i32, "velocity {}", velocity);
+            }
+            if limits.dac1_max_accel > 0 {
+                assert!((velocity - last_velocity).abs() <= limits.dac1_max_accel as i32,
+                    "acceleration {}", velocity - last_velocity);
+            }
+            assert!(state.inner.dac1_min <= state.dac1_out && state.dac1_out <= state.inner.dac1_max);
+        }
+    }
+
+    #[test]
+    fn test_outputs_unlimited() {
+        let mut state = initial_state(SetDeviceState::default());
+        let mut output = OutputState::default();
+        state.dac1 = 12345;
+        state.dac2 = -23456;
+        update_outputs(&mut state, &mut output);
+        assert_eq!((state.dac1_out, state.dac2_out), (12345, -23456));
+    }
+
+    #[test]
+    fn test_outputs_slew_limit() {
+        let inner = SetDeviceState {
+            output_limits: OutputLimits { dac1_max_slew: 100, dac2_max_slew: 7, ..OutputLimits::default() },
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        let mut output = OutputState::default();
+        state.dac2 = -20;
+        for i in 1..=10 {
+            run_outputs(&mut state, &mut output, 1000, 1);
+            assert_eq!(state.dac1_out, 100*i);
+        }
+        assert_eq!(state.dac2_out, -20);
+        run_outputs(&mut state, &mut output, 1000, 10);
+        assert_eq!(state.dac1_out, 1000);
+        // and back
+        run_outputs(&mut state, &mut output, -1000, 20);
+        assert_eq!(state.dac1_out, -1000);
+    }
+
+    #[test]
+    fn test_outputs_accel_limit() {
+        let inner = SetDeviceState {
+            output_limits: OutputLimits { dac1_max_slew: 200, dac1_max_accel: 10, ..OutputLimits::default() },
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        let mut output = OutputState::default();
+
+        // speeds up, cruises and brakes without overshooting
+        let mut last = 0;
+        for _ in 0..200 {
+            run_outputs(&mut state, &mut output, 10_000, 1);
+            assert!(last <= state.dac1_out && state.dac1_out <= 10_000);
+            last = state.dac1_out;
+        }
+        assert_eq!(state.dac1_out, 10_000);
+        assert_eq!(output.dac1.velocity, 0);
+
+        // small moves still settle exactly
+        for &target in [10_001, 9_990, 10_037].iter() {
+            run_outputs(&mut state, &mut output, target, 50);
+            assert_eq!(state.dac1_out, target);
+        }
+    }
+
+    #[test]
+    fn test_outputs_reversal_within_limits() {
+        let inner = SetDeviceState {
+            dac1_min: -2000,
+            dac1_max: 2000,
+            output_limits: OutputLimits { dac1_max_accel: 5, ..OutputLimits::default() },
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner);
+        let mut output = OutputState::default();
+
+        // the target jumps back while moving fast, so the output overshoots
+        run_outputs(&mut state, &mut output, 2000, 20);
+        run_outputs(&mut state, &mut output, -2000, 20);
+        run_outputs(&mut state, &mut output, 2000, 1000);
+        assert_eq!(state.dac1_out, 2000);
+    }
+
+    #[test]
+    fn test_outputs_follow_mode_change() {
+        let inner = SetDeviceState {
+            output_limits: OutputLimits { dac1_max_slew: 100, dac2_max_slew: 100, ..OutputLimits::default() },
+            ..SetDeviceState::default()
+        };
+        let mut state = initial_state(inner.clone());
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+
+        // a new state with other initial values ramps from the old outputs
+        state = initial_state(SetDeviceState { dac1_initial: 500, dac2_initial: -500, ..inner });
+        for i in 1..=5 {
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+            assert_eq!((state.dac1_out, state.dac2_out), (100*i, -100*i));
+        }
+    }
+
+    /// Closed loop at 10 kHz with a heartbeat timeout of 2 ms.
+    fn heartbeat_state() -> SetDeviceState {
+        SetDeviceState {
+            heartbeat: HeartbeatConfig {
+                timeout_ms: 2,
+                dac1_park: 1000,
+                dac2_park: -1000,
+                park_slew: 300,
+            },
+            ..closed_loop_state(ClosedLoopMode::Proportional)
+        }
+    }
+
+    #[test]
+    fn test_heartbeat_timeout_parks() {
+        let mut state = initial_state(heartbeat_state());
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+        let mut heartbeat = HeartbeatMonitor::default();
+
+        for _ in 0..19 {
+            assert!(!heartbeat.tick(&mut state));
+        }
+        assert!(!state.parked);
+        assert!(heartbeat.tick(&mut state));
+        assert!(state.parked);
+        assert!(!is_locked(&state));
+        // the configuration is not changed
+        assert_eq!(state.inner, heartbeat_state());
+
+        // ramps to the park position and stays there
+        for i in 1..=5 {
+            assert!(!heartbeat.tick(&mut state));
+            plant(&mut state, (0, 0));
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+            assert_eq!((state.dac1_out, state.dac2_out), ((300*i).min(1000), (-300*i).max(-1000)));
+        }
+    }
+
+    #[test]
+    fn test_set_galvos_while_parked() {
+        let mut state = initial_state(heartbeat_state());
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+        let mut heartbeat = HeartbeatMonitor::default();
+        while !heartbeat.tick(&mut state) {}
+
+        assert!(!set_galvos(&mut state, -5000, 5000));
+        assert_eq!(state.inner, heartbeat_state());
+        for _ in 0..10 {
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+        }
+        assert!(state.parked);
+        assert_eq!((state.dac1_out, state.dac2_out), (1000, -1000));
+
+        // applied again after SetState
+        let mut state = initial_state(heartbeat_state());
+        assert!(set_galvos(&mut state, -5000, 5000));
+        assert_eq!(state.inner.mode, DeviceMode::SampleAdc);
+        assert_eq!((state.dac1, state.dac2), (-5000, 5000));
+    }
+
+    #[test]
+    fn test_heartbeat_reset() {
+        let mut state = initial_state(heartbeat_state());
+        let mut heartbeat = HeartbeatMonitor::default();
+        for _ in 0..100 {
+            for _ in 0..19 {
+                assert!(!heartbeat.tick(&mut state));
+            }
+            heartbeat.reset();
+        }
+        assert!(!state.parked);
+        assert_eq!(state.inner.mode, DeviceMode::ClosedLoop(ClosedLoopMode::Proportional));
+    }
+
+    #[test]
+    fn test_heartbeat_park_slew() {
+        // a lower configured slew limit is kept
+        let mut inner = heartbeat_state();
+        inner.output_limits.dac1_max_slew = 10;
+        inner.output_limits.dac2_max_slew = 1000;
+        let mut state = initial_state(inner.clone());
+        let mut output = OutputState::default();
+        let mut heartbeat = HeartbeatMonitor::default();
+        while !heartbeat.tick(&mut state) {}
+        update_outputs(&mut state, &mut output);
+        assert_eq!((state.dac1_out, state.dac2_out), (10, -300));
+        assert_eq!(state.inner.output_limits, inner.output_limits);
+
+        // disabled
+        inner.heartbeat.timeout_ms = 0;
+        let mut state = initial_state(inner);
+        for _ in 0..1000 {
+            assert!(!heartbeat.tick(&mut state));
+        }
+        assert!(!state.parked);
+    }
+
+    #[test]
+    fn test_aux_modes() {
+        let mut state = initial_state(SetDeviceState::default());
+        let mut output = OutputState::default();
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, 0);
+
+        state.inner.aux = AuxConfig { mode: AuxMode::Disabled, dac3: 1234, dac3_off: -5 };
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, 0);
+
+        // not locked outside of the closed loop, but output anyway
+        state.inner.aux.mode = AuxMode::Manual;
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, 1234);
+
+        state.inner.aux.mode = AuxMode::LockGated;
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, -5);
+    }
+
+    #[test]
+    fn test_aux_lock_gated() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.dac1_max = 2000;
+        inner.aux = AuxConfig { mode: AuxMode::LockGated, dac3: 1234, dac3_off: -5 };
+        let mut state = initial_state(inner);
+        let mut output = OutputState::default();
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, 1234);
+
+        // the target is lost
+        state.target = TargetStatus::Lost;
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, -5);
+        state.target = TargetStatus::Weak;
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, 1234);
+
+        // an output reached its limit
+        state.dac1 = 2000;
+        update_outputs(&mut state, &mut output);
+        assert_eq!(state.dac3_out, -5);
+    }
+
+    #[test]
+    fn test_sync_periodic() {
+        let inner = SetDeviceState {
+            sync: SyncConfig {
+                pulse_on: SyncTrigger::EveryNTicks(NonZeroU32::new(5).unwrap()),
+                pulse_us: 200,
+                ..SyncConfig::default()
+            },
+            ..SetDeviceState::default()
+        };
+        let state = initial_state(inner);
+        let mut sync = SyncOutput::default();
+        let mut count = 0;
+        for counter in 0..20 {
+            let pulse = sync.tick(&state, counter);
+            if counter % 5 == 0 {
+                count += 1;
+                assert_eq!(pulse, Some(SyncPulse { count, counter, timestamp_us: 0, cause: SyncCause::Periodic }));
+            } else {
+                assert_eq!(pulse, None);
+            }
+            // two ticks at 10 kHz
+            assert_eq!(sync.is_high(), counter % 5 < 2, "tick {}", counter);
+        }
+    }
+
+    #[test]
+    fn test_sync_lock_events() {
+        let mut inner = closed_loop_state(ClosedLoopMode::Proportional);
+        inner.sync.pulse_on = SyncTrigger::LockLost;
+        let mut state = initial_state(inner);
+        let mut sync = SyncOutput::default();
+
+        // locked from the start, no pulse
+        assert_eq!(sync.tick(&state, 0), None);
+        state.target = TargetStatus::Lost;
+        assert_eq!(sync.tick(&state, 1).map(|pulse| pulse.cause), Some(SyncCause::LockLost));
+        assert!(sync.is_high());
+        assert_eq!(sync.tick(&state, 2), None);
+        assert!(!sync.is_high());
+
+        state.inner.sync.pulse_on = SyncTrigger::LockAcquired;
+        state.target = TargetStatus::Present;
+        let pulse = sync.tick(&state, 3).unwrap();
+        assert_eq!((pulse.count, pulse.counter, pulse.cause), (2, 3, SyncCause::LockAcquired));
+        assert_eq!(sync.tick(&state, 4), None);
+
+        // never pulses
+        state.inner.sync.pulse_on = SyncTrigger::Never;
+        state.target = TargetStatus::Lost;
+        assert_eq!(sync.tick(&state, 5), None);
+        assert!(!sync.is_high());
+    }
+
+    #[test]
+    fn test_waveform_mode() {
+        let sine = AxisWaveform {
+            shape: WaveShape::Sine,
+            amplitude: 1000,
+            offset: 0,
+            frequency_hz: 100.0,
+            phase_deg: 0.0,
+        };
+        let inner = SetDeviceState {
+            mode: DeviceMode::Waveform(WaveformParams {
+                dac1: sine.clone(),
+                dac2: AxisWaveform { shape: WaveShape::Table { len: 2 }, phase_deg: 90.0, ..sine },
+            }),
+            dac1_max: 500,
+            ..SetDeviceState::default()
+        };
+        let table = [i16::MAX, -i16::MAX];
+        let mut state = initial_state(inner);
+        let mut next = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+
+        let mut values = [(0, 0); 100];
+        for value in values.iter_mut() {
+            calculate_next_dac_values(&mut state, &mut next, &mut controller, &table);
+            *value = (state.dac1, state.dac2);
+        }
+        // clipped to dac1_max, the table starts half way at 90 degrees
+        assert_eq!([values[0], values[25], values[50], values[75]],
+            [(0, 0), (500, -1000), (0, 0), (-1000, 1000)]);
+    }
+}
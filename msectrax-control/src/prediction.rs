@@ -0,0 +1,111 @@
+//! Target motion estimation for `PredictionParams`.
+
+use msectrax_comms::PredictionFilter;
+
+/// Estimated position and velocity of the target along one axis, in DAC
+/// counts and counts per closed loop update.
+#[derive(Default)]
+pub struct TargetEstimate {
+    started: bool,
+    position: f32,
+    velocity: f32,
+    /// Covariance of the Kalman filter.
+    p11: f32,
+    p12: f32,
+    p22: f32,
+}
+
+impl TargetEstimate {
+    /// Add the target position measured at this update, returning the
+    /// estimated velocity.
+    pub fn update( &mut self, filter: &PredictionFilter, measured: f32 ) -> f32 {
+        if !self.started {
+            *self = TargetEstimate {
+                started: true,
+                position: measured,
+                velocity: 0.0,
+                ..TargetEstimate::default()
+            };
+            if let PredictionFilter::Kalman { measurement_noise, .. } = filter {
+                // The velocity is as uncertain as the first position.
+                self.p11 = *measurement_noise;
+                self.p22 = *measurement_noise;
+            }
+            return 0.0;
+        }
+
+        let predicted = self.position + self.velocity;
+        let residual = measured - predicted;
+        let (k1, k2) = match filter {
+            PredictionFilter::AlphaBeta { alpha, beta } => (*alpha, *beta),
+            PredictionFilter::Kalman { process_noise, measurement_noise } => {
+                // Constant velocity model with white noise acceleration.
+                let q = *process_noise;
+                let p11 = self.p11 + 2.0*self.p12 + self.p22 + q/4.0;
+                let p12 = self.p12 + self.p22 + q/2.0;
+                let p22 = self.p22 + q;
+                let s = p11 + measurement_noise;
+                let (k1, k2) = if s > 0.0 { (p11/s, p12/s) } else { (1.0, 0.0) };
+                self.p11 = (1.0 - k1)*p11;
+                self.p12 = (1.0 - k1)*p12;
+                self.p22 = p22 - k2*p12;
+                (k1, k2)
+            }
+        };
+        self.position = predicted + k1*residual;
+        self.velocity += k2*residual;
+        self.velocity
+    }
+
+    pub fn velocity( &self ) -> f32 {
+        self.velocity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The velocity estimated after `n` updates of a target moving at `speed`
+    /// from 100, with alternating noise of `noise`.
+    fn estimate(filter: &PredictionFilter, speed: f32, noise: f32, n: usize) -> f32 {
+        let mut estimate = TargetEstimate::default();
+        let mut velocity = 0.0;
+        for i in 0..n {
+            let noise = if i % 2 == 0 { noise } else { -noise };
+            velocity = estimate.update(filter, 100.0 + speed*i as f32 + noise);
+        }
+        velocity
+    }
+
+    fn assert_near(actual: f32, expected: f32, tolerance: f32) {
+        let diff = actual - expected;
+        assert!(-tolerance < diff && diff < tolerance, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_first_update() {
+        let filter = PredictionFilter::AlphaBeta { alpha: 0.5, beta: 0.1 };
+        let mut estimate = TargetEstimate::default();
+        assert_eq!(estimate.update(&filter, 1000.0), 0.0);
+        assert_eq!(estimate.position, 1000.0);
+    }
+
+    #[test]
+    fn test_alpha_beta_velocity() {
+        let filter = PredictionFilter::AlphaBeta { alpha: 0.5, beta: 0.1 };
+        assert_near(estimate(&filter, 10.0, 0.0, 200), 10.0, 1e-3);
+        assert_near(estimate(&filter, -3.0, 0.0, 200), -3.0, 1e-3);
+        assert_near(estimate(&filter, 0.0, 0.0, 200), 0.0, 1e-3);
+        assert_near(estimate(&filter, 10.0, 5.0, 200), 10.0, 1.0);
+    }
+
+    #[test]
+    fn test_kalman_velocity() {
+        let filter = PredictionFilter::Kalman { process_noise: 0.01, measurement_noise: 25.0 };
+        assert_near(estimate(&filter, 10.0, 0.0, 500), 10.0, 1e-2);
+        assert_near(estimate(&filter, -3.0, 0.0, 500), -3.0, 1e-2);
+        // smooths the noise better than the fast alpha-beta filter
+        assert_near(estimate(&filter, 10.0, 5.0, 500), 10.0, 0.2);
+    }
+}
@@ -0,0 +1,384 @@
+//! Frequency response measurement for `DeviceMode::FrequencySweep`.
+
+use msectrax_comms::{AdcChannel, DeviceState, SweepAxis, SweepParams,
+    SweepPoint, SweepResult, MAX_SWEEP_POINTS};
+
+use crate::waveform::{phase_step, sin_turns, TURN};
+
+/// Full scale of the reference sine and cosine.
+const REFERENCE_SCALE: f32 = 32767.0;
+
+/// Sums for correlating one signal with the reference sine and cosine.
+#[derive(Default)]
+struct Correlation {
+    sum: i64,
+    sum_sin: i64,
+    sum_cos: i64,
+}
+
+impl Correlation {
+    fn add( &mut self, value: i16, sin: i32, cos: i32 ) {
+        self.sum += value as i64;
+        self.sum_sin += (value as i32*sin) as i64;
+        self.sum_cos += (value as i32*cos) as i64;
+    }
+
+    /// The in-phase and quadrature amplitudes over `n` samples, given the
+    /// sums of the reference itself.
+    fn components( &self, reference: &Correlation, n: u32 ) -> (f32, f32) {
+        // Removing the mean keeps the offset out of the result when the
+        // measurement is not exactly a whole number of periods.
+        let n = n as f64;
+        let mean = self.sum as f64 / n;
+        let scale = 2.0 / (n*REFERENCE_SCALE as f64);
+        let i = (self.sum_sin as f64 - mean*reference.sum_sin as f64)*scale;
+        let q = (self.sum_cos as f64 - mean*reference.sum_cos as f64)*scale;
+        (i as f32, q as f32)
+    }
+}
+
+/// The reference sine and cosine at `phase`, in 2^-32 turns.
+fn reference( phase: u32 ) -> (i32, i32) {
+    let value = |phase: u32| {
+        let turns = phase as f32 / TURN;
+        // Rounding may give exactly one turn.
+        let turns = if turns < 1.0 { turns } else { 0.0 };
+        let value = sin_turns(turns)*REFERENCE_SCALE;
+        (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i32
+    };
+    (value(phase), value(phase.wrapping_add(1 << 30)))
+}
+
+/// Square root of `x`, zero for `x` at or below zero.
+fn sqrt( x: f32 ) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent gives a first guess within a few percent,
+    // which Newton's method refines to full precision.
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1fbd_1df5);
+    for _ in 0..3 {
+        y = 0.5*(y + x/y);
+    }
+    y
+}
+
+/// `atan(x)` for `x` from 0 to 1, to about 1e-7 radians (Abramowitz and
+/// Stegun 4.4.49).
+fn atan_unit( x: f32 ) -> f32 {
+    let x2 = x*x;
+    x*(1.0 + x2*(-0.333_331_45 + x2*(0.199_935_51 + x2*(-0.142_089 +
+        x2*(0.106_562_64 + x2*(-0.075_289_64 + x2*(0.042_909_61 +
+        x2*(-0.016_165_737 + x2*0.002_866_225_7))))))))
+}
+
+/// The angle of the point `(x, y)`, from -180 to 180 degrees.
+fn atan2_deg( y: f32, x: f32 ) -> f32 {
+    let abs = |v: f32| if v < 0.0 { -v } else { v };
+    let (ax, ay) = (abs(x), abs(y));
+    if ax == 0.0 && ay == 0.0 {
+        return 0.0;
+    }
+    let pi = core::f32::consts::PI;
+    let mut angle = if ax >= ay { atan_unit(ay/ax) } else { pi/2.0 - atan_unit(ax/ay) };
+    if x < 0.0 {
+        angle = pi - angle;
+    }
+    if y < 0.0 {
+        angle = -angle;
+    }
+    angle*180.0/pi
+}
+
+/// A count of ticks for `periods` periods, rounded.
+fn periods_to_ticks( periods: u16, ticks_per_period: f32 ) -> u32 {
+    // The cast saturates at the limits.
+    (periods as f32*ticks_per_period + 0.5) as u32
+}
+
+/// State of a frequency sweep, see `SweepParams`.
+#[derive(Default)]
+pub struct Sweep {
+    started: bool,
+    /// Phase of the output in 2^-32 turns, and its increment per tick at the
+    /// current frequency.
+    phase: u32,
+    step: u32,
+    /// Ticks output at the current frequency.
+    ticks: u32,
+    settle_ticks: u32,
+    measure_ticks: u32,
+    /// The phase of the previous tick's output, if it is measured.
+    measured_phase: Option<u32>,
+    /// Samples correlated at the current frequency.
+    n_samples: u32,
+    reference: Correlation,
+    dac: Correlation,
+    adc: Correlation,
+    result: SweepResult,
+}
+
+impl Sweep {
+    pub fn result( &self ) -> &SweepResult {
+        &self.result
+    }
+
+    /// The DAC values for the current tick, after measuring the ADC values
+    /// read at this tick. Call once per control loop tick.
+    pub fn next_values( &mut self, params: &SweepParams, dev_state: &DeviceState ) -> (i16, i16) {
+        let inner = &dev_state.inner;
+        let loop_rate_hz = inner.loop_rate_hz.get();
+        if !self.started {
+            self.started = true;
+            self.result = SweepResult {
+                n_frequencies: (params.n_frequencies as usize).min(MAX_SWEEP_POINTS) as u8,
+                ..SweepResult::default()
+            };
+            self.start_point(params, loop_rate_hz);
+        }
+
+        let (center, dac_out) = match params.axis {
+            SweepAxis::Dac1 => (inner.dac1_initial, dev_state.dac1_out),
+            SweepAxis::Dac2 => (inner.dac2_initial, dev_state.dac2_out),
+        };
+        if let Some(phase) = self.measured_phase.take() {
+            let adc = match params.response {
+                AdcChannel::Adc1 => dev_state.adc1,
+                AdcChannel::Adc2 => dev_state.adc2,
+                AdcChannel::Sum => dev_state.adc_sum,
+            };
+            let (sin, cos) = reference(phase);
+            self.reference.add(1, sin, cos);
+            self.dac.add(dac_out, sin, cos);
+            self.adc.add(adc, sin, cos);
+            self.n_samples += 1;
+            if self.n_samples >= self.measure_ticks {
+                self.finish_point(loop_rate_hz);
+                self.start_point(params, loop_rate_hz);
+            }
+        }
+
+        if self.result.n_done >= self.result.n_frequencies {
+            return (inner.dac1_initial, inner.dac2_initial);
+        }
+        if self.ticks >= self.settle_ticks {
+            self.measured_phase = Some(self.phase);
+        }
+        let (sin, _) = reference(self.phase);
+        let value = center as f32 + params.amplitude as f32*sin as f32/REFERENCE_SCALE;
+        // Round to the nearest value, the cast saturates at the limits.
+        let value = (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i16;
+        self.phase = self.phase.wrapping_add(self.step);
+        self.ticks = self.ticks.saturating_add(1);
+
+        match params.axis {
+            SweepAxis::Dac1 => (value, inner.dac2_initial),
+            SweepAxis::Dac2 => (inner.dac1_initial, value),
+        }
+    }
+
+    /// Start measuring the next point, skipping the frequencies which are
+    /// out of range.
+    fn start_point( &mut self, params: &SweepParams, loop_rate_hz: u32 ) {
+        while self.result.n_done < self.result.n_frequencies {
+            let index = self.result.n_done as usize;
+            let frequency_hz = params.frequencies_hz[index];
+            let step = phase_step(frequency_hz, loop_rate_hz);
+            if frequency_hz > 0.0 && frequency_hz < loop_rate_hz as f32 / 2.0 && step > 0 {
+                let ticks_per_period = TURN / step as f32;
+                self.step = step;
+                self.ticks = 0;
+                self.settle_ticks = periods_to_ticks(params.settle_periods, ticks_per_period);
+                self.measure_ticks = periods_to_ticks(params.measure_periods.get(), ticks_per_period).max(1);
+                self.n_samples = 0;
+                self.reference = Correlation::default();
+                self.dac = Correlation::default();
+                self.adc = Correlation::default();
+                return;
+            }
+            self.result.points[index] = SweepPoint {
+                frequency_hz,
+                dac_amplitude: f32::NAN,
+                adc_amplitude: f32::NAN,
+                gain: f32::NAN,
+                phase_deg: f32::NAN,
+            };
+            self.result.n_done += 1;
+        }
+    }
+
+    fn finish_point( &mut self, loop_rate_hz: u32 ) {
+        let (dac_i, dac_q) = self.dac.components(&self.reference, self.n_samples);
+        let (adc_i, adc_q) = self.adc.components(&self.reference, self.n_samples);
+        let dac_amplitude = sqrt(dac_i*dac_i + dac_q*dac_q);
+        let adc_amplitude = sqrt(adc_i*adc_i + adc_q*adc_q);
+        let (gain, phase_deg) = if dac_amplitude > 0.0 {
+            let mut phase_deg = atan2_deg(adc_q, adc_i) - atan2_deg(dac_q, dac_i);
+            if phase_deg < -180.0 {
+                phase_deg += 360.0;
+            } else if phase_deg > 180.0 {
+                phase_deg -= 360.0;
+            }
+            (adc_amplitude / dac_amplitude, phase_deg)
+        } else {
+            (f32::NAN, f32::NAN)
+        };
+        self.result.points[self.result.n_done as usize] = SweepPoint {
+            frequency_hz: self.step as f32 / TURN*loop_rate_hz as f32,
+            dac_amplitude,
+            adc_amplitude,
+            gain,
+            phase_deg,
+        };
+        self.result.n_done += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::num::NonZeroU16;
+    use msectrax_comms::{DeviceMode, SetDeviceState};
+    use crate::{ControllerState, OutputState, calculate_next_dac_values,
+        update_outputs, calc_next_update, initial_state};
+
+    fn sweep_params(frequencies: &[f32]) -> SweepParams {
+        let mut frequencies_hz = [0.0; MAX_SWEEP_POINTS];
+        frequencies_hz[..frequencies.len()].copy_from_slice(frequencies);
+        SweepParams {
+            axis: SweepAxis::Dac1,
+            response: AdcChannel::Adc1,
+            amplitude: 1000,
+            frequencies_hz,
+            n_frequencies: frequencies.len() as u8,
+            settle_periods: 2,
+            measure_periods: NonZeroU16::new(10).unwrap(),
+        }
+    }
+
+    /// Sweep at a 10 kHz loop rate with a plant whose output on the response
+    /// channel is `gain` times the swept DAC output of `delay` ticks before
+    /// the tick paired with it. Returns the result and the last state.
+    fn run(params: SweepParams, gain: f32, delay: usize) -> (SweepResult, DeviceState) {
+        let axis = params.axis;
+        let response = params.response;
+        let mut state = initial_state(SetDeviceState {
+            mode: DeviceMode::FrequencySweep(params),
+            dac1_initial: 500,
+            dac2_initial: -300,
+            ..SetDeviceState::default()
+        });
+        let mut cl_next_update_cycle = calc_next_update(&state);
+        let mut controller = ControllerState::default();
+        let mut output = OutputState::default();
+        let mut history = std::vec::Vec::new();
+        let center = match axis {
+            SweepAxis::Dac1 => 500,
+            SweepAxis::Dac2 => -300,
+        };
+        for _ in 0..100_000 {
+            // The ADCs are read before the output of the tick.
+            let past = history.len().checked_sub(1 + delay).map(|i| history[i]).unwrap_or(center);
+            let value = 1000.0 + gain*(past - center) as f32;
+            let value = (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i16;
+            match response {
+                AdcChannel::Adc1 => state.adc1 = value,
+                AdcChannel::Adc2 => state.adc2 = value,
+                AdcChannel::Sum => state.adc_sum = value,
+            }
+            calculate_next_dac_values(&mut state, &mut cl_next_update_cycle, &mut controller, &[]);
+            update_outputs(&mut state, &mut output);
+            history.push(match axis {
+                SweepAxis::Dac1 => state.dac1_out,
+                SweepAxis::Dac2 => state.dac2_out,
+            });
+            let result = controller.sweep_result();
+            if result.n_done == result.n_frequencies {
+                break;
+            }
+        }
+        (controller.sweep_result().clone(), state)
+    }
+
+    fn assert_close(value: f32, expected: f32, tolerance: f32) {
+        assert!(value - expected < tolerance && expected - value < tolerance,
+            "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn test_sqrt() {
+        for i in 1..1000 {
+            let x = i as f32*i as f32*0.37;
+            let expected = std::primitive::f32::sqrt(x);
+            assert_close(sqrt(x) / expected, 1.0, 1e-6);
+        }
+        assert_eq!(sqrt(0.0), 0.0);
+        assert_eq!(sqrt(-1.0), 0.0);
+    }
+
+    #[test]
+    fn test_atan2() {
+        for i in 0..720 {
+            let angle = (i as f32 - 359.5)*core::f32::consts::PI/360.0;
+            let (y, x) = (std::primitive::f32::sin(angle)*3.0, std::primitive::f32::cos(angle)*3.0);
+            assert_close(atan2_deg(y, x), angle*180.0/core::f32::consts::PI, 1e-4);
+        }
+        assert_eq!(atan2_deg(0.0, 0.0), 0.0);
+        assert_close(atan2_deg(0.0, -1.0), 180.0, 1e-4);
+    }
+
+    #[test]
+    fn test_gain_and_phase() {
+        let (result, _) = run(sweep_params(&[100.0, 1000.0, 250.0]), 0.5, 2);
+        assert_eq!((result.n_done, result.n_frequencies), (3, 3));
+        for (point, frequency_hz) in result.points.iter().zip([100.0, 1000.0, 250.0].iter()) {
+            assert_close(point.frequency_hz, *frequency_hz, 1e-3);
+            assert_close(point.dac_amplitude, 1000.0, 0.5);
+            assert_close(point.adc_amplitude, 500.0, 0.5);
+            assert_close(point.gain, 0.5, 1e-3);
+            // two ticks of delay at 10 kHz
+            assert_close(point.phase_deg, -360.0*frequency_hz*2.0/10_000.0, 0.05);
+        }
+
+        // without delay, the response is in phase
+        let (result, _) = run(sweep_params(&[333.0]), 2.0, 0);
+        assert_close(result.points[0].gain, 2.0, 1e-3);
+        assert_close(result.points[0].phase_deg, 0.0, 0.05);
+    }
+
+    #[test]
+    fn test_other_axis() {
+        let params = SweepParams {
+            axis: SweepAxis::Dac2,
+            response: AdcChannel::Sum,
+            ..sweep_params(&[500.0])
+        };
+        let (result, state) = run(params, 0.25, 12);
+        assert_close(result.points[0].gain, 0.25, 1e-3);
+        // a delay beyond half a period wraps the phase
+        assert_close(result.points[0].phase_deg, 144.0, 0.05);
+        // both axes are back at the initial values
+        assert_eq!((state.dac1, state.dac2), (500, -300));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let (result, state) = run(sweep_params(&[6000.0, 0.0, 100.0, -5.0]), 1.0, 0);
+        assert_eq!((result.n_done, result.n_frequencies), (4, 4));
+        for i in [0, 1, 3].iter() {
+            let point = &result.points[*i];
+            assert!(point.gain.is_nan() && point.phase_deg.is_nan());
+        }
+        assert_eq!(result.points[0].frequency_hz, 6000.0);
+        assert_close(result.points[2].gain, 1.0, 1e-3);
+        assert_eq!(state.dac1, 500);
+
+        // too many frequencies are limited to the array
+        let mut params = sweep_params(&[]);
+        params.n_frequencies = 200;
+        let (result, _) = run(params, 1.0, 0);
+        assert_eq!((result.n_done, result.n_frequencies), (16, 16));
+    }
+}
@@ -0,0 +1,196 @@
+//! Test patterns for `DeviceMode::Waveform`.
+
+use msectrax_comms::{AxisWaveform, WaveShape};
+
+/// One period in the units of the phase accumulators.
+pub const TURN: f32 = 4_294_967_296.0;
+
+/// Phase accumulator of one axis, in 2^-32 turns.
+#[derive(Default)]
+pub struct WavePhase {
+    phase: u32,
+}
+
+/// `x` minus the nearest integer towards negative infinity, from 0 to 1.
+fn fract( x: f32 ) -> f32 {
+    let fract = x - (x as i64) as f32;
+    if fract < 0.0 { fract + 1.0 } else { fract }
+}
+
+/// The phase accumulator increment per control loop tick.
+pub fn phase_step( frequency_hz: f32, loop_rate_hz: u32 ) -> u32 {
+    (fract(frequency_hz / loop_rate_hz as f32) * TURN) as u32
+}
+
+/// `sin(2*pi*turns)` for `turns` from 0 to 1, to better than 1e-5.
+pub fn sin_turns( turns: f32 ) -> f32 {
+    // Use the symmetry of the sine to keep the series argument within a
+    // quarter turn of zero.
+    let x = if turns < 0.25 {
+        turns
+    } else if turns < 0.75 {
+        0.5 - turns
+    } else {
+        turns - 1.0
+    };
+    let x = x*2.0*core::f32::consts::PI;
+    let x2 = x*x;
+    x*(1.0 - x2/6.0*(1.0 - x2/20.0*(1.0 - x2/42.0*(1.0 - x2/72.0))))
+}
+
+/// The table linearly interpolated at `turns` from 0 to 1, scaled so that
+/// `i16::MAX` is 1.
+fn table_value( table: &[i16], turns: f32 ) -> f32 {
+    if table.is_empty() {
+        return 0.0;
+    }
+    let pos = turns*table.len() as f32;
+    let i = (pos as usize).min(table.len() - 1);
+    let frac = pos - i as f32;
+    let a = table[i] as f32;
+    let b = table[(i + 1) % table.len()] as f32;
+    (a + (b - a)*frac) / i16::MAX as f32
+}
+
+/// The shape at `turns` from 0 to 1, between -1 and 1.
+pub fn shape_value( shape: &WaveShape, turns: f32, table: &[i16] ) -> f32 {
+    match shape {
+        WaveShape::Sine => sin_turns(turns),
+        WaveShape::Triangle => {
+            if turns < 0.25 {
+                4.0*turns
+            } else if turns < 0.75 {
+                2.0 - 4.0*turns
+            } else {
+                4.0*turns - 4.0
+            }
+        }
+        WaveShape::Square => if turns < 0.5 { 1.0 } else { -1.0 },
+        WaveShape::Sawtooth => if turns < 0.5 { 2.0*turns } else { 2.0*turns - 2.0 },
+        WaveShape::Table { len } => {
+            table_value(&table[..(*len as usize).min(table.len())], turns)
+        }
+    }
+}
+
+impl WavePhase {
+    /// The DAC value for the current tick, then advance by one tick.
+    pub fn next_value( &mut self, wave: &AxisWaveform, loop_rate_hz: u32, table: &[i16] ) -> i16 {
+        let phase_offset = (fract(wave.phase_deg / 360.0) * TURN) as u32;
+        let turns = self.phase.wrapping_add(phase_offset) as f32 / TURN;
+        // Rounding may give exactly one turn.
+        let turns = if turns < 1.0 { turns } else { 0.0 };
+        self.phase = self.phase.wrapping_add(phase_step(wave.frequency_hz, loop_rate_hz));
+
+        let value = wave.offset as f32 + wave.amplitude as f32*shape_value(&wave.shape, turns, table);
+        // Round to the nearest value, the cast saturates at the limits.
+        (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn wave(shape: WaveShape) -> AxisWaveform {
+        AxisWaveform {
+            shape,
+            amplitude: 1000,
+            offset: 0,
+            frequency_hz: 100.0,
+            phase_deg: 0.0,
+        }
+    }
+
+    /// One period at 100 Hz and a 10 kHz loop rate.
+    fn period(wave: &AxisWaveform, table: &[i16]) -> [i16; 100] {
+        let mut phase = WavePhase::default();
+        let mut values = [0; 100];
+        for value in values.iter_mut() {
+            *value = phase.next_value(wave, 10_000, table);
+        }
+        values
+    }
+
+    #[test]
+    fn test_sin_turns() {
+        for i in 0..1000 {
+            let turns = i as f32 / 1000.0;
+            let expected = std::primitive::f32::sin(turns*2.0*core::f32::consts::PI);
+            let diff = sin_turns(turns) - expected;
+            assert!(diff.abs() < 1e-5, "sin({}) {} != {}", turns, sin_turns(turns), expected);
+        }
+    }
+
+    #[test]
+    fn test_fract() {
+        assert_eq!(fract(1.25), 0.25);
+        assert_eq!(fract(-0.25), 0.75);
+        assert_eq!(fract(3.0), 0.0);
+    }
+
+    #[test]
+    fn test_shapes() {
+        let sine = period(&wave(WaveShape::Sine), &[]);
+        assert_eq!([sine[0], sine[25], sine[50], sine[75]], [0, 1000, 0, -1000]);
+        let triangle = period(&wave(WaveShape::Triangle), &[]);
+        assert_eq!([triangle[0], triangle[10], triangle[25], triangle[50], triangle[75]],
+            [0, 400, 1000, 0, -1000]);
+        let square = period(&wave(WaveShape::Square), &[]);
+        assert_eq!([square[0], square[49], square[50], square[99]], [1000, 1000, -1000, -1000]);
+        let sawtooth = period(&wave(WaveShape::Sawtooth), &[]);
+        assert_eq!([sawtooth[0], sawtooth[25], sawtooth[50], sawtooth[75]], [0, 500, -1000, -500]);
+    }
+
+    #[test]
+    fn test_repeats() {
+        let mut phase = WavePhase::default();
+        let wave = wave(WaveShape::Sine);
+        let mut first = [0; 100];
+        for value in first.iter_mut() {
+            *value = phase.next_value(&wave, 10_000, &[]);
+        }
+        for i in 0..1000 {
+            assert_eq!(phase.next_value(&wave, 10_000, &[]), first[i % 100]);
+        }
+    }
+
+    #[test]
+    fn test_phase_and_offset() {
+        let sine = AxisWaveform { phase_deg: 90.0, offset: -500, ..wave(WaveShape::Sine) };
+        let cosine = period(&sine, &[]);
+        assert_eq!([cosine[0], cosine[25], cosine[50]], [500, -500, -1500]);
+
+        // negative phases wrap
+        let sine = AxisWaveform { phase_deg: -270.0, ..sine };
+        assert_eq!(period(&sine, &[])[0], 500);
+    }
+
+    #[test]
+    fn test_clips() {
+        let wave = AxisWaveform { amplitude: i16::MAX, offset: i16::MAX, ..wave(WaveShape::Square) };
+        let values = period(&wave, &[]);
+        assert_eq!([values[0], values[99]], [i16::MAX, 0]);
+    }
+
+    #[test]
+    fn test_table() {
+        let table = [0, i16::MAX, 0, -i16::MAX, 12345];
+        let wave = wave(WaveShape::Table { len: 4 });
+        let values = period(&wave, &table);
+        assert_eq!([values[0], values[25], values[50], values[75]], [0, 1000, 0, -1000]);
+        // interpolated
+        assert_eq!(values[10], 400);
+        // wraps to the first value
+        assert_eq!(values[90], -400);
+
+        // a length beyond the table is limited to it
+        let wave = AxisWaveform { shape: WaveShape::Table { len: 1000 }, ..wave };
+        assert_eq!(period(&wave, &table[..4]), values);
+        // an empty table gives the offset
+        let wave = AxisWaveform { shape: WaveShape::Table { len: 0 }, offset: 7, ..wave };
+        assert_eq!(period(&wave, &table)[10], 7);
+    }
+}
@@ -2,6 +2,8 @@ use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -16,4 +18,20 @@ fn main() {
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Build information reported by `QueryDeviceInfo`
+    let git_hash = Command::new("git")
+        .args(&["describe", "--always", "--dirty", "--abbrev=8", "--exclude=*"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=MSECTRAX_GIT_HASH={}", git_hash);
+
+    let build_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    println!("cargo:rustc-env=MSECTRAX_BUILD_TIME={}", build_time);
+
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
 }
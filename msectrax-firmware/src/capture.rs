@@ -0,0 +1,160 @@
+use msectrax_comms::{AdcChannel, CaptureChunk, CaptureConfig, CaptureState,
+    CaptureStatus, CaptureTrigger, DeviceMode, DeviceState, Edge, StoredSample,
+    CAPTURE_CHUNK_SIZE};
+use msectrax_control::is_locked;
+
+/// Records samples into a ring buffer around a trigger event.
+pub struct Capture {
+    config: CaptureConfig,
+    state: CaptureState,
+    countdown: u16,
+    /// Next position to write in the ring buffer.
+    write_idx: usize,
+    /// Number of valid samples in the ring buffer.
+    n_samples: usize,
+    /// Samples still to record after the trigger.
+    post_remaining: usize,
+    trigger_index: usize,
+    trigger_counter: u32,
+    last_mode: Option<DeviceMode>,
+    last_locked: bool,
+    last_adc: Option<(i16, i16, i16)>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self {
+            config: CaptureConfig {
+                trigger: CaptureTrigger::Immediate,
+                pre_trigger: 0,
+                decimation: core::num::NonZeroU16::new(1).unwrap(),
+            },
+            state: CaptureState::Idle,
+            countdown: 0,
+            write_idx: 0,
+            n_samples: 0,
+            post_remaining: 0,
+            trigger_index: 0,
+            trigger_counter: 0,
+            last_mode: None,
+            last_locked: false,
+            last_adc: None,
+        }
+    }
+
+    /// Start a new capture, discarding the previous one.
+    pub fn arm(&mut self, config: CaptureConfig) {
+        *self = Self::new();
+        self.config = config;
+        self.state = CaptureState::Armed;
+    }
+
+    /// Call once per control loop iteration.
+    pub fn push(&mut self, counter: u32, dev_state: &DeviceState, buf: &mut [StoredSample]) {
+        let triggered = match self.state {
+            CaptureState::Armed => self.check_trigger(dev_state),
+            CaptureState::Triggered => false,
+            CaptureState::Idle | CaptureState::Done => return,
+        };
+
+        if triggered {
+            let pre_trigger = (self.config.pre_trigger as usize).min(buf.len() - 1);
+            // Keep at most `pre_trigger` samples from before the trigger.
+            self.n_samples = self.n_samples.min(pre_trigger);
+            self.trigger_index = self.n_samples;
+            self.trigger_counter = counter;
+            self.post_remaining = buf.len() - pre_trigger;
+            self.state = CaptureState::Triggered;
+            // Always record the trigger sample.
+            self.countdown = 0;
+        }
+
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return;
+        }
+        self.countdown = self.config.decimation.get() - 1;
+
+        buf[self.write_idx] = StoredSample {
+            timestamp_us: dev_state.timestamp_us,
+            adc1: dev_state.adc1,
+            adc2: dev_state.adc2,
+            adc_sum: dev_state.adc_sum,
+            dac1: dev_state.dac1_out,
+            dac2: dev_state.dac2_out,
+        };
+        self.write_idx = (self.write_idx + 1) % buf.len();
+        self.n_samples = (self.n_samples + 1).min(buf.len());
+
+        if let CaptureState::Triggered = self.state {
+            self.post_remaining -= 1;
+            if self.post_remaining == 0 {
+                self.state = CaptureState::Done;
+            }
+        }
+    }
+
+    fn check_trigger(&mut self, dev_state: &DeviceState) -> bool {
+        let mode = &dev_state.inner.mode;
+        let locked = is_locked(dev_state);
+        let adc = (dev_state.adc1, dev_state.adc2, dev_state.adc_sum);
+
+        let triggered = match &self.config.trigger {
+            CaptureTrigger::Immediate => true,
+            CaptureTrigger::ModeChange => {
+                self.last_mode.as_ref().map(|last| last != mode).unwrap_or(false)
+            }
+            CaptureTrigger::LockLoss => self.last_locked && !locked,
+            CaptureTrigger::AdcThreshold(threshold) => {
+                let select = |adc: (i16, i16, i16)| match threshold.channel {
+                    AdcChannel::Adc1 => adc.0,
+                    AdcChannel::Adc2 => adc.1,
+                    AdcChannel::Sum => adc.2,
+                };
+                match self.last_adc {
+                    Some(last) => {
+                        let (last, cur) = (select(last), select(adc));
+                        match threshold.edge {
+                            Edge::Rising => last < threshold.level && cur >= threshold.level,
+                            Edge::Falling => last > threshold.level && cur <= threshold.level,
+                        }
+                    }
+                    None => false,
+                }
+            }
+        };
+
+        if self.last_mode.as_ref() != Some(mode) {
+            self.last_mode = Some(mode.clone());
+        }
+        self.last_locked = locked;
+        self.last_adc = Some(adc);
+        triggered
+    }
+
+    pub fn status(&self, buffer_size: usize) -> CaptureStatus {
+        CaptureStatus {
+            state: self.state,
+            n_samples: self.n_samples as u16,
+            trigger_index: self.trigger_index as u16,
+            trigger_counter: self.trigger_counter,
+            buffer_size: buffer_size as u16,
+        }
+    }
+
+    /// Read captured samples, oldest first.
+    pub fn read(&self, offset: u16, buf: &[StoredSample]) -> CaptureChunk {
+        let mut chunk = CaptureChunk {
+            offset,
+            samples: [StoredSample::default(); CAPTURE_CHUNK_SIZE],
+        };
+        let oldest = (self.write_idx + buf.len() - self.n_samples) % buf.len();
+        for (i, sample) in chunk.samples.iter_mut().enumerate() {
+            let idx = offset as usize + i;
+            if idx < self.n_samples {
+                *sample = buf[(oldest + idx) % buf.len()];
+            }
+        }
+        chunk
+    }
+}
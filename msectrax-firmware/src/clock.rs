@@ -0,0 +1,48 @@
+//! The device clock for `timestamp_us`, counting microseconds since boot.
+//!
+//! TIM1 counts microseconds and TIM4, clocked by the TIM1 update event,
+//! counts its overflows. Together they form a 32 bit counter which runs
+//! without interrupts.
+
+use stm32_hal::rcc::Clocks;
+use stm32_hal::stm32::{RCC, TIM1, TIM4};
+
+/// Start the clock. The timers cannot be used for anything else.
+pub fn start(_tim1: TIM1, _tim4: TIM4, clocks: &Clocks) {
+    let rcc = unsafe { &*RCC::ptr() };
+    rcc.apb2enr.modify(|_, w| w.tim1en().set_bit());
+    rcc.apb1enr.modify(|_, w| w.tim4en().set_bit());
+
+    let tim1 = unsafe { &*TIM1::ptr() };
+    let tim4 = unsafe { &*TIM4::ptr() };
+
+    // The low half. The update generated to load the prescaler must not be
+    // counted, so TIM4 starts afterwards.
+    let psc = clocks.pclk2_tim().0 / 1_000_000 - 1;
+    tim1.psc.write(|w| w.psc().bits(psc as u16));
+    tim1.arr.write(|w| w.arr().bits(0xFFFF));
+    tim1.egr.write(|w| w.ug().set_bit());
+    tim1.cr2.modify(|_, w| w.mms().update());
+
+    // The high half: external clock mode 1 from ITR0, which is TIM1 TRGO.
+    tim4.arr.write(|w| w.arr().bits(0xFFFF));
+    tim4.smcr.modify(|_, w| unsafe { w.ts().bits(0b000).sms().bits(0b111) });
+    tim4.cnt.write(|w| w.cnt().bits(0));
+    tim4.cr1.modify(|_, w| w.cen().set_bit());
+
+    tim1.cr1.modify(|_, w| w.cen().set_bit());
+}
+
+/// Microseconds since `start`, wrapping after about 71.6 minutes.
+pub fn now() -> u32 {
+    let tim1 = unsafe { &*TIM1::ptr() };
+    let tim4 = unsafe { &*TIM4::ptr() };
+    loop {
+        // Retry if TIM1 overflowed between the reads.
+        let high = tim4.cnt.read().bits() & 0xFFFF;
+        let low = tim1.cnt.read().bits() & 0xFFFF;
+        if tim4.cnt.read().bits() & 0xFFFF == high {
+            return high << 16 | low;
+        }
+    }
+}
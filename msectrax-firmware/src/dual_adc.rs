@@ -0,0 +1,185 @@
+//! ADC1 and ADC2 sampling both QPD channels at the same time, and the QPD
+//! sum.
+//!
+//! The ADCs run in regular simultaneous mode, triggered by TIM3 at
+//! `oversampling` times the control loop rate. Each trigger converts a
+//! sequence of two: first both QPD channels, then the sum on ADC1 while ADC2
+//! converts its channel again (the same channel must not be sampled by both
+//! at once). DMA copies each pair of conversions (ADC1 in the low and ADC2 in
+//! the high half word of the ADC1 data register) into a circular buffer,
+//! which therefore always holds the conversions of the last control loop
+//! tick. The control loop averages them without waiting for the ADCs.
+
+use stm32_hal::adc::Adc;
+use stm32_hal::dma::dma1::C1;
+use stm32_hal::rcc::Clocks;
+use stm32_hal::stm32::{ADC1, ADC2, DMA1, TIM3};
+use stm32_hal::timer::CountDownTimer;
+use stm32_hal::prelude::*;
+
+use msectrax_comms::{ErrorCode, MAX_ADC_OVERSAMPLING};
+
+use crate::{ADC1ChType, ADC2ChType, ADCSumChType};
+
+/// ADC clock cycles per trigger: two conversions of 7.5 cycles sampling time
+/// and 12.5 cycles conversion.
+const ADC_CYCLES: u32 = 40;
+/// `SMPx` value for 7.5 cycles.
+const SAMPLE_TIME: u8 = 0b010;
+/// ADC12_IN11 on PC1, ADC12_IN9 on PB1 and ADC12_IN10 on PC0.
+const ADC1_CHANNEL: u8 = 11;
+const ADC2_CHANNEL: u8 = 9;
+const SUM_CHANNEL: u8 = 10;
+
+/// Conversions per trigger.
+const SEQUENCE_LEN: usize = 2;
+const BUFFER_LEN: usize = SEQUENCE_LEN * MAX_ADC_OVERSAMPLING as usize;
+
+/// Written by DMA only.
+static mut SAMPLES: [u32; BUFFER_LEN] = [0; BUFFER_LEN];
+
+pub struct DualAdc {
+    // Only kept so that nothing else uses the ADCs, the HAL does not support
+    // the dual modes.
+    _adc1: Adc<ADC1>,
+    _adc2: Adc<ADC2>,
+    _pins: (ADC1ChType, ADC2ChType, ADCSumChType),
+    dma: C1,
+    trigger: CountDownTimer<TIM3>,
+    trigger_clock_hz: u32,
+    max_rate_hz: u32,
+    /// The requested `(loop_rate_hz, oversampling)`.
+    config: Option<(u32, u8)>,
+    oversampling: u8,
+    sample_rate_hz: u32,
+}
+
+impl DualAdc {
+    /// Takes the ADCs after `Adc::adc1` and `Adc::adc2` powered them up and
+    /// calibrated them. Sampling starts with `configure`.
+    pub fn new(adc1: Adc<ADC1>, adc2: Adc<ADC2>, pins: (ADC1ChType, ADC2ChType, ADCSumChType),
+        dma: C1, trigger: CountDownTimer<TIM3>, clocks: &Clocks) -> Self
+    {
+        let regs1 = unsafe { &*ADC1::ptr() };
+        let regs2 = unsafe { &*ADC2::ptr() };
+
+        // Regular simultaneous mode, scanning the sequence.
+        regs1.cr1.modify(|_, w| unsafe { w.dualmod().bits(0b0110).scan().set_bit() });
+        regs2.cr1.modify(|_, w| w.scan().set_bit());
+        // ADC1 is started by TIM3 TRGO, ADC2 follows it but still needs the
+        // external trigger enabled with the software start selected.
+        regs1.cr2.modify(|_, w| unsafe {
+            w.cont().clear_bit()
+                .align().clear_bit()
+                .dma().set_bit()
+                .extsel().bits(0b100)
+                .exttrig().set_bit()
+        });
+        regs2.cr2.modify(|_, w| unsafe {
+            w.cont().clear_bit()
+                .align().clear_bit()
+                .extsel().bits(0b111)
+                .exttrig().set_bit()
+        });
+        // Sequences of the same length and sampling times.
+        regs1.sqr1.modify(|_, w| unsafe { w.l().bits(SEQUENCE_LEN as u8 - 1) });
+        regs1.sqr3.modify(|_, w| unsafe { w.sq1().bits(ADC1_CHANNEL).sq2().bits(SUM_CHANNEL) });
+        regs1.smpr1.modify(|_, w| unsafe { w.smp11().bits(SAMPLE_TIME).smp10().bits(SAMPLE_TIME) });
+        regs2.sqr1.modify(|_, w| unsafe { w.l().bits(SEQUENCE_LEN as u8 - 1) });
+        regs2.sqr3.modify(|_, w| unsafe { w.sq1().bits(ADC2_CHANNEL).sq2().bits(ADC2_CHANNEL) });
+        regs2.smpr2.modify(|_, w| unsafe { w.smp9().bits(SAMPLE_TIME) });
+
+        let mut dma = dma;
+        dma.set_peripheral_address(&regs1.dr as *const _ as u32, false);
+        dma.set_memory_address(unsafe { SAMPLES.as_ptr() } as u32, true);
+        dma.ch().cr.modify(|_, w| {
+            w.mem2mem().clear_bit()
+                .pl().high()
+                .msize().bits32()
+                .psize().bits32()
+                .circ().set_bit()
+                .dir().clear_bit()
+        });
+
+        Self {
+            _adc1: adc1,
+            _adc2: adc2,
+            _pins: pins,
+            dma,
+            trigger,
+            trigger_clock_hz: clocks.pclk1_tim().0,
+            max_rate_hz: clocks.adcclk().0 / ADC_CYCLES,
+            config: None,
+            oversampling: 1,
+            sample_rate_hz: 0,
+        }
+    }
+
+    /// Sample `oversampling` times per control loop tick, or as often as the
+    /// ADCs can. Does nothing if the configuration did not change.
+    pub fn configure(&mut self, loop_rate_hz: u32, oversampling: u8) {
+        if self.config == Some((loop_rate_hz, oversampling)) {
+            return;
+        }
+        self.config = Some((loop_rate_hz, oversampling));
+
+        let max_oversampling = (self.max_rate_hz / loop_rate_hz).min(MAX_ADC_OVERSAMPLING as u32) as u8;
+        let n = oversampling.min(max_oversampling).max(1);
+
+        let tim3 = unsafe { &*TIM3::ptr() };
+        tim3.cr1.modify(|_, w| w.cen().clear_bit());
+        self.restart_dma(n);
+        self.oversampling = n;
+        self.trigger.start((loop_rate_hz * n as u32).hz());
+        tim3.cr2.modify(|_, w| w.mms().update());
+
+        // The timer can only approximate the requested rate.
+        let psc = tim3.psc.read().bits() + 1;
+        let arr = tim3.arr.read().bits() + 1;
+        self.sample_rate_hz = self.trigger_clock_hz / (psc * arr);
+    }
+
+    fn restart_dma(&mut self, n: u8) {
+        self.dma.stop();
+        self.dma.set_transfer_length(SEQUENCE_LEN * n as usize);
+        self.dma.start();
+    }
+
+    /// The average of the conversions of the last control loop tick.
+    /// Returns `(adc1, adc2, sum)`.
+    pub fn read(&mut self) -> Result<(u16, u16, u16), ErrorCode> {
+        let dma1 = unsafe { &*DMA1::ptr() };
+        if dma1.isr.read().teif1().bit_is_set() {
+            // The channel was disabled by the error.
+            dma1.ifcr.write(|w| w.ctif1().set_bit());
+            self.restart_dma(self.oversampling);
+            return Err(ErrorCode::AdcError);
+        }
+
+        let n = self.oversampling as u32;
+        let mut total1 = 0;
+        let mut total2 = 0;
+        let mut total_sum = 0;
+        for i in 0..self.oversampling as usize {
+            // Each entry is written at once, so can be read while DMA
+            // replaces others.
+            let pair = unsafe { core::ptr::read_volatile(&SAMPLES[SEQUENCE_LEN * i]) };
+            total1 += pair & 0xFFFF;
+            total2 += pair >> 16;
+            let sum_pair = unsafe { core::ptr::read_volatile(&SAMPLES[SEQUENCE_LEN * i + 1]) };
+            total_sum += sum_pair & 0xFFFF;
+        }
+        let average = |total: u32| ((total + n / 2) / n) as u16;
+        Ok((average(total1), average(total2), average(total_sum)))
+    }
+
+    /// The number of conversions averaged by `read`.
+    pub fn oversampling(&self) -> u8 {
+        self.oversampling
+    }
+
+    /// Conversions per second of each ADC.
+    pub fn sample_rate_hz(&self) -> u32 {
+        self.sample_rate_hz
+    }
+}
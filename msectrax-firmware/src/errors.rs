@@ -0,0 +1,25 @@
+use msectrax_comms::ErrorCode;
+
+/// An error which happened outside of a request, waiting to be sent.
+///
+/// Only the first error is kept until it is sent, so a failure which repeats
+/// every control loop tick does not flood the serial link.
+pub struct ErrorLog {
+    pending: Option<(ErrorCode, u32)>,
+}
+
+impl ErrorLog {
+    pub fn new() -> Self {
+        Self { pending: None }
+    }
+
+    pub fn record(&mut self, code: ErrorCode, detail: u32) {
+        if self.pending.is_none() {
+            self.pending = Some((code, detail));
+        }
+    }
+
+    pub fn take(&mut self) -> Option<(ErrorCode, u32)> {
+        self.pending.take()
+    }
+}
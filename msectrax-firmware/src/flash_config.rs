@@ -0,0 +1,91 @@
+use stm32_hal::stm32::{flash::RegisterBlock, FLASH};
+
+/// Start of the last 1K flash page, which is left out of `FLASH` in the linker
+/// script and holds the saved `SetDeviceState`.
+const CONFIG_ADDR: u32 = 0x0800_FC00;
+const PAGE_SIZE: usize = 1024;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+/// The raw contents of the config page.
+pub fn read() -> &'static [u8] {
+    // Safe because the page is always mapped and is only changed by `write`
+    // and `erase`, which run from idle() like all users of the slice.
+    unsafe { core::slice::from_raw_parts(CONFIG_ADDR as *const u8, PAGE_SIZE) }
+}
+
+/// Erase the config page.
+///
+/// The CPU stalls while the flash is busy (about 20 ms), so this delays the
+/// control loop.
+///
+/// On error, returns the flash status register.
+pub fn erase() -> Result<(), u32> {
+    let flash = unsafe { &*FLASH::ptr() };
+    unlock(flash);
+    flash.cr.modify(|_, w| w.per().set_bit());
+    flash.ar.write(|w| unsafe { w.bits(CONFIG_ADDR) });
+    flash.cr.modify(|_, w| w.strt().set_bit());
+    let result = wait_done(flash);
+    flash.cr.modify(|_, w| w.per().clear_bit());
+    lock(flash);
+    result
+}
+
+/// Erase the config page and program `data` into it.
+///
+/// On error, returns the flash status register.
+pub fn write(data: &[u8]) -> Result<(), u32> {
+    if data.len() > PAGE_SIZE {
+        return Err(0);
+    }
+    erase()?;
+    let flash = unsafe { &*FLASH::ptr() };
+    unlock(flash);
+    flash.cr.modify(|_, w| w.pg().set_bit());
+    let mut result = Ok(());
+    // The flash is programmed a half-word at a time, pad with the erased
+    // value.
+    for (i, pair) in data.chunks(2).enumerate() {
+        let half_word = u16::from_le_bytes([pair[0], *pair.get(1).unwrap_or(&0xFF)]);
+        let addr = (CONFIG_ADDR as usize + 2 * i) as *mut u16;
+        unsafe { core::ptr::write_volatile(addr, half_word) };
+        result = wait_done(flash);
+        if result.is_err() {
+            break;
+        }
+    }
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+    lock(flash);
+    result?;
+    if &read()[..data.len()] != data {
+        return Err(flash.sr.read().bits());
+    }
+    Ok(())
+}
+
+fn unlock(flash: &RegisterBlock) {
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+        flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+    }
+}
+
+fn lock(flash: &RegisterBlock) {
+    flash.cr.modify(|_, w| w.lock().set_bit());
+}
+
+/// Wait for the current operation and check for errors.
+fn wait_done(flash: &RegisterBlock) -> Result<(), u32> {
+    while flash.sr.read().bsy().bit_is_set() {}
+    let sr = flash.sr.read();
+    let failed = sr.pgerr().bit_is_set() || sr.wrprterr().bit_is_set();
+    // Clear the flags by writing ones.
+    flash.sr.write(|w| unsafe { w.bits(sr.bits()) });
+    if failed {
+        Err(sr.bits())
+    } else {
+        Ok(())
+    }
+}
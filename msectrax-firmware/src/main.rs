@@ -26,10 +26,29 @@ below but have not tested if this frees PB3.
 // PA7 SPI1_MOSI (Arduino D11)
 // PB6 = data latch "A0" (Arduino D10)
 // PC7 = update "A1" (Arduino D9)
+// The cascade is two DAC714s for the galvos, optionally followed by a third
+// for `AuxConfig`.
 
 // ADC:
 // PC1 Analog adc1 (Arduino A4)
 // PB1 Analog adc2 (no Arduino pin)
+// PC0 Analog QPD sum (Arduino A5)
+// adc1 and adc2 are sampled at the same time by ADC1 and ADC2, see `dual_adc`.
+
+// Sync:
+// PA0 trigger input (Arduino A0), timestamped by the EXTI0 interrupt
+// PA1 sync output (Arduino A1)
+// See `SyncConfig`.
+
+// Timer:
+// TIM2 drives the control loop (ADC sampling, control and DAC output) at
+// `SetDeviceState::loop_rate_hz`. The serial protocol is handled in idle().
+// TIM3 triggers the ADC conversions.
+// TIM1 and TIM4 count the microseconds for `timestamp_us`, see `clock`.
+
+// Watchdogs:
+// The control loop parks the galvos if the host stops sending (see
+// `HeartbeatConfig`), and the IWDG resets the MCU if idle() stops running.
 
 // Future ADC idea: perhaps switch to an ADC peripheral such as
 // - ADS1602IPFBT (SPI)
@@ -43,33 +62,54 @@ use stm32_hal::serial::{self, Serial, Rx};
 use stm32_hal::stm32::{USART2 as OtherUSART2, Interrupt};
 use stm32_hal::spi::Spi;
 use stm32_hal::gpio::{Output, PushPull};
-use stm32_hal::gpio::gpioa::{PA5, PA6, PA7};
+use stm32_hal::gpio::gpioa::{PA1, PA5, PA6, PA7};
 use stm32_hal::gpio::gpiob::PB6;
 use stm32_hal::gpio::gpioc::PC7;
 
-use stm32_hal::stm32::SPI1;
+use stm32_hal::stm32::{SPI1, TIM2};
 use stm32_hal::adc;
+use stm32_hal::timer::{Timer, CountDownTimer, Event};
+use stm32_hal::watchdog::IndependentWatchdog;
 
 use embedded_hal::digital::v2::OutputPin;
 
+use cortex_m::peripheral::DWT;
+
 use mini_rxtx::Decoded;
 
-use msectrax_comms::{ToDevice, FromDevice, DeviceState, DeviceMode,
-    ClosedLoopMode};
+use msectrax_comms::{ToDevice, FromDevice, DeviceState,
+    ToDeviceEnvelope, FromDeviceEnvelope, StoredSample, ErrorCode, DeviceInfo,
+    AuxMode, AnalogReading, WAVE_TABLE_SIZE};
+use msectrax_control::{ControllerState, OutputState, HeartbeatMonitor, SyncOutput,
+    calculate_next_dac_values, update_outputs, calc_next_update, initial_state, set_galvos};
 mod wrapped_tx;
+mod stream;
+mod capture;
+mod timing;
+mod errors;
+mod flash_config;
+mod dual_adc;
+mod trigger_input;
+mod clock;
 
 // -----------------------
 
-const BUFFER_SIZE: usize = 1200;
+/// Capacity of the capture buffer, see "RAM budget" in the README.
+const BUFFER_SIZE: usize = 800;
 
-#[allow(dead_code)]
-#[derive(Copy,Clone)]
-pub struct StoredSample {
-    adc1: u16,
-    adc2: u16,
-    dac1: u16,
-    dac2: u16,
-}
+const EMPTY_SAMPLE: StoredSample = StoredSample {
+    timestamp_us: 0, adc1: 0, adc2: 0, adc_sum: 0, dac1: 0, dac2: 0,
+};
+
+/// Address of the 96 bit unique device id of the STM32F1.
+const MCU_UID_ADDR: *const u32 = 0x1FFF_F7E8 as *const u32;
+
+/// Space in the transmit queue kept free for replies when streaming.
+const REPLY_RESERVE: usize = 128;
+
+/// The MCU is reset if idle() does not run for this long. Longer than
+/// erasing and programming the config page.
+const WATCHDOG_TIMEOUT_MS: u32 = 500;
 
 // -----------------------
 
@@ -116,6 +156,10 @@ impl From<stm32_hal::spi::Error> for MyError {
 
 // -----------------------
 
+pub type RxTx = mini_rxtx::MiniTxRx<Rx<OtherUSART2>,wrapped_tx::WrappedTx>;
+
+// -----------------------
+
 pub type PushPullGpio = stm32_hal::gpio::Alternate<stm32_hal::gpio::PushPull>;
 
 pub type FloatInput = stm32_hal::gpio::Input<stm32_hal::gpio::Floating>;
@@ -127,73 +171,97 @@ pub type MyCascade = Dac714Cascade<Spi<SPI1,stm32_hal::spi::Spi1NoRemap,(PA5<Pus
 
 pub type ADC1ChType = stm32_hal::gpio::gpioc::PC1<stm32_hal::gpio::Analog>;
 pub type ADC2ChType = stm32_hal::gpio::gpiob::PB1<stm32_hal::gpio::Analog>;
+pub type ADCSumChType = stm32_hal::gpio::gpioc::PC0<stm32_hal::gpio::Analog>;
 
-pub struct AnalogSystem {
-    dev_adc1: stm32_hal::adc::Adc<stm32_hal::device::ADC1>,
-    dev_adc2: stm32_hal::adc::Adc<stm32_hal::device::ADC2>,
-    adc1: ADC1ChType,
-    adc2: ADC2ChType,
-}
+pub type SyncPinType = PA1<Output<PushPull>>;
 
 // -----------------------
 
-/// Calculate error angle based on the current ADC values and the calibration data
-fn to_angle( p: &msectrax_comms::AdcToAngleCalibration, adc1: i16, adc2: i16 ) -> f32 {
-    let adc1 = adc1 as f32;
-    let adc2 = adc2 as f32;
-    adc1*p.adc1_gain + adc2*p.adc2_gain + p.offset
-}
-
-fn query_adcs( dev_state: &mut DeviceState, analog: &mut AnalogSystem ) {
-    let adc1: u16 = analog.dev_adc1.read(&mut analog.adc1).unwrap();
-    let adc2: u16 = analog.dev_adc2.read(&mut analog.adc2).unwrap();
+/// Read the ADCs. On error, the previous values are kept.
+fn query_adcs( dev_state: &mut DeviceState, analog: &mut dual_adc::DualAdc ) -> Result<(),ErrorCode> {
+    dev_state.adc_oversampling = analog.oversampling();
+    dev_state.adc_sample_rate_hz = analog.sample_rate_hz();
+    let (adc1, adc2, adc_sum) = analog.read()?;
 
     dev_state.adc1 = adc1 as i16;
     dev_state.adc2 = adc2 as i16;
+    dev_state.adc_sum = adc_sum as i16;
+    Ok(())
 }
 
-/// update galvos, returns true if galvo state changed
-fn calculate_next_dac_values( dev_state: &mut DeviceState, cl_next_update_cycle: &mut u32) {
-
-    // TODO FIXME: the timing here will not be very precise, since we update by
-    // a fixed amount every time we are called. But since we are called from
-    // idle(), it is not clear how often that will be, and it may be rather
-    // variable.
-    match &dev_state.inner.mode {
-        DeviceMode::SawtoothTest => {
-            dev_state.dac1 = dev_state.dac1.wrapping_add(10);
-            dev_state.dac2 = dev_state.dac2.wrapping_add(20);
-        }
-        DeviceMode::SampleAdc => {}
-        DeviceMode::ClosedLoop(cl_params) => {
-            dev_state.cl_cycles = dev_state.cl_cycles.wrapping_add(1);
-
-            if dev_state.cl_cycles == *cl_next_update_cycle {
-                let azimuth_error = to_angle( &dev_state.inner.dac1_angle_func, dev_state.adc1, dev_state.adc2 ) - dev_state.inner.dac1_initial as f32;
-                let elevation_error = to_angle( &dev_state.inner.dac2_angle_func, dev_state.adc1, dev_state.adc2 ) - dev_state.inner.dac2_initial as f32;
-
-                match cl_params {
-                    ClosedLoopMode::Proportional => {
-                        dev_state.dac1_f32 += azimuth_error*dev_state.inner.dac1_angle_gain;
-                        dev_state.dac2_f32 += elevation_error*dev_state.inner.dac2_angle_gain;
-                    },
-                }
+fn device_info(headstage_id: u16, aux_mode: AuxMode) -> DeviceInfo {
+    let mut mcu_uid = [0; 3];
+    for (i, word) in mcu_uid.iter_mut().enumerate() {
+        // Safe because the unique id is always readable.
+        *word = unsafe { core::ptr::read_volatile(MCU_UID_ADDR.add(i)) };
+    }
+    DeviceInfo {
+        firmware_version: msectrax_comms::to_fixed_str(env!("CARGO_PKG_VERSION")),
+        git_hash: msectrax_comms::to_fixed_str(env!("MSECTRAX_GIT_HASH")),
+        build_time: env!("MSECTRAX_BUILD_TIME").parse().unwrap_or(0),
+        board: msectrax_comms::Board::NucleoF103RB,
+        headstage_id,
+        mcu_uid,
+        buffer_size: BUFFER_SIZE as u16,
+        modes: msectrax_comms::SupportedModes {
+            sawtooth_test: true,
+            sample_adc: true,
+            closed_loop: true,
+            proportional: true,
+            proportional_integral: true,
+            proportional_integral_derivative: true,
+            search: true,
+            waveform: true,
+            frequency_sweep: true,
+        },
+        n_adc_channels: 3,
+        // A board may lack the third DAC714, see `AuxMode::Disabled`.
+        n_dac_channels: if aux_mode == AuxMode::Disabled { 2 } else { 3 },
+    }
+}
 
-                dev_state.dac1 = dev_state.dac1_f32 as i16;
-                dev_state.dac2 = dev_state.dac2_f32 as i16;
-                *cl_next_update_cycle = cl_next_update_cycle.wrapping_add(dev_state.inner.cl_period.get());
-            }
-        }
+/// Queue a message for the host, keeping `reserve` bytes of space free.
+///
+/// On error, returns the error code and detail to report.
+fn queue_msg( rxtx: &mut RxTx, msg: mini_rxtx::SerializedMsg, reserve: usize ) -> Result<(),(ErrorCode,u32)> {
+    let n_bytes = msg.framed_slice().len();
+    if rxtx.tx_space() < n_bytes + reserve {
+        return Err((ErrorCode::TxQueueFull, n_bytes as u32));
     }
+    rxtx.send_msg(msg).map_err(|_| (ErrorCode::TxQueueFull, n_bytes as u32))
+}
 
-    // clip dac values
-    dev_state.dac1 = clip(dev_state.dac1,
-        dev_state.inner.dac1_min,
-        dev_state.inner.dac1_max);
-    dev_state.dac2 = clip(dev_state.dac2,
-        dev_state.inner.dac2_min,
-        dev_state.inner.dac2_max);
+/// Queue a message from the control loop with `UNSOLICITED_ID`.
+///
+/// If the host cannot keep up, these are dropped rather than the replies.
+/// Nothing is queued while `reply_waiting`.
+fn queue_unsolicited( rxtx: &mut RxTx, reply_waiting: bool, msg: FromDevice ) -> Result<(),(ErrorCode,u32)> {
+    let envelope = FromDeviceEnvelope {
+        id: msectrax_comms::UNSOLICITED_ID,
+        msg,
+    };
+    let mut encode_buf: [u8; 256] = [0; 256];
+    let sent = match mini_rxtx::serialize_msg(&envelope, &mut encode_buf) {
+        Ok(msg) if reply_waiting => Err((ErrorCode::TxQueueFull, msg.framed_slice().len() as u32)),
+        Ok(msg) => queue_msg(rxtx, msg, REPLY_RESERVE),
+        Err(_) => Err((ErrorCode::EncodeError, 0)),
+    };
+    rtfm::pend(Interrupt::USART2);
+    sent
+}
 
+/// Wait until `n_bytes` fit in the transmit queue, sending queued bytes
+/// meanwhile. A stream can keep the queue too full for a large reply, so the
+/// control loop stops queuing unsolicited messages while `reply_waiting`.
+fn wait_for_tx_space( rxtx: &mut impl rtfm::Mutex<T=RxTx>, reply_waiting: &mut impl rtfm::Mutex<T=bool>,
+    watchdog: &mut IndependentWatchdog, n_bytes: usize )
+{
+    while rxtx.lock(|rxtx| rxtx.tx_space()) < n_bytes {
+        reply_waiting.lock(|reply_waiting| *reply_waiting = true);
+        watchdog.feed();
+        rxtx.lock(|rxtx| rxtx.pump_sender());
+    }
+    reply_waiting.lock(|reply_waiting| *reply_waiting = false);
 }
 
 fn delay_func() {
@@ -208,13 +276,33 @@ fn delay_func() {
 const APP: () = {
     // Late resources
     struct Resources {
-        rxtx: mini_rxtx::MiniTxRx<Rx<OtherUSART2>,wrapped_tx::WrappedTx>,
+        rxtx: RxTx,
         state: DeviceState,
         cl_next_update_cycle: u32,
+        controller: ControllerState,
+        output: OutputState,
         dac714_cascade: MyCascade,
         // itm: cortex_m::peripheral::ITM,
-        analog: AnalogSystem,
+        analog: dual_adc::DualAdc,
+        // Initialized statically rather than returned from init(), which
+        // would need room for a copy on the stack.
+        #[init([EMPTY_SAMPLE; BUFFER_SIZE])]
         ram_buffer: [StoredSample; BUFFER_SIZE],
+        #[init([0; WAVE_TABLE_SIZE])]
+        wave_table: [i16; WAVE_TABLE_SIZE],
+        #[init(false)]
+        reply_waiting: bool,
+        timer: CountDownTimer<TIM2>,
+        streamer: Option<stream::Streamer>,
+        capture: capture::Capture,
+        sample_counter: u32,
+        timing: timing::LoopTiming,
+        errors: errors::ErrorLog,
+        heartbeat: HeartbeatMonitor,
+        watchdog: IndependentWatchdog,
+        trigger_input: trigger_input::TriggerInput,
+        sync_output: SyncOutput,
+        sync_pin: SyncPinType,
     }
 
     #[init]
@@ -223,6 +311,19 @@ const APP: () = {
         // Device specific peripherals
         let device: stm32_hal::stm32::Peripherals = c.device;
 
+        // Cortex-M peripherals
+        let mut cp = c.core;
+
+        // enable the cycle counter for loop timing
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+
+        let mut errors = errors::ErrorLog::new();
+        if device.RCC.csr.read().iwdgrstf().bit_is_set() {
+            errors.record(ErrorCode::WatchdogReset, 0);
+        }
+        device.RCC.csr.modify(|_, w| w.rmvf().set_bit());
+
         let mut flash = device.FLASH.constrain();
         let mut rcc = device.RCC.constrain();
         let gpio_bus = &mut rcc.apb2;
@@ -243,9 +344,10 @@ const APP: () = {
         let dev_adc1 = adc::Adc::adc1(device.ADC1, &mut rcc.apb2, clocks);
         let dev_adc2 = adc::Adc::adc2(device.ADC2, &mut rcc.apb2, clocks);
 
-        // Configure pc1, pb1 as an analog input
+        // Configure pc1, pb1, pc0 as an analog input
         let adc1 = gpioc.pc1.into_analog(&mut gpioc.crl);
         let adc2 = gpiob.pb1.into_analog(&mut gpiob.crl);
+        let adc_sum = gpioc.pc0.into_analog(&mut gpioc.crl);
         // ADC stuff end ------
 
         // let cp = core;
@@ -311,7 +413,11 @@ const APP: () = {
         serial.listen(serial::Event::Rxne);
         // serial.listen(serial::Event::Txe); // TODO I am confused why this is not needed.
         let (tx, rx) = serial.split();
-        let state = DeviceState::default();
+        // Start with the saved configuration, if there is a valid one.
+        let state = match msectrax_comms::config::decode(flash_config::read()) {
+            Ok(inner) if inner.check().is_ok() => initial_state(inner),
+            _ => DeviceState::default(),
+        };
 
         // initialize dac714 cascade
         let cascade = {
@@ -351,45 +457,92 @@ const APP: () = {
             cascade
         };
 
-        let analog = AnalogSystem {
-            dev_adc1,
-            dev_adc2,
-            adc1,
-            adc2,
-        };
+        clock::start(device.TIM1, device.TIM4, &clocks);
+
+        // The trigger input and sync output, after the AFIO clock is on.
+        let trigger_pin = gpioa.pa0.into_floating_input(&mut gpioa.crl);
+        let mut trigger_input = trigger_input::TriggerInput::new(trigger_pin);
+        trigger_input.configure(state.inner.sync.trigger_edge);
+        let mut sync_pin = gpioa.pa1.into_push_pull_output(&mut gpioa.crl);
+        sync_pin.set_low().expect("sync set low");
+
+        // The ADC trigger, started by `configure`.
+        let adc_trigger = Timer::tim3(device.TIM3, &clocks, &mut rcc.apb1)
+            .start_count_down(state.inner.loop_rate_hz.get().hz());
+        let dma1 = device.DMA1.split(&mut rcc.ahb);
+        let mut analog = dual_adc::DualAdc::new(dev_adc1, dev_adc2, (adc1, adc2, adc_sum),
+            dma1.1, adc_trigger, &clocks);
+        analog.configure(state.inner.loop_rate_hz.get(), state.inner.adc_oversampling);
 
         let cl_next_update_cycle = calc_next_update(&state);
 
+        // start the control loop timer
+        let mut timer = Timer::tim2(device.TIM2, &clocks, &mut rcc.apb1)
+            .start_count_down(state.inner.loop_rate_hz.get().hz());
+        timer.listen(Event::Update);
+
+        let mut watchdog = IndependentWatchdog::new(device.IWDG);
+        watchdog.start(WATCHDOG_TIMEOUT_MS.ms());
+
         // Initialization of late resources
         init::LateResources {
             rxtx: mini_rxtx::MiniTxRx::new(wrapped_tx::WrappedTx{tx},rx),
             state,
             cl_next_update_cycle,
+            controller: ControllerState::default(),
+            output: OutputState::default(),
             dac714_cascade: cascade,
             // itm,
             analog,
-            ram_buffer: [StoredSample {adc1: 0, adc2: 0, dac1: 0, dac2: 0}; BUFFER_SIZE],
+            timer,
+            streamer: None,
+            capture: capture::Capture::new(),
+            sample_counter: 0,
+            timing: timing::LoopTiming::new(clocks.sysclk().0),
+            errors,
+            heartbeat: HeartbeatMonitor::default(),
+            watchdog,
+            trigger_input,
+            sync_output: SyncOutput::default(),
+            sync_pin,
         }
     }
 
 
-    #[idle(resources = [rxtx, state, cl_next_update_cycle, analog, dac714_cascade])]
+    #[idle(resources = [rxtx, state, cl_next_update_cycle, controller, analog, ram_buffer, wave_table, timer, streamer, capture, timing, errors, heartbeat, watchdog, trigger_input, reply_waiting])]
     fn idle(mut c: idle::Context) -> ! {
 
         // iprintln!(&mut resources.ITM.stim[0], "entered idle()");
 
-        let mut decode_buf = [0u8; 256];
+        let mut decode_buf = [0u8; msectrax_comms::MAX_MSG_SIZE];
         let mut decoder = mini_rxtx::Decoder::new(&mut decode_buf);
-        let mut encode_buf: [u8; 256] = [0; 256];
+        let mut encode_buf = [0u8; msectrax_comms::MAX_MSG_SIZE];
+        let mut last_rx_errors = 0;
+        let mut headstage_id = msectrax_comms::config::decode_headstage_id(flash_config::read()).unwrap_or(0);
 
         loop {
+            c.resources.watchdog.feed();
 
-            query_adcs( c.resources.state, c.resources.analog );
-
-            calculate_next_dac_values( c.resources.state, c.resources.cl_next_update_cycle);
-
-            c.resources.dac714_cascade.set_value_ab(
-                c.resources.state.dac1, c.resources.state.dac2 ).unwrap();
+            // Report problems which did not happen while handling a request.
+            let rx_errors = c.resources.rxtx.lock(|rxtx| rxtx.rx_errors());
+            if rx_errors != last_rx_errors {
+                last_rx_errors = rx_errors;
+                c.resources.errors.lock(|errors| errors.record(ErrorCode::RxError, rx_errors));
+            }
+            if let Some((code, detail)) = c.resources.errors.lock(|errors| errors.take()) {
+                let envelope = FromDeviceEnvelope {
+                    id: msectrax_comms::UNSOLICITED_ID,
+                    msg: FromDevice::Error { code, detail },
+                };
+                if let Ok(msg) = mini_rxtx::serialize_msg(&envelope, &mut encode_buf) {
+                    if c.resources.rxtx.lock(|sender| queue_msg(sender, msg, 0)).is_ok() {
+                        rtfm::pend(Interrupt::USART2);
+                    } else {
+                        // Try again once there is space.
+                        c.resources.errors.lock(|errors| errors.record(code, detail));
+                    }
+                }
+            }
 
             let maybe_byte = c.resources.rxtx.lock(|x| x.pump());
             if let Some(byte) = maybe_byte {
@@ -397,61 +550,200 @@ const APP: () = {
                 // iprintln!(&c.resources.itm.stim[0], "got byte: {}", byte);
 
                 // process byte
-                let response = match decoder.consume::<msectrax_comms::ToDevice>(byte) {
-                    Decoded::Msg(ToDevice::SetState(inner)) => {
-                        let dac1 = inner.dac1_initial;
-                        let dac2 = inner.dac2_initial;
-                        let dac1_f32 = dac1 as f32;
-                        let dac2_f32 = dac2 as f32;
-                        let next_state = DeviceState {
-                            inner,
-                            cl_cycles: 0,
-                            adc1: 0,
-                            adc2: 0,
-                            dac1,
-                            dac2,
-                            dac1_f32,
-                            dac2_f32,
-                        };
-                        *(c.resources.cl_next_update_cycle) = calc_next_update(&next_state);
-                        *(c.resources.state) = next_state;
-                        Some(FromDevice::Empty)
+                let (id, msg) = match decoder.consume::<ToDeviceEnvelope>(byte) {
+                    Decoded::Msg(envelope) => {
+                        c.resources.heartbeat.lock(|heartbeat| heartbeat.reset());
+                        (envelope.id, envelope.msg)
+                    }
+                    Decoded::FrameNotYetComplete => {
+                        // Frame not complete yet, do nothing until next byte.
+                        continue;
+                    }
+                    Decoded::Error(_) => {
+                        // The frame was dropped (and counted) by the decoder,
+                        // which now waits for the next valid frame.
+                        let dropped_frames = decoder.dropped_frames();
+                        c.resources.errors.lock(|errors| errors.record(ErrorCode::BadFrame, dropped_frames));
+                        continue;
+                    }
+                };
+                // Set by requests which replace the `SetDeviceState`.
+                let mut next_inner = None;
+                let response = match msg {
+                    ToDevice::SetState(inner) => match inner.check() {
+                        Ok(()) => {
+                            next_inner = Some(inner);
+                            FromDevice::Empty
+                        }
+                        Err((code, detail)) => FromDevice::Error { code, detail },
                     },
-                    Decoded::Msg(ToDevice::EchoRequest8(buf)) => {
-                        Some(FromDevice::EchoResponse8(buf))
+                    ToDevice::EchoRequest8(buf) => {
+                        FromDevice::EchoResponse8(buf)
                     }
-                    Decoded::Msg(ToDevice::QueryState) => {
-                        Some(FromDevice::EchoState(c.resources.state.clone()))
+                    ToDevice::QueryState => {
+                        FromDevice::EchoState(c.resources.state.lock(|state| state.clone()))
                     }
-                    Decoded::Msg(ToDevice::QueryAnalog) => {
-                        let analog_state = (c.resources.state.adc1, c.resources.state.adc2);
-                        Some(FromDevice::EchoAnalog(analog_state))
+                    ToDevice::QueryAnalog => {
+                        let reading = c.resources.state.lock(|state| AnalogReading {
+                            adc1: state.adc1,
+                            adc2: state.adc2,
+                            adc_sum: state.adc_sum,
+                            timestamp_us: state.timestamp_us,
+                        });
+                        FromDevice::EchoAnalog(reading)
                     }
-                    Decoded::Msg(ToDevice::QueryDatatypesVersion) => {
-                        Some(FromDevice::EchoDatatypesVersion(msectrax_comms::DATATYPES_VERSION))
+                    ToDevice::QueryDatatypesVersion => {
+                        FromDevice::EchoDatatypesVersion(msectrax_comms::DATATYPES_VERSION)
                     }
-                    Decoded::Msg(ToDevice::SetGalvos((dac1,dac2))) => {
-                        c.resources.state.inner.mode = DeviceMode::SampleAdc;
-                        c.resources.state.dac1 = dac1;
-                        c.resources.state.dac2 = dac2;
-                        Some(FromDevice::Empty)
+                    ToDevice::SetGalvos((dac1,dac2)) => {
+                        if c.resources.state.lock(|state| set_galvos(state, dac1, dac2)) {
+                            FromDevice::Empty
+                        } else {
+                            FromDevice::Error { code: ErrorCode::Parked, detail: 0 }
+                        }
                     }
-                    Decoded::FrameNotYetComplete => {
-                        // Frame not complete yet, do nothing until next byte.
-                        None
+                    ToDevice::SetAux(dac3) => {
+                        c.resources.state.lock(|state| state.inner.aux.dac3 = dac3);
+                        FromDevice::Empty
                     }
-                    Decoded::Error(_) => {
-                        panic!("error reading frame");
+                    ToDevice::StartStream { decimation } => {
+                        c.resources.streamer.lock(|streamer| {
+                            *streamer = Some(stream::Streamer::new(decimation));
+                        });
+                        FromDevice::Empty
+                    }
+                    ToDevice::StopStream => {
+                        c.resources.streamer.lock(|streamer| *streamer = None);
+                        FromDevice::Empty
+                    }
+                    ToDevice::ArmCapture(config) => {
+                        c.resources.capture.lock(|capture| capture.arm(config));
+                        FromDevice::Empty
+                    }
+                    ToDevice::QueryCaptureStatus => {
+                        let status = c.resources.capture.lock(|capture| capture.status(BUFFER_SIZE));
+                        FromDevice::EchoCaptureStatus(status)
+                    }
+                    ToDevice::ReadCapture { offset } => {
+                        let ram_buffer = &mut c.resources.ram_buffer;
+                        let chunk = c.resources.capture.lock(|capture| {
+                            ram_buffer.lock(|ram_buffer| capture.read(offset, ram_buffer))
+                        });
+                        FromDevice::EchoCaptureChunk(chunk)
+                    }
+                    ToDevice::QueryTiming => {
+                        FromDevice::EchoTiming(c.resources.timing.lock(|timing| timing.take_stats()))
+                    }
+                    ToDevice::QueryDeviceInfo => {
+                        let aux_mode = c.resources.state.lock(|state| state.inner.aux.mode);
+                        FromDevice::EchoDeviceInfo(device_info(headstage_id, aux_mode))
+                    }
+                    ToDevice::SaveConfig => {
+                        let inner = c.resources.state.lock(|state| state.inner.clone());
+                        // The reply is encoded later, so its buffer is free.
+                        match msectrax_comms::config::encode(&inner, headstage_id, &mut encode_buf) {
+                            Ok(n_bytes) => match flash_config::write(&encode_buf[..n_bytes]) {
+                                Ok(()) => FromDevice::Empty,
+                                Err(sr) => FromDevice::Error { code: ErrorCode::FlashError, detail: sr },
+                            },
+                            Err(_) => FromDevice::Error { code: ErrorCode::EncodeError, detail: 0 },
+                        }
+                    }
+                    ToDevice::LoadConfig => {
+                        match msectrax_comms::config::decode(flash_config::read()) {
+                            Ok(inner) => match inner.check() {
+                                Ok(()) => {
+                                    next_inner = Some(inner);
+                                    FromDevice::Empty
+                                }
+                                Err((code, detail)) => FromDevice::Error { code, detail },
+                            },
+                            Err(e) => FromDevice::Error { code: ErrorCode::NoConfig, detail: e as u32 },
+                        }
+                    }
+                    ToDevice::WriteWaveTable { offset, values } => {
+                        let offset = offset as usize;
+                        if offset < WAVE_TABLE_SIZE {
+                            c.resources.wave_table.lock(|wave_table| {
+                                let dest = &mut wave_table[offset..];
+                                let n = dest.len().min(values.len());
+                                dest[..n].copy_from_slice(&values[..n]);
+                            });
+                            FromDevice::Empty
+                        } else {
+                            FromDevice::Error { code: ErrorCode::OutOfRange, detail: offset as u32 }
+                        }
+                    }
+                    ToDevice::EraseConfig => {
+                        match flash_config::erase() {
+                            Ok(()) => FromDevice::Empty,
+                            Err(sr) => FromDevice::Error { code: ErrorCode::FlashError, detail: sr },
+                        }
+                    }
+                    ToDevice::Heartbeat => FromDevice::Empty,
+                    ToDevice::SetHeadstageId(id) => {
+                        headstage_id = id;
+                        FromDevice::Empty
+                    }
+                    ToDevice::QuerySweep => {
+                        FromDevice::EchoSweep(c.resources.controller.lock(|controller| controller.sweep_result().clone()))
                     }
                 };
-                if let Some(response) = response {
-                    let msg = mini_rxtx::serialize_msg(&response, &mut encode_buf).unwrap();
-                    c.resources.rxtx.lock( |sender| {
-                        sender.send_msg(msg).unwrap();
+                if let Some(inner) = next_inner {
+                    let loop_rate_hz = inner.loop_rate_hz;
+                    let adc_oversampling = inner.adc_oversampling;
+                    let trigger_edge = inner.sync.trigger_edge;
+                    let next_state = initial_state(inner);
+                    let next_update_cycle = calc_next_update(&next_state);
+
+                    // Update everything used by the control loop at once.
+                    let cl_next_update_cycle = &mut c.resources.cl_next_update_cycle;
+                    let controller = &mut c.resources.controller;
+                    let timer = &mut c.resources.timer;
+                    c.resources.state.lock(|state| {
+                        cl_next_update_cycle.lock(|cl_next_update_cycle| {
+                            controller.lock(|controller| {
+                                timer.lock(|timer| {
+                                    if state.inner.loop_rate_hz != loop_rate_hz {
+                                        timer.start(loop_rate_hz.get().hz());
+                                    }
+                                    *state = next_state;
+                                    *cl_next_update_cycle = next_update_cycle;
+                                    *controller = ControllerState::default();
+                                });
+                            });
+                        });
                     });
-
-                    rtfm::pend(Interrupt::USART2);
+                    c.resources.analog.lock(|analog| {
+                        analog.configure(loop_rate_hz.get(), adc_oversampling)
+                    });
+                    c.resources.trigger_input.lock(|trigger_input| trigger_input.configure(trigger_edge));
                 }
+                let response = FromDeviceEnvelope { id, msg: response };
+                let sent = match mini_rxtx::serialize_msg(&response, &mut encode_buf) {
+                    Ok(msg) => {
+                        wait_for_tx_space(&mut c.resources.rxtx, &mut c.resources.reply_waiting,
+                            c.resources.watchdog, msg.framed_slice().len());
+                        c.resources.rxtx.lock(|sender| queue_msg(sender, msg, 0))
+                    }
+                    Err(_) => {
+                        // Reply with an error instead, which is small enough to
+                        // encode.
+                        let response = FromDeviceEnvelope {
+                            id,
+                            msg: FromDevice::Error { code: ErrorCode::EncodeError, detail: 0 },
+                        };
+                        match mini_rxtx::serialize_msg(&response, &mut encode_buf) {
+                            Ok(msg) => c.resources.rxtx.lock(|sender| queue_msg(sender, msg, 0)),
+                            Err(_) => Err((ErrorCode::EncodeError, 0)),
+                        }
+                    }
+                };
+                if let Err((code, detail)) = sent {
+                    c.resources.errors.lock(|errors| errors.record(code, detail));
+                }
+
+                rtfm::pend(Interrupt::USART2);
 
             } else {
                 // TODO: fix things so we can do this. Right we busy-loop.
@@ -461,27 +753,84 @@ const APP: () = {
         }
     }
 
+    /// The control loop, called at `SetDeviceState::loop_rate_hz`.
+    #[task(binds = TIM2, priority = 2, resources = [timer, rxtx, state, cl_next_update_cycle, controller, output, analog, dac714_cascade, ram_buffer, wave_table, streamer, capture, sample_counter, timing, errors, heartbeat, trigger_input, sync_output, sync_pin, reply_waiting])]
+    fn control_loop(mut c: control_loop::Context) {
+        let start = DWT::get_cycle_count();
+        c.resources.timer.clear_update_interrupt_flag();
+        let sample_counter = *c.resources.sample_counter;
+        c.resources.state.timestamp_us = clock::now();
+
+        while let Some(event) = c.resources.trigger_input.lock(|trigger_input| trigger_input.take(sample_counter)) {
+            if let Err((code, detail)) = queue_unsolicited(c.resources.rxtx, *c.resources.reply_waiting, FromDevice::TriggerEvent(event)) {
+                c.resources.errors.record(code, detail);
+            }
+        }
+
+        if let Err(code) = query_adcs( c.resources.state, c.resources.analog ) {
+            c.resources.errors.record(code, 0);
+        }
+
+        if c.resources.heartbeat.tick( c.resources.state ) {
+            c.resources.errors.record(ErrorCode::HeartbeatTimeout, 0);
+        }
+
+        calculate_next_dac_values( c.resources.state, c.resources.cl_next_update_cycle, c.resources.controller,
+            c.resources.wave_table);
+        update_outputs( c.resources.state, c.resources.output );
+
+        let state = &c.resources.state;
+        let written = match state.inner.aux.mode {
+            AuxMode::Disabled => c.resources.dac714_cascade.set_value_ab(
+                state.dac1_out, state.dac2_out ),
+            _ => c.resources.dac714_cascade.set_value_abc(
+                state.dac1_out, state.dac2_out, state.dac3_out ),
+        };
+        if written.is_err() {
+            c.resources.errors.record(ErrorCode::SpiError, 0);
+        }
+
+        let pulse = c.resources.sync_output.tick(c.resources.state, sample_counter);
+        if c.resources.sync_output.is_high() {
+            c.resources.sync_pin.set_high().ok();
+        } else {
+            c.resources.sync_pin.set_low().ok();
+        }
+        if let Some(pulse) = pulse {
+            if c.resources.state.inner.sync.report_pulses {
+                if let Err((code, detail)) = queue_unsolicited(c.resources.rxtx, *c.resources.reply_waiting, FromDevice::SyncPulse(pulse)) {
+                    c.resources.errors.record(code, detail);
+                }
+            }
+        }
+
+        if let Some(streamer) = c.resources.streamer.as_mut() {
+            if let Some(batch) = streamer.push(sample_counter, c.resources.state) {
+                let sent = queue_unsolicited(c.resources.rxtx, *c.resources.reply_waiting, FromDevice::SampleBatch(batch.clone()));
+                if let Err((code, detail)) = sent {
+                    c.resources.errors.record(code, detail);
+                }
+            }
+        }
+        c.resources.capture.push(sample_counter, c.resources.state, c.resources.ram_buffer);
+        *c.resources.sample_counter = sample_counter.wrapping_add(1);
+
+        c.resources.timing.record(start, DWT::get_cycle_count(),
+            c.resources.state.inner.loop_rate_hz.get());
+    }
+
+    /// Timestamps the trigger input edges.
+    #[task(binds = EXTI0, priority = 3, resources = [trigger_input])]
+    fn trigger_edge(c: trigger_edge::Context) {
+        let cycles = DWT::get_cycle_count();
+        c.resources.trigger_input.on_interrupt(clock::now(), cycles);
+    }
+
     // for F103
     #[task(binds = USART2, resources = [rxtx])]
-    fn usart2(c: usart2::Context) {
-        c.resources.rxtx.on_interrupt();
+    fn usart2(mut c: usart2::Context) {
+        c.resources.rxtx.lock(|rxtx| rxtx.on_interrupt());
     }
 
 };
 
-fn calc_next_update(state: &DeviceState) -> u32 {
-    state.cl_cycles + state.inner.cl_period.get()
-}
-
-fn clip<R>(cur: R, min: R, max: R) -> R
-    where
-        R: core::cmp::PartialOrd,
-{
-    if cur < min {
-        return min;
-    }
-    if cur > max {
-        return max;
-    }
-    cur
-}
@@ -0,0 +1,53 @@
+use msectrax_comms::{DeviceState, SampleBatch, StoredSample, SAMPLE_BATCH_SIZE};
+
+/// Collects every `decimation`-th sample into batches for streaming.
+pub struct Streamer {
+    decimation: u16,
+    countdown: u16,
+    n_samples: usize,
+    batch: SampleBatch,
+}
+
+impl Streamer {
+    pub fn new(decimation: core::num::NonZeroU16) -> Self {
+        Self {
+            decimation: decimation.get(),
+            countdown: 0,
+            n_samples: 0,
+            batch: SampleBatch {
+                counter: 0,
+                decimation: decimation.get(),
+                samples: [StoredSample::default(); SAMPLE_BATCH_SIZE],
+            },
+        }
+    }
+
+    /// Call once per control loop iteration. Returns a batch when it is full.
+    pub fn push(&mut self, counter: u32, state: &DeviceState) -> Option<&SampleBatch> {
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return None;
+        }
+        self.countdown = self.decimation - 1;
+
+        if self.n_samples == 0 {
+            self.batch.counter = counter;
+        }
+        self.batch.samples[self.n_samples] = StoredSample {
+            timestamp_us: state.timestamp_us,
+            adc1: state.adc1,
+            adc2: state.adc2,
+            adc_sum: state.adc_sum,
+            dac1: state.dac1_out,
+            dac2: state.dac2_out,
+        };
+        self.n_samples += 1;
+
+        if self.n_samples == SAMPLE_BATCH_SIZE {
+            self.n_samples = 0;
+            Some(&self.batch)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use msectrax_comms::TimingStats;
+
+/// Control loop timing from the DWT cycle counter.
+pub struct LoopTiming {
+    cpu_hz: u32,
+    /// Cycle count at the start of the current one second window.
+    window_start: Option<u32>,
+    window_iterations: u32,
+    iterations_per_second: u32,
+    n_iterations: u32,
+    min_cycles: u32,
+    max_cycles: u32,
+    sum_cycles: u64,
+    missed_deadlines: u32,
+}
+
+impl LoopTiming {
+    pub fn new(cpu_hz: u32) -> Self {
+        Self {
+            cpu_hz,
+            window_start: None,
+            window_iterations: 0,
+            iterations_per_second: 0,
+            n_iterations: 0,
+            min_cycles: u32::max_value(),
+            max_cycles: 0,
+            sum_cycles: 0,
+            missed_deadlines: 0,
+        }
+    }
+
+    /// Call once per control loop iteration with the cycle counter at its
+    /// start and end.
+    pub fn record(&mut self, start: u32, end: u32, loop_rate_hz: u32) {
+        // The cycle counter wraps, so only differences are meaningful.
+        let duration = end.wrapping_sub(start);
+        self.n_iterations = self.n_iterations.wrapping_add(1);
+        self.min_cycles = self.min_cycles.min(duration);
+        self.max_cycles = self.max_cycles.max(duration);
+        self.sum_cycles += duration as u64;
+        if duration > self.cpu_hz / loop_rate_hz {
+            self.missed_deadlines = self.missed_deadlines.wrapping_add(1);
+        }
+
+        match self.window_start {
+            Some(window_start) if start.wrapping_sub(window_start) < self.cpu_hz => {
+                self.window_iterations += 1;
+            }
+            Some(_) => {
+                self.iterations_per_second = self.window_iterations;
+                self.window_start = Some(start);
+                self.window_iterations = 1;
+            }
+            None => {
+                self.window_start = Some(start);
+                self.window_iterations = 1;
+            }
+        }
+    }
+
+    /// Get the statistics and start collecting new ones.
+    pub fn take_stats(&mut self) -> TimingStats {
+        let stats = TimingStats {
+            iterations_per_second: self.iterations_per_second,
+            n_iterations: self.n_iterations,
+            min_cycles: if self.n_iterations > 0 { self.min_cycles } else { 0 },
+            mean_cycles: if self.n_iterations > 0 {
+                (self.sum_cycles / self.n_iterations as u64) as u32
+            } else {
+                0
+            },
+            max_cycles: self.max_cycles,
+            missed_deadlines: self.missed_deadlines,
+            cpu_hz: self.cpu_hz,
+        };
+        self.n_iterations = 0;
+        self.min_cycles = u32::max_value();
+        self.max_cycles = 0;
+        self.sum_cycles = 0;
+        stats
+    }
+}
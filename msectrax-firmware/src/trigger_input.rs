@@ -0,0 +1,87 @@
+//! Timestamps edges on the trigger input PA0, see `SyncConfig::trigger_edge`.
+//!
+//! EXTI0 interrupts at a higher priority than the control loop, so the clocks
+//! are read within a few cycles of the edge. The control loop reports the
+//! edges.
+
+use stm32_hal::gpio::{Input, Floating};
+use stm32_hal::gpio::gpioa::PA0;
+use stm32_hal::stm32::{AFIO, EXTI};
+
+use msectrax_comms::{Edge, TriggerEvent};
+
+/// Edges kept until the control loop takes them.
+const MAX_PENDING: usize = 4;
+
+pub struct TriggerInput {
+    _pin: PA0<Input<Floating>>,
+    edge: Option<Edge>,
+    /// Edges since boot.
+    count: u32,
+    /// The `(count, timestamp_us, cycles)` of the edges not yet taken, oldest
+    /// first. The count is stored because dropped edges leave gaps.
+    pending: [(u32, u32, u32); MAX_PENDING],
+    n_pending: usize,
+}
+
+impl TriggerInput {
+    /// Connects EXTI0 to PA0, with both edges disabled.
+    pub fn new(pin: PA0<Input<Floating>>) -> Self {
+        // Requires the AFIO clock, enabled by `AFIO::constrain`.
+        let afio = unsafe { &*AFIO::ptr() };
+        afio.exticr1.modify(|_, w| unsafe { w.exti0().bits(0b0000) });
+        Self {
+            _pin: pin,
+            edge: None,
+            count: 0,
+            pending: [(0, 0, 0); MAX_PENDING],
+            n_pending: 0,
+        }
+    }
+
+    /// Interrupt on `edge`, or not at all.
+    pub fn configure(&mut self, edge: Option<Edge>) {
+        if self.edge == edge {
+            return;
+        }
+        self.edge = edge;
+        let exti = unsafe { &*EXTI::ptr() };
+        exti.imr.modify(|_, w| w.mr0().clear_bit());
+        exti.rtsr.modify(|_, w| w.tr0().bit(edge == Some(Edge::Rising)));
+        exti.ftsr.modify(|_, w| w.tr0().bit(edge == Some(Edge::Falling)));
+        exti.pr.write(|w| w.pr0().set_bit());
+        exti.imr.modify(|_, w| w.mr0().bit(edge.is_some()));
+    }
+
+    /// Call from the EXTI0 interrupt with the device clock and the cycle
+    /// counter.
+    pub fn on_interrupt(&mut self, timestamp_us: u32, cycles: u32) {
+        let exti = unsafe { &*EXTI::ptr() };
+        exti.pr.write(|w| w.pr0().set_bit());
+
+        self.count = self.count.wrapping_add(1);
+        // When full, the edge is only counted.
+        if self.n_pending < MAX_PENDING {
+            self.pending[self.n_pending] = (self.count, timestamp_us, cycles);
+            self.n_pending += 1;
+        }
+    }
+
+    /// The oldest edge not yet taken, for the control loop tick with the
+    /// sample counter `counter`.
+    pub fn take(&mut self, counter: u32) -> Option<TriggerEvent> {
+        if self.n_pending == 0 {
+            return None;
+        }
+        let (count, timestamp_us, cycles) = self.pending[0];
+        let event = TriggerEvent {
+            count,
+            counter,
+            timestamp_us,
+            cycles,
+        };
+        self.pending.copy_within(1..self.n_pending, 0);
+        self.n_pending -= 1;
+        Some(event)
+    }
+}
@@ -1,6 +1,8 @@
 /* Linker script for the STM32F103C8T6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  /* The last 1K page is reserved for the saved configuration, see
+     src/flash_config.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 63K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
@@ -33,19 +33,23 @@ struct Model {
     web: FetchService,
     _interval_task: Box<dyn Task>,
     ft: Option<FetchTask>,
+    ft_timing: Option<FetchTask>,
     link: ComponentLink<Self>,
     dac1_initial: TypedInputStorage<DacValue>,
     dac2_initial: TypedInputStorage<DacValue>,
     last_state: Option<msectrax_comms::DeviceState>,
+    last_timing: Option<msectrax_comms::TimingStats>,
     query_state: bool,
 }
 
 enum Msg {
     Holdoff,
     Lockon,
+    Search,
     CheckState,
     Ignore,
     GotState(msectrax_comms::DeviceState),
+    GotTiming(msectrax_comms::TimingStats),
     ToggleQueryState(bool),
 }
 
@@ -62,10 +66,12 @@ impl Component for Model {
             web: FetchService::new(),
             _interval_task: Box::new(handle),
             ft: None,
+            ft_timing: None,
             link,
             dac1_initial: TypedInputStorage::from_initial(DacValue(-3365)),
             dac2_initial: TypedInputStorage::from_initial(DacValue(-3709)),
             last_state: None,
+            last_timing: None,
             query_state: true,
         }
     }
@@ -97,16 +103,41 @@ impl Component for Model {
                 }
                 return false; // don't update DOM, do that on return
             },
+            Msg::Search => {
+                if let Ok(dac1) = self.dac1_initial.parsed() {
+                    if let Ok(dac2) = self.dac2_initial.parsed() {
+                        let mut initial = match &self.last_state {
+                            None => msectrax_comms::SetDeviceState::default(),
+                            Some(s) => s.inner.clone(),
+                        };
+                        initial.mode = msectrax_comms::DeviceMode::Search( msectrax_comms::SearchParams {
+                            dac1_center: dac1.0,
+                            dac2_center: dac2.0,
+                            ..msectrax_comms::SearchParams::default()
+                        });
+                        initial.dac1_initial = dac1.0;
+                        initial.dac2_initial = dac2.0;
+                        let msg = msectrax_comms::ToDevice::SetState(initial);
+                        self.ft = Some(send_message(&msg, self));
+                    }
+                }
+                return false; // don't update DOM, do that on return
+            },
             Msg::CheckState => {
                 if self.query_state {
                     let msg = msectrax_comms::ToDevice::QueryState;
                     self.ft = Some(send_message(&msg, self));
+                    let msg = msectrax_comms::ToDevice::QueryTiming;
+                    self.ft_timing = Some(send_message(&msg, self));
                 }
                 return false; // don't update DOM, do that on return
             },
             Msg::GotState(state) => {
                 self.last_state = Some(state);
             }
+            Msg::GotTiming(timing) => {
+                self.last_timing = Some(timing);
+            }
             Msg::ToggleQueryState(v) => {
                 self.query_state = v;
             }
@@ -131,6 +162,9 @@ fn send_message(msg: &msectrax_comms::ToDevice, model: &mut Model) -> FetchTask
             Ok(msectrax_comms::FromDevice::EchoState(state)) => {
                 Msg::GotState(state)
             },
+            Ok(msectrax_comms::FromDevice::EchoTiming(timing)) => {
+                Msg::GotTiming(timing)
+            },
             Ok(msectrax_comms::FromDevice::Empty) => {
                 Msg::Ignore
             },
@@ -164,10 +198,20 @@ impl Model {
                 <div>
                     {"Mode: "}{format!("{:?}",state.inner.mode)}
                     <div>
+                        <p>{"Device time: "}{format!("{:.3} s",state.timestamp_us as f64 * 1e-6)}</p>
                         <p>{"DAC1: "}{format!("{}",state.dac1)}</p>
                         <p>{"DAC2: "}{format!("{}",state.dac2)}</p>
+                        <p>{"DAC3 (aux): "}{format!("{} ({:?})",state.dac3_out,state.inner.aux.mode)}</p>
                         <p>{"ADC1: "}{format!("{}",state.adc1)}</p>
                         <p>{"ADC2: "}{format!("{}",state.adc2)}</p>
+                        <p>{"QPD sum: "}{format!("{}",state.adc_sum)}</p>
+                        <p>{"ADC sampling: "}{format!("{} Hz, {} averaged per tick",state.adc_sample_rate_hz,state.adc_oversampling)}</p>
+                        <p>{"Target: "}{format!("{:?} (signal {})",state.target,state.signal)}</p>
+                        <p>{"Parked: "}{if state.parked { "yes, the host stopped sending" } else { "no" }}</p>
+                        <p>{"Lock point: "}{match state.lock_point {
+                            Some(p) => format!("DAC1 {}, DAC2 {} (search point {})",p.dac1,p.dac2,p.index),
+                            None => "none".to_string(),
+                        }}</p>
                     </div>
                     <div class="preformatted",>
                         {state_string}
@@ -178,6 +222,22 @@ impl Model {
             html!{<div></div>}
         }
     }
+
+    fn view_timing(&self) -> Html<Model> {
+        if let Some(ref timing) = self.last_timing {
+            let to_usec = |cycles: u32| cycles as f64 * 1e6 / timing.cpu_hz as f64;
+            html! {
+                <div>
+                    <p>{"Loop rate: "}{format!("{} Hz",timing.iterations_per_second)}</p>
+                    <p>{"Loop duration (min/mean/max): "}{format!("{:.1} / {:.1} / {:.1} usec",
+                        to_usec(timing.min_cycles), to_usec(timing.mean_cycles), to_usec(timing.max_cycles))}</p>
+                    <p>{"Missed deadlines: "}{format!("{}",timing.missed_deadlines)}</p>
+                </div>
+            }
+        } else {
+            html!{<div></div>}
+        }
+    }
 }
 
 impl Renderable<Model> for Model {
@@ -210,6 +270,9 @@ impl Renderable<Model> for Model {
                     <div class="button-holder",>
                         <Button: title="Lock", onsignal=|_| Msg::Lockon,/>
                     </div>
+                    <div class="button-holder",>
+                        <Button: title="Search", onsignal=|_| Msg::Search,/>
+                    </div>
                 </div>
 
                 <div class="border-1px",>
@@ -223,6 +286,11 @@ impl Renderable<Model> for Model {
 
                     { self.view_state() }
                 </div>
+
+                <div class="border-1px",>
+                    <h2>{"Loop Timing"}</h2>
+                    { self.view_timing() }
+                </div>
             </div>
         }
     }
@@ -4,6 +4,7 @@ extern crate log;
 mod error;
 
 use futures::Future;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 use std::sync::Arc;
 use parking_lot::Mutex;
@@ -17,11 +18,47 @@ use structopt::StructOpt;
 
 use crossbeam_channel::Receiver;
 
-use msectrax_comms::{DeviceMode, ClosedLoopMode};
+use msectrax_comms::{DeviceMode, ClosedLoopMode, ToDeviceEnvelope,
+    FromDeviceEnvelope};
 use crate::error::Error as MyError;
 
 type MyResult<T> = std::result::Result<T,MyError>;
 
+/// How long to wait for the reply to a request before giving up on it.
+const REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1000);
+
+/// Send a `Heartbeat` when nothing else was sent to the device for this long,
+/// well within the device's `HeartbeatConfig::timeout_ms`.
+const KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Number of keepalive `Heartbeat` ids remembered until their reply arrives.
+/// Enough for `REPLY_TIMEOUT`, older ones are assumed lost.
+const MAX_PENDING_HEARTBEATS: usize = 16;
+
+/// Number of HTTP requests which can be in flight to the device at once.
+const N_SERIAL_EXECUTORS: usize = 4;
+
+/// Number of streamed sample batches kept until they are fetched.
+const MAX_STORED_BATCHES: usize = 10_000;
+
+/// Number of trigger input and sync output events kept until they are
+/// fetched.
+const MAX_STORED_EVENTS: usize = 10_000;
+
+/// Streamed sample batches which have not yet been fetched over HTTP, as
+/// JSON with the `DeviceClock` times.
+type SampleStore = Arc<Mutex<VecDeque<serde_json::Value>>>;
+
+/// `TriggerEvent` and `SyncPulse` messages which have not yet been fetched
+/// over HTTP, as JSON with the `DeviceClock` times.
+type EventStore = Arc<Mutex<VecDeque<serde_json::Value>>>;
+
+/// Columns of the `/bode` table, the fields of `SweepPoint`.
+const BODE_COLUMNS: [&str; 5] = ["frequency_hz", "dac_amplitude", "adc_amplitude", "gain", "phase_deg"];
+
+/// The identity of the connected device, once known.
+type DeviceInfoStore = Arc<Mutex<Option<serde_json::Value>>>;
+
 #[allow(dead_code)]
 #[cfg(target_os = "macos")]
 const DEFAULT_DEVICE: &'static str = "/dev/tty.usbmodem1423";
@@ -59,13 +96,87 @@ fn show_examples(http_addr: &str) {
 
     let mut closed_loop_state = msectrax_comms::SetDeviceState::default();
     closed_loop_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::Proportional);
+    closed_loop_state.aux = msectrax_comms::AuxConfig {
+        mode: msectrax_comms::AuxMode::LockGated,
+        dac3: 10000,
+        dac3_off: 0,
+    };
+    closed_loop_state.sync = msectrax_comms::SyncConfig {
+        trigger_edge: Some(msectrax_comms::Edge::Rising),
+        pulse_on: msectrax_comms::SyncTrigger::LockAcquired,
+        pulse_us: 1000,
+        report_pulses: true,
+    };
+    let mut pid_state = msectrax_comms::SetDeviceState::default();
+    pid_state.mode = DeviceMode::ClosedLoop(ClosedLoopMode::ProportionalIntegralDerivative(
+        msectrax_comms::PidParams {
+            dac1: msectrax_comms::PidGains::default(),
+            dac2: msectrax_comms::PidGains::default(),
+            prediction: Some(msectrax_comms::PredictionParams::default()),
+        }));
+    let mut search_state = msectrax_comms::SetDeviceState::default();
+    search_state.mode = DeviceMode::Search(msectrax_comms::SearchParams::default());
+    let mut waveform_state = msectrax_comms::SetDeviceState::default();
+    let sine = msectrax_comms::AxisWaveform {
+        shape: msectrax_comms::WaveShape::Sine,
+        amplitude: 10000,
+        offset: 0,
+        frequency_hz: 10.0,
+        phase_deg: 0.0,
+    };
+    waveform_state.mode = DeviceMode::Waveform(msectrax_comms::WaveformParams {
+        dac1: sine.clone(),
+        dac2: msectrax_comms::AxisWaveform { phase_deg: 90.0, ..sine },
+    });
+    let mut sweep_state = msectrax_comms::SetDeviceState::default();
+    let mut frequencies_hz = [0.0; msectrax_comms::MAX_SWEEP_POINTS];
+    for (i, frequency_hz) in frequencies_hz.iter_mut().enumerate() {
+        // logarithmically spaced from 10 Hz to 3 kHz
+        *frequency_hz = 10.0*300.0f32.powf(i as f32 / (msectrax_comms::MAX_SWEEP_POINTS - 1) as f32);
+    }
+    sweep_state.mode = DeviceMode::FrequencySweep(msectrax_comms::SweepParams {
+        axis: msectrax_comms::SweepAxis::Dac1,
+        response: msectrax_comms::AdcChannel::Adc1,
+        amplitude: 1000,
+        frequencies_hz,
+        n_frequencies: msectrax_comms::MAX_SWEEP_POINTS as u8,
+        settle_periods: 5,
+        measure_periods: std::num::NonZeroU16::new(20).unwrap(),
+    });
     let example_msgs = [
         EchoRequest8((1,2,3,4,5,6,7,8)),
         SetState(msectrax_comms::SetDeviceState::default()),
         SetState(closed_loop_state),
+        SetState(pid_state),
+        SetState(search_state),
+        SetState(waveform_state),
+        SetState(sweep_state),
         QueryState,
         QueryAnalog,
         SetGalvos((0,0)),
+        SetAux(10000),
+        StartStream { decimation: std::num::NonZeroU16::new(10).unwrap() },
+        StopStream,
+        ArmCapture(msectrax_comms::CaptureConfig {
+            trigger: msectrax_comms::CaptureTrigger::AdcThreshold(msectrax_comms::AdcThreshold {
+                channel: msectrax_comms::AdcChannel::Adc1,
+                level: 2048,
+                edge: msectrax_comms::Edge::Rising,
+            }),
+            pre_trigger: 200,
+            decimation: std::num::NonZeroU16::new(1).unwrap(),
+        }),
+        QueryCaptureStatus,
+        ReadCapture { offset: 0 },
+        QueryTiming,
+        QueryDeviceInfo,
+        SetHeadstageId(2),
+        SaveConfig,
+        LoadConfig,
+        EraseConfig,
+        WriteWaveTable { offset: 0, values: [0; msectrax_comms::WAVE_TABLE_CHUNK_SIZE] },
+        Heartbeat,
+        QuerySweep,
     ];
     let bufs: Vec<String> = example_msgs.iter().map(|msg| format!("    {}",serde_json::to_string(&msg).unwrap()) ).collect();
     println!("# Example messages understood as JSON HTTP requests: \n\n{}\n", bufs.join("\n\n"));
@@ -76,6 +187,27 @@ fn show_examples(http_addr: &str) {
     println!("# Example usage with curl:
 
         {}", curl_cmd);
+
+    println!("
+# While streaming, fetch (and remove) the received samples with:
+
+        curl http://{}/samples", http_addr);
+
+    println!("
+# Fetch (and remove) the trigger input and sync output events with:
+
+        curl http://{}/events", http_addr);
+
+    println!("
+# Fetch the frequency response measured by a FrequencySweep as JSON or CSV with:
+
+        curl http://{0}/bode
+        curl http://{0}/bode?format=csv", http_addr);
+
+    println!("
+# Show the identity of the connected device with:
+
+        curl http://{}/device-info", http_addr);
 }
 
 enum VersionCheck {
@@ -83,17 +215,112 @@ enum VersionCheck {
     Success,
 }
 
+/// A message for the device and the channel for its reply.
+pub struct Request {
+    to_device: msectrax_comms::ToDevice,
+    reply_tx: crossbeam_channel::Sender<DeviceReply>,
+}
+
+/// A reply from the device, and the same as JSON with the `DeviceClock` times.
+pub struct DeviceReply {
+    msg: msectrax_comms::FromDevice,
+    json: serde_json::Value,
+}
+
+struct PendingReply {
+    reply_tx: crossbeam_channel::Sender<DeviceReply>,
+    sent: std::time::Instant,
+}
+
+/// Extends the device's 32 bit `timestamp_us` values to a 64 bit time in
+/// microseconds, which keeps increasing when the device clock wraps.
+///
+/// This works as long as the device sends timestamps at least every half
+/// wrap (about 35 minutes), for example while streaming, and until the device
+/// restarts.
+#[derive(Default)]
+struct DeviceClock {
+    /// The latest timestamp and its 64 bit time.
+    latest: Option<(u32, u64)>,
+}
+
+impl DeviceClock {
+    fn unwrap(&mut self, timestamp_us: u32) -> u64 {
+        let (latest_timestamp, latest_time) = match self.latest {
+            Some(latest) => latest,
+            None => {
+                self.latest = Some((timestamp_us, timestamp_us as u64));
+                return timestamp_us as u64;
+            }
+        };
+        // Messages are not in timestamp order, for example a sample batch is
+        // older than a state sent before it.
+        let diff = msectrax_comms::timestamp_diff_us(timestamp_us, latest_timestamp);
+        let time = (latest_time as i64 + diff as i64).max(0) as u64;
+        if diff > 0 {
+            self.latest = Some((timestamp_us, time));
+        }
+        time
+    }
+
+    /// Add a `time_us` next to every `timestamp_us` in `value`. Zero
+    /// timestamps mark missing samples and get no time.
+    fn add_times(&mut self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                let timestamp_us = map.get("timestamp_us").and_then(|t| t.as_u64());
+                if let Some(timestamp_us) = timestamp_us.filter(|t| *t != 0) {
+                    let time_us = self.unwrap(timestamp_us as u32);
+                    map.insert("time_us".to_string(), time_us.into());
+                }
+                for item in map.values_mut() {
+                    self.add_times(item);
+                }
+            }
+            serde_json::Value::Array(items) => {
+                for item in items.iter_mut() {
+                    self.add_times(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Convert `msg` to JSON with the times added.
+    fn to_json(&mut self, msg: &msectrax_comms::FromDevice) -> serde_json::Value {
+        let mut value = serde_json::to_value(msg).expect("to_value");
+        self.add_times(&mut value);
+        value
+    }
+}
+
 struct SerialThread {
     ser: Box<dyn serialport::SerialPort>,
-    outq: Receiver<msectrax_comms::ToDevice>,
-    from_device_tx: crossbeam_channel::Sender<msectrax_comms::FromDevice>,
+    outq: Receiver<Request>,
+    next_id: u16,
+    pending: HashMap<u16, PendingReply>,
+    samples: SampleStore,
+    events: EventStore,
+    device_info: DeviceInfoStore,
+    /// Id of the `QueryDatatypesVersion` request sent at startup.
+    version_check_id: Option<u16>,
+    /// Id of the `QueryDeviceInfo` request sent at startup.
+    device_info_id: Option<u16>,
+    /// When the last message was sent to the device.
+    last_sent: std::time::Instant,
+    /// Ids of the keepalive `Heartbeat`s without a reply yet, nobody waits
+    /// for them.
+    heartbeat_ids: VecDeque<u16>,
+    clock: DeviceClock,
 }
 
 impl SerialThread {
     fn new(
         device: &std::path::Path,
-        outq: Receiver<msectrax_comms::ToDevice>,
-        from_device_tx: crossbeam_channel::Sender<msectrax_comms::FromDevice>,
+        outq: Receiver<Request>,
+        samples: SampleStore,
+        events: EventStore,
+        device_info: DeviceInfoStore,
     ) -> MyResult<Self>
     {
 
@@ -114,30 +341,54 @@ impl SerialThread {
         Ok(Self {
             ser,
             outq,
-            from_device_tx,
+            next_id: msectrax_comms::UNSOLICITED_ID,
+            pending: HashMap::new(),
+            samples,
+            events,
+            device_info,
+            version_check_id: None,
+            device_info_id: None,
+            last_sent: std::time::Instant::now(),
+            heartbeat_ids: VecDeque::new(),
+            clock: DeviceClock::default(),
         })
     }
 
+    /// Get a new request id, never `UNSOLICITED_ID`.
+    fn next_id(&mut self) -> u16 {
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.next_id == msectrax_comms::UNSOLICITED_ID {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        self.next_id
+    }
+
     fn my_write(&mut self, buf: &[u8]) -> std::io::Result<()> {
         trace!("sending {} bytes", buf.len());
         for byte in buf.iter() {
             trace!("  sending byte: {}", byte);
         }
         self.ser.write(buf)?;
+        self.last_sent = std::time::Instant::now();
         Ok(())
     }
 
     fn run(&mut self, flag: thread_control::Flag) -> MyResult<()> {
-        let mut send_buf = [0; 256];
+        let mut send_buf = [0; msectrax_comms::MAX_MSG_SIZE];
         let mut read_buf = [0; 256];
 
-        let mut decode_buf = [0; 256];
+        let mut decode_buf = [0; msectrax_comms::MAX_MSG_SIZE];
         let mut decoder = mini_rxtx::Decoder::new(&mut decode_buf);
 
         // initiate version check
         let mut version_check_state = {
-            let serialized_msg = mini_rxtx::serialize_msg(&msectrax_comms::ToDevice::QueryDatatypesVersion, &mut send_buf).expect("serialize_msg");
+            let envelope = ToDeviceEnvelope {
+                id: self.next_id(),
+                msg: msectrax_comms::ToDevice::QueryDatatypesVersion,
+            };
+            let serialized_msg = mini_rxtx::serialize_msg(&envelope, &mut send_buf).expect("serialize_msg");
             self.my_write( serialized_msg.framed_slice() )?;
+            self.version_check_id = Some(envelope.id);
             info!("Sent firmware version request.");
             VersionCheck::Started(std::time::Instant::now())
         };
@@ -148,10 +399,18 @@ impl SerialThread {
             loop {
                 // Handle all new messages, but do not block.
                 match self.outq.recv_timeout(std::time::Duration::from_millis(0)) {
-                    Ok(msg) => {
-                        debug!("sending message {:?}", msg);
-                        let serialized_msg = mini_rxtx::serialize_msg(&msg, &mut send_buf).expect("serialize_msg");
+                    Ok(request) => {
+                        let envelope = ToDeviceEnvelope {
+                            id: self.next_id(),
+                            msg: request.to_device,
+                        };
+                        debug!("sending message {:?}", envelope);
+                        let serialized_msg = mini_rxtx::serialize_msg(&envelope, &mut send_buf).expect("serialize_msg");
                         self.my_write( serialized_msg.framed_slice() )?;
+                        self.pending.insert(envelope.id, PendingReply {
+                            reply_tx: request.reply_tx,
+                            sent: std::time::Instant::now(),
+                        });
                     },
                     Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
                         break;
@@ -163,6 +422,22 @@ impl SerialThread {
             }
 
 
+            // Keep the device from parking the galvos.
+            if let VersionCheck::Success = version_check_state {
+                if self.last_sent.elapsed() >= KEEPALIVE_INTERVAL {
+                    let envelope = ToDeviceEnvelope {
+                        id: self.next_id(),
+                        msg: msectrax_comms::ToDevice::Heartbeat,
+                    };
+                    let serialized_msg = mini_rxtx::serialize_msg(&envelope, &mut send_buf).expect("serialize_msg");
+                    self.my_write( serialized_msg.framed_slice() )?;
+                    if self.heartbeat_ids.len() == MAX_PENDING_HEARTBEATS {
+                        self.heartbeat_ids.pop_front();
+                    }
+                    self.heartbeat_ids.push_back(envelope.id);
+                }
+            }
+
             // TODO: this could be made (much) more efficient. Right
             // now, we wake up every timeout duration and run the whole
             // cycle when no byte arrives.
@@ -172,23 +447,36 @@ impl SerialThread {
                         let byte = read_buf[i];
                         trace!("read byte {} (char {})",
                             byte, String::from_utf8_lossy(&read_buf[i..i+1]));
-                        match decoder.consume::<msectrax_comms::FromDevice>(byte) {
-                            mini_rxtx::Decoded::Msg(msg) => {
-                                if let msectrax_comms::FromDevice::EchoDatatypesVersion(firmware_version) = msg {
-                                    info!("Firmware version {}", firmware_version);
-                                    if firmware_version != msectrax_comms::DATATYPES_VERSION {
-                                        return Err(crate::error::Error::FirmwareVersionMismatch((firmware_version,msectrax_comms::DATATYPES_VERSION)));
-                                    } else {
-                                        info!("firmware version OK.");
-                                        version_check_state = VersionCheck::Success;
+                        match decoder.consume::<FromDeviceEnvelope>(byte) {
+                            mini_rxtx::Decoded::Msg(envelope) => {
+                                match envelope.msg {
+                                    msectrax_comms::FromDevice::EchoDatatypesVersion(firmware_version)
+                                        if self.version_check_id == Some(envelope.id) =>
+                                    {
+                                        self.version_check_id = None;
+                                        info!("Firmware version {}", firmware_version);
+                                        if firmware_version != msectrax_comms::DATATYPES_VERSION {
+                                            return Err(crate::error::Error::FirmwareVersionMismatch((firmware_version,msectrax_comms::DATATYPES_VERSION)));
+                                        } else {
+                                            info!("firmware version OK.");
+                                            version_check_state = VersionCheck::Success;
+
+                                            let envelope = ToDeviceEnvelope {
+                                                id: self.next_id(),
+                                                msg: msectrax_comms::ToDevice::QueryDeviceInfo,
+                                            };
+                                            let serialized_msg = mini_rxtx::serialize_msg(&envelope, &mut send_buf).expect("serialize_msg");
+                                            self.my_write( serialized_msg.framed_slice() )?;
+                                            self.device_info_id = Some(envelope.id);
+                                        }
                                     }
-                                } else {
-                                    self.from_device_tx.send(msg).unwrap();
+                                    _ => self.dispatch(envelope),
                                 }
                             }
                             mini_rxtx::Decoded::FrameNotYetComplete => {}
                             mini_rxtx::Decoded::Error(e) => {
-                                return Err(e.into());
+                                warn!("dropped frame from device: {:?} ({} dropped total)",
+                                    e, decoder.dropped_frames());
                             }
                         }
                     }
@@ -201,6 +489,8 @@ impl SerialThread {
                 }
             }
 
+            self.expire_pending();
+
             version_check_state = match version_check_state {
                 VersionCheck::Started(start) => {
                     if start.elapsed() > std::time::Duration::from_millis(500) {
@@ -215,12 +505,124 @@ impl SerialThread {
 
         Ok(())
     }
+
+    /// Send a message from the device to whoever is waiting for it.
+    fn dispatch(&mut self, envelope: FromDeviceEnvelope) {
+        if envelope.id == msectrax_comms::UNSOLICITED_ID {
+            self.handle_unsolicited(envelope.msg);
+            return;
+        }
+        if let Some(i) = self.heartbeat_ids.iter().position(|id| *id == envelope.id) {
+            self.heartbeat_ids.remove(i);
+            return;
+        }
+        if let msectrax_comms::FromDevice::EchoTiming(ref timing) = envelope.msg {
+            log_timing(timing);
+        }
+        if let msectrax_comms::FromDevice::EchoDeviceInfo(ref info) = envelope.msg {
+            let info = device_info_json(info);
+            info!("device info: {}", info);
+            *self.device_info.lock() = Some(info);
+            if self.device_info_id == Some(envelope.id) {
+                // the startup request, nobody is waiting for it
+                self.device_info_id = None;
+                return;
+            }
+        }
+        match self.pending.remove(&envelope.id) {
+            Some(pending) => {
+                let json = self.clock.to_json(&envelope.msg);
+                // The receiver is gone if the request was abandoned.
+                if pending.reply_tx.send(DeviceReply { msg: envelope.msg, json }).is_err() {
+                    debug!("reply {} not needed anymore", envelope.id);
+                }
+            }
+            None => {
+                warn!("ignoring message with unknown id {}: {:?}", envelope.id, envelope.msg);
+            }
+        }
+    }
+
+    /// Handle a message which the device sent on its own.
+    fn handle_unsolicited(&mut self, msg: msectrax_comms::FromDevice) {
+        match msg {
+            msectrax_comms::FromDevice::SampleBatch(batch) => {
+                let mut batch = serde_json::to_value(&batch).expect("to_value");
+                self.clock.add_times(&mut batch);
+                let mut samples = self.samples.lock();
+                if samples.len() >= MAX_STORED_BATCHES {
+                    warn!("samples are not being fetched, discarding oldest");
+                    samples.pop_front();
+                }
+                samples.push_back(batch);
+            }
+            msectrax_comms::FromDevice::Error { code, detail } => {
+                error!("device error: {:?} (detail {})", code, detail);
+            }
+            msg @ msectrax_comms::FromDevice::TriggerEvent(_) |
+            msg @ msectrax_comms::FromDevice::SyncPulse(_) => {
+                let event = self.clock.to_json(&msg);
+                let mut events = self.events.lock();
+                if events.len() >= MAX_STORED_EVENTS {
+                    warn!("events are not being fetched, discarding oldest");
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+            msg => {
+                warn!("ignoring unexpected unsolicited message: {:?}", msg);
+            }
+        }
+    }
+
+    /// Give up on requests which have not been answered in time.
+    ///
+    /// Dropping the reply channel lets the waiting HTTP request fail.
+    fn expire_pending(&mut self) {
+        self.pending.retain(|id, pending| {
+            if pending.sent.elapsed() > REPLY_TIMEOUT {
+                warn!("no reply from device to request {}", id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Show control loop timing replies in the proxy log.
+fn log_timing(timing: &msectrax_comms::TimingStats) {
+    let to_usec = |cycles: u32| cycles as f64 * 1e6 / timing.cpu_hz as f64;
+    info!("control loop: {} Hz, duration min/mean/max {:.1}/{:.1}/{:.1} usec \
+        over {} iterations, {} missed deadlines",
+        timing.iterations_per_second,
+        to_usec(timing.min_cycles), to_usec(timing.mean_cycles), to_usec(timing.max_cycles),
+        timing.n_iterations, timing.missed_deadlines);
 }
 
+/// Convert the device info to JSON with readable text fields.
+fn device_info_json(info: &msectrax_comms::DeviceInfo) -> serde_json::Value {
+    use msectrax_comms::fixed_str;
+    serde_json::json!({
+        "firmware_version": fixed_str(&info.firmware_version),
+        "git_hash": fixed_str(&info.git_hash),
+        "build_time": info.build_time,
+        "board": info.board,
+        "headstage_id": info.headstage_id,
+        "mcu_uid": format!("{:08x}{:08x}{:08x}", info.mcu_uid[2], info.mcu_uid[1], info.mcu_uid[0]),
+        "buffer_size": info.buffer_size,
+        "modes": info.modes,
+        "n_adc_channels": info.n_adc_channels,
+        "n_dac_channels": info.n_dac_channels,
+    })
+}
 
 /// This is state where we will store *SerialExecutor* address.
 struct AppState {
     serial_executor: actix::Addr<SerialExecutor>,
+    samples: SampleStore,
+    events: EventStore,
+    device_info: DeviceInfoStore,
 }
 
 pub struct WrappedToDevice {
@@ -228,12 +630,11 @@ pub struct WrappedToDevice {
 }
 
 impl Message for WrappedToDevice {
-    type Result = Result<msectrax_comms::FromDevice, Error>;
+    type Result = Result<serde_json::Value, Error>;
 }
 
 struct SerialExecutor{
-    tx: crossbeam_channel::Sender<msectrax_comms::ToDevice>,
-    rx: Arc<Mutex<crossbeam_channel::Receiver<msectrax_comms::FromDevice>>>,
+    tx: crossbeam_channel::Sender<Request>,
 }
 
 impl actix::Actor for SerialExecutor {
@@ -241,13 +642,16 @@ impl actix::Actor for SerialExecutor {
 }
 
 impl Handler<WrappedToDevice> for SerialExecutor {
-    type Result = Result<msectrax_comms::FromDevice, Error>;
+    type Result = Result<serde_json::Value, Error>;
 
     fn handle(&mut self, msg: WrappedToDevice, _: &mut Self::Context) -> Self::Result {
 
-        let rx = self.rx.lock(); // grab lock early to have exclusive control over serial thread.
-        // Keep lock for the the entire scope of this handle() call.
-        match self.tx.send(msg.to_device) {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        let request = Request {
+            to_device: msg.to_device,
+            reply_tx,
+        };
+        match self.tx.send(request) {
             Ok(()) => {},
             Err(e) => {
                 // If we have a serial error, stop the entire proxy program.
@@ -260,20 +664,26 @@ impl Handler<WrappedToDevice> for SerialExecutor {
             },
         }
 
-        // wait for response
-        let from_device = match rx.recv() {
-            Ok(fd) => fd,
+        // Wait for the response. The serial thread drops the reply channel if
+        // the device does not answer in time.
+        let reply = match reply_rx.recv() {
+            Ok(reply) => reply,
             Err(e) => {
-                // If we have a serial error, stop the entire proxy program.
-                error!("{}", e);
-                actix::System::current().stop_with_code(1);
+                error!("no reply from device");
                 let e2 = actix_web::error::InternalError::new(
                     e,
-                    actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+                    actix_web::http::StatusCode::GATEWAY_TIMEOUT);
                 return Err(e2.into());
             }
         };
-        Ok(from_device)
+        if let msectrax_comms::FromDevice::Error { code, detail } = reply.msg {
+            error!("device error in reply: {:?} (detail {})", code, detail);
+            let e2 = actix_web::error::InternalError::new(
+                format!("device error: {:?} (detail {})", code, detail),
+                actix_web::http::StatusCode::BAD_GATEWAY);
+            return Err(e2.into());
+        }
+        Ok(reply.json)
     }
 }
 
@@ -283,13 +693,92 @@ fn handle_http_post((item, state): (Json<msectrax_comms::ToDevice>, State<AppSta
             to_device: item.into_inner(),
         })
         .from_err()
-        .and_then(|res| match res {
-            Ok(from_dev) => Ok(HttpResponse::Ok().json(from_dev)),
-            Err(_) => Ok(HttpResponse::InternalServerError().into()),
+        .and_then(|res| res.map(|from_dev| HttpResponse::Ok().json(from_dev)))
+        .responder()
+}
+
+/// Return all streamed sample batches received since the last call.
+fn get_samples(req: &HttpRequest<AppState>) -> Result<HttpResponse,Error> {
+    let batches: Vec<serde_json::Value> = req.state().samples.lock().drain(..).collect();
+    Ok(HttpResponse::Ok().json(batches))
+}
+
+/// Return all trigger input and sync output events received since the last
+/// call, oldest first.
+fn get_events(req: &HttpRequest<AppState>) -> Result<HttpResponse,Error> {
+    let events: Vec<serde_json::Value> = req.state().events.lock().drain(..).collect();
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Query the results of `DeviceMode::FrequencySweep` and return the points
+/// measured so far, as JSON or as CSV with `?format=csv`.
+fn get_bode(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let csv = req.query_string().split('&').any(|param| param == "format=csv");
+    req.state().serial_executor
+        .send(WrappedToDevice {
+            to_device: msectrax_comms::ToDevice::QuerySweep,
         })
+        .from_err()
+        .and_then(move |res| res.and_then(|reply| {
+            let table = match bode_table(&reply) {
+                Some(table) => table,
+                None => {
+                    let e2 = actix_web::error::InternalError::new(
+                        "unexpected reply to QuerySweep",
+                        actix_web::http::StatusCode::BAD_GATEWAY);
+                    return Err(e2.into());
+                }
+            };
+            if csv {
+                Ok(HttpResponse::Ok().content_type("text/csv").body(bode_csv(&table)))
+            } else {
+                Ok(HttpResponse::Ok().json(table))
+            }
+        }))
         .responder()
 }
 
+/// The `EchoSweep` reply without the points which are not measured yet.
+///
+/// Values which could not be measured are NaN on the device and `null` in
+/// the JSON.
+fn bode_table(reply: &serde_json::Value) -> Option<serde_json::Value> {
+    let result = reply.get("EchoSweep")?;
+    let n_done = result.get("n_done")?.as_u64()? as usize;
+    let points: Vec<serde_json::Value> = result.get("points")?.as_array()?
+        .iter().take(n_done).cloned().collect();
+    Some(serde_json::json!({
+        "n_done": n_done,
+        "n_frequencies": result.get("n_frequencies")?,
+        "points": points,
+    }))
+}
+
+/// The points of a `bode_table` as CSV, with `NaN` for missing values.
+fn bode_csv(table: &serde_json::Value) -> String {
+    let mut csv = BODE_COLUMNS.join(",");
+    csv.push('\n');
+    let points = table["points"].as_array().map(|points| points.as_slice()).unwrap_or(&[]);
+    for point in points {
+        let row: Vec<String> = BODE_COLUMNS.iter().map(|column| match point[*column].as_f64() {
+            // The device sends f32, print it as such.
+            Some(value) => (value as f32).to_string(),
+            None => "NaN".to_string(),
+        }).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Return the identity of the connected device, as read at startup.
+fn get_device_info(req: &HttpRequest<AppState>) -> Result<HttpResponse,Error> {
+    match req.state().device_info.lock().clone() {
+        Some(info) => Ok(HttpResponse::Ok().json(info)),
+        None => Ok(HttpResponse::ServiceUnavailable().body("device info not yet received")),
+    }
+}
+
 const INDEX_HTML: &'static [u8] = include_bytes!("../msectrax-bui-frontend/dist/index.html");
 const STYLE_CSS: &'static [u8] = include_bytes!("../msectrax-bui-frontend/dist/style.css");
 const FRONTEND_JS: &'static [u8] = include_bytes!("../msectrax-bui-frontend/dist/msectrax-bui-frontend.js");
@@ -336,14 +825,18 @@ fn main() -> MyResult<()> {
     let logging = args.logging;
 
     let (tx, rx) = crossbeam_channel::bounded(100);
-    let (from_device_tx, from_device_rx) = crossbeam_channel::bounded(100);
-    let rx_arc = Arc::new(Mutex::new(from_device_rx));
+    let samples: SampleStore = Arc::new(Mutex::new(VecDeque::new()));
+    let thread_samples = samples.clone();
+    let events: EventStore = Arc::new(Mutex::new(VecDeque::new()));
+    let thread_events = events.clone();
+    let device_info: DeviceInfoStore = Arc::new(Mutex::new(None));
+    let thread_device_info = device_info.clone();
 
     let thread_builder = std::thread::Builder::new()
         .name("comms".to_string());
     let (flag, control) = thread_control::make_pair();
     let _thread_handle = thread_builder.spawn(move || {
-        let mut x = SerialThread::new(&args.device, rx, from_device_tx).expect("new");
+        let mut x = SerialThread::new(&args.device, rx, thread_samples, thread_events, thread_device_info).expect("new");
         x.run(flag).expect("run");
     })?;
 
@@ -357,16 +850,21 @@ fn main() -> MyResult<()> {
 
     let sys = actix::System::new("msectrax-proxy");
 
-    // Start 1 serial executor
-    let addr = SyncArbiter::start(1, move || {
+    // Start serial executors. Replies are matched to requests by id, so
+    // several requests can be in flight at once.
+    let addr = SyncArbiter::start(N_SERIAL_EXECUTORS, move || {
         let tx = tx.clone();
-        let rx = rx_arc.clone();
-        SerialExecutor{ tx, rx }
+        SerialExecutor{ tx }
     });
 
     // Start http server
     server::new(move || {
-        let state = AppState{serial_executor: addr.clone()};
+        let state = AppState{
+            serial_executor: addr.clone(),
+            samples: samples.clone(),
+            events: events.clone(),
+            device_info: device_info.clone(),
+        };
 
         let app = App::with_state(state);
         let app: App<AppState> = match logging {
@@ -375,6 +873,10 @@ fn main() -> MyResult<()> {
         };
         app
             .resource("/callback", |r| r.method(http::Method::POST).with(handle_http_post))
+            .resource("/samples", |r| r.method(http::Method::GET).f(get_samples))
+            .resource("/events", |r| r.method(http::Method::GET).f(get_events))
+            .resource("/bode", |r| r.method(http::Method::GET).f(get_bode))
+            .resource("/device-info", |r| r.method(http::Method::GET).f(get_device_info))
             .resource("/", |r| r.method(http::Method::GET).f(index_html))
             .resource("/index.html", |r| r.method(http::Method::GET).f(index_html))
             .resource("/style.css", |r| r.method(http::Method::GET).f(style_css))
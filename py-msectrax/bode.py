@@ -0,0 +1,63 @@
+import argparse
+import datetime
+import os
+import time
+import requests
+
+url = "http://127.0.0.1:8080/callback"
+bode_url = "http://127.0.0.1:8080/bode"
+
+MAX_SWEEP_POINTS = 16 # msectrax_comms::MAX_SWEEP_POINTS
+
+parser = argparse.ArgumentParser(description="Measure the frequency response from a galvo to the QPD.")
+parser.add_argument("--axis", default="Dac1", choices=["Dac1", "Dac2"])
+parser.add_argument("--response", default="Adc1", choices=["Adc1", "Adc2", "Sum"])
+parser.add_argument("--amplitude", type=int, default=1000, help="in DAC counts")
+parser.add_argument("--min-frequency", type=float, default=10.0, help="in Hz")
+parser.add_argument("--max-frequency", type=float, default=3000.0, help="in Hz")
+parser.add_argument("--settle-periods", type=int, default=5)
+parser.add_argument("--measure-periods", type=int, default=20)
+args = parser.parse_args()
+
+def post(data):
+    r = requests.post(url=url, json=data)
+    r.raise_for_status()
+    return r.json()
+
+# logarithmically spaced frequencies
+ratio = args.max_frequency/args.min_frequency
+frequencies = [args.min_frequency*ratio**(i/(MAX_SWEEP_POINTS-1)) for i in range(MAX_SWEEP_POINTS)]
+
+# keep the rest of the current settings
+inner = post("QueryState")["EchoState"]["inner"]
+inner["mode"] = {"FrequencySweep": {
+    "axis": args.axis,
+    "response": args.response,
+    "amplitude": args.amplitude,
+    "frequencies_hz": frequencies,
+    "n_frequencies": len(frequencies),
+    "settle_periods": args.settle_periods,
+    "measure_periods": args.measure_periods,
+}}
+post({"SetState": inner})
+
+while True:
+    r = requests.get(bode_url)
+    r.raise_for_status()
+    table = r.json()
+    print("measured %d of %d frequencies"%(table['n_done'], table['n_frequencies']))
+    if table['n_done'] == table['n_frequencies']:
+        break
+    time.sleep(1.0)
+
+r = requests.get(bode_url, params={"format": "csv"})
+r.raise_for_status()
+
+outputFilePath = os.path.join(os.path.dirname(__file__),
+                 datetime.datetime.now().strftime("bode-%Y-%m-%dT%H.%M.%S") + ".csv")
+with open(outputFilePath, mode='w') as outputfile:
+    outputfile.write("# saved by bode.py, %s to %s\n"%(args.axis, args.response))
+    outputfile.write(r.text)
+
+print(r.text)
+print("saved to %s"%outputFilePath)
@@ -0,0 +1,63 @@
+import requests
+import time
+import datetime
+import os
+from tzlocal import get_localzone # $ pip install tzlocal
+
+url = "http://127.0.0.1:8080/callback"
+
+config = {
+    # one of "Immediate", "ModeChange", "LockLoss" or
+    # {"AdcThreshold": {"channel": "Adc1", "level": 2048, "edge": "Rising"}}
+    "trigger": "LockLoss",
+    "pre_trigger": 200, # number of samples to keep from before the trigger
+    "decimation": 1, # record every n-th sample of the control loop
+}
+
+save_cols = ('index','dac1','dac2','adc1','adc2')
+
+r = requests.post(url=url, json={"ArmCapture": config})
+r.raise_for_status()
+print("armed, waiting for trigger")
+
+while True:
+    r = requests.post(url=url, json="QueryCaptureStatus")
+    r.raise_for_status()
+    status = r.json()['EchoCaptureStatus']
+    if status['state'] == 'Done':
+        break
+    time.sleep(0.1)
+
+print(status)
+
+samples = []
+offset = 0
+while offset < status['n_samples']:
+    r = requests.post(url=url, json={"ReadCapture": {"offset": offset}})
+    r.raise_for_status()
+    chunk = r.json()['EchoCaptureChunk']
+    samples.extend(chunk['samples'])
+    offset += len(chunk['samples'])
+samples = samples[:status['n_samples']]
+
+outputFilePath = os.path.join(os.path.dirname(__file__),
+                 datetime.datetime.now().strftime("capture-%Y-%m-%dT%H.%M.%S") + ".csv")
+outputfile = open(outputFilePath, mode='wb')
+
+# write a comment line
+now = datetime.datetime.now(get_localzone())
+outdatastr = "# saved by capture.py at %s, trigger at index %d, decimation %d"%(
+    now.isoformat('T'), status['trigger_index'], config['decimation']) + "\n"
+outputfile.write(outdatastr.encode())
+
+# write CSV header
+outdatastr = ",".join(colname for colname in save_cols) + "\n"
+outputfile.write(outdatastr.encode())
+
+for i, sample in enumerate(samples):
+    # index relative to the trigger
+    sample['index'] = i - status['trigger_index']
+    outdatastr = ",".join(str(sample[colname]) for colname in save_cols) + "\n"
+    outputfile.write(outdatastr.encode())
+
+print("saved %d samples to %s"%(len(samples), outputFilePath))
@@ -6,32 +6,56 @@ url = "http://127.0.0.1:8080/callback"
 data = {
     "SetState": {
         "mode": {"ClosedLoop":"Proportional"},
-        "cl_cycles": 0,
-        "cl_period": 10, # integer range 1 (no delay) to u32 maximum
-        "dac1": 0,
-        "dac2": 0,
-        "adc1": 0,
-        "adc2": 0,
-        "dac1_f32": 0.0,
-        "dac2_f32": 0.0,
-        "dac1_angle_func": {
-            "adc1_offset": 0,
+        "loop_rate_hz": 10000, # control loop (ADC sampling) rate
+        "cl_period_us": 1000, # closed loop update period, at least one loop tick
+        "adc_oversampling": 1, # ADC conversions averaged per loop tick, 1 to 64
+        "dac1_initial": 0,
+        "dac2_initial": 0,
+        "dac1_angle_func": {"Linear": {
             "adc1_gain": 0.0,
-            "adc2_offset": -1954,
             "adc2_gain": 1.0/0.238,
-        },
-        "dac2_angle_func": {
-            "adc1_offset": -2065,
+            "offset": -1954/0.238,
+        }},
+        "dac2_angle_func": {"Linear": {
             "adc1_gain": 1.0/-0.22,
-            "adc2_offset": 0,
             "adc2_gain": 0.0,
-        },
+            "offset": -2065/-0.22,
+        }},
         "dac1_angle_gain": 1e-3,
         "dac2_angle_gain": 1e-3,
+        "sum_normalization": None, # or scale adc1 and adc2 by the QPD sum, see SumNormalization
         "dac1_min": -32768,
         "dac1_max": 32767,
         "dac2_min": -32768,
         "dac2_max": 32767,
+        "target_loss": { # lost when |adc1|+|adc2| < threshold for lost_after_us
+            "threshold": 0, # 0 disables target loss detection
+            "lost_after_us": 10000,
+            "action": "Hold", # or "ReturnToInitial" or {"Search": {...}}
+        },
+        "output_limits": { # 0 is unlimited
+            "dac1_max_slew": 0, # counts per loop tick
+            "dac2_max_slew": 0,
+            "dac1_max_accel": 0, # counts per loop tick per tick
+            "dac2_max_accel": 0,
+        },
+        "heartbeat": { # park the galvos when the proxy stops sending
+            "timeout_ms": 1000, # 0 disables the timeout
+            "dac1_park": 0,
+            "dac2_park": 0,
+            "park_slew": 100, # counts per loop tick, 0 keeps output_limits
+        },
+        "aux": { # third DAC, e.g. laser power
+            "mode": "Disabled", # or "Manual" or "LockGated"
+            "dac3": 0,
+            "dac3_off": 0, # output in LockGated mode while not locked
+        },
+        "sync": { # camera trigger input (PA0) and sync output (PA1)
+            "trigger_edge": None, # or "Rising" or "Falling", fetch from /events
+            "pulse_on": "Never", # or "LockAcquired", "LockLost", {"EveryNTicks": n}
+            "pulse_us": 100,
+            "report_pulses": False,
+        },
     }
 }
 
@@ -45,7 +69,7 @@ while 1:
     r.raise_for_status()
     print(r.json())
 
-    query_data = "QueryActualCycles"
+    query_data = "QueryTiming"
     r = requests.post(url=url, json=query_data)
     r.raise_for_status()
     print(r.json())
@@ -18,25 +18,56 @@ if headstage=="headstage1":
     data = {
         "SetState": {
             "mode": {"ClosedLoop":"Proportional"},
-            "cl_period": 1, # integer range 1 (no delay) to u32 maximum
+            "loop_rate_hz": 10000, # control loop (ADC sampling) rate
+            "cl_period_us": 100, # closed loop update period, at least one loop tick
+            "adc_oversampling": 1, # ADC conversions averaged per loop tick, 1 to 64
             "dac1_initial": -11093,
             "dac2_initial": 8853,
-            "dac1_angle_func": {
+            "dac1_angle_func": {"Linear": {
                 "adc1_gain": -0.010447744188452558,
                 "adc2_gain": 0.6793280985084404,
                 "offset": -13388.874373535962,
-            },
-            "dac2_angle_func": {
+            }},
+            "dac2_angle_func": {"Linear": {
                 "adc1_gain": 0.8250760411287147,
                 "adc2_gain": 0.10317298178375989,
                 "offset": 6524.860850191762,
-            },
+            }},
             "dac1_angle_gain": -0.02,
             "dac2_angle_gain": -0.02,
+            "sum_normalization": None, # or scale adc1 and adc2 by the QPD sum, see SumNormalization
             "dac1_min": -32768,
             "dac1_max": 32767,
             "dac2_min": -32768,
             "dac2_max": 32767,
+            "target_loss": { # lost when |adc1|+|adc2| < threshold for lost_after_us
+                "threshold": 0, # 0 disables target loss detection
+                "lost_after_us": 10000,
+                "action": "Hold", # or "ReturnToInitial" or {"Search": {...}}
+            },
+            "output_limits": { # 0 is unlimited
+                "dac1_max_slew": 0, # counts per loop tick
+                "dac2_max_slew": 0,
+                "dac1_max_accel": 0, # counts per loop tick per tick
+                "dac2_max_accel": 0,
+            },
+            "heartbeat": { # park the galvos when the proxy stops sending
+                "timeout_ms": 1000, # 0 disables the timeout
+                "dac1_park": 0,
+                "dac2_park": 0,
+                "park_slew": 100, # counts per loop tick, 0 keeps output_limits
+            },
+            "aux": { # third DAC, e.g. laser power
+                "mode": "Disabled", # or "Manual" or "LockGated"
+                "dac3": 0,
+                "dac3_off": 0, # output in LockGated mode while not locked
+            },
+            "sync": { # camera trigger input (PA0) and sync output (PA1)
+                "trigger_edge": None, # or "Rising" or "Falling", fetch from /events
+                "pulse_on": "Never", # or "LockAcquired", "LockLost", {"EveryNTicks": n}
+                "pulse_us": 100,
+                "report_pulses": False,
+            },
         }
     }
 
@@ -53,25 +84,56 @@ if headstage=="headstage2":
     data = {
         "SetState": {
             "mode": {"ClosedLoop":"Proportional"},
-            "cl_period": 1, # integer range 1 (no delay) to u32 maximum
+            "loop_rate_hz": 10000, # control loop (ADC sampling) rate
+            "cl_period_us": 100, # closed loop update period, at least one loop tick
+            "adc_oversampling": 1, # ADC conversions averaged per loop tick, 1 to 64
             "dac1_initial": -4386,
             "dac2_initial": -57,
-            "dac1_angle_func": {
+            "dac1_angle_func": {"Linear": {
                 "adc1_gain": -0.0638002222554458,
                 "adc2_gain": 0.5873149806393063,
                 "offset": -4811.105807700699,
-            },
-            "dac2_angle_func": {
+            }},
+            "dac2_angle_func": {"Linear": {
                 "adc1_gain": 0.9463829955512771,
                 "adc2_gain": -0.09913784171019957,
                 "offset": -7760.781758781409,
-            },
+            }},
             "dac1_angle_gain": -0.02,
             "dac2_angle_gain": -0.02,
+            "sum_normalization": None, # or scale adc1 and adc2 by the QPD sum, see SumNormalization
             "dac1_min": -32768,
             "dac1_max": 32767,
             "dac2_min": -32768,
             "dac2_max": 32767,
+            "target_loss": { # lost when |adc1|+|adc2| < threshold for lost_after_us
+                "threshold": 0, # 0 disables target loss detection
+                "lost_after_us": 10000,
+                "action": "Hold", # or "ReturnToInitial" or {"Search": {...}}
+            },
+            "output_limits": { # 0 is unlimited
+                "dac1_max_slew": 0, # counts per loop tick
+                "dac2_max_slew": 0,
+                "dac1_max_accel": 0, # counts per loop tick per tick
+                "dac2_max_accel": 0,
+            },
+            "heartbeat": { # park the galvos when the proxy stops sending
+                "timeout_ms": 1000, # 0 disables the timeout
+                "dac1_park": 0,
+                "dac2_park": 0,
+                "park_slew": 100, # counts per loop tick, 0 keeps output_limits
+            },
+            "aux": { # third DAC, e.g. laser power
+                "mode": "Disabled", # or "Manual" or "LockGated"
+                "dac3": 0,
+                "dac3_off": 0, # output in LockGated mode while not locked
+            },
+            "sync": { # camera trigger input (PA0) and sync output (PA1)
+                "trigger_edge": None, # or "Rising" or "Falling", fetch from /events
+                "pulse_on": "Never", # or "LockAcquired", "LockLost", {"EveryNTicks": n}
+                "pulse_us": 100,
+                "report_pulses": False,
+            },
         }
     }
 
@@ -93,7 +155,7 @@ while 1:
     elapsed=time.monotonic_ns()-start_time # num nanoseconds elapsed (int)
 
     cycles_per_sec = state['cl_cycles'] / (elapsed*1e-9)
-    cl_cycles_per_second = cycles_per_sec / float(state['inner']['cl_period'])
+    cl_cycles_per_second = 1e6 / float(state['inner']['cl_period_us'])
     loop_time = 1.0/cl_cycles_per_second
     print('cycle_rate: {:1g} Hz, cl_rate: {:1g} Hz, loop_time: {:1g} usec'.format(cycles_per_sec, cl_cycles_per_second, loop_time*1e6))
     print('    ADC1: {: 8d}     ADC2: {: 8d}       DAC1: {: 8d}     DAC2: {: 8d}'.format(state['adc1'], state['adc2'], state['dac1'], state['dac2']))
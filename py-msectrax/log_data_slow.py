@@ -8,7 +8,7 @@ from tzlocal import get_localzone # $ pip install tzlocal
 
 url = "http://127.0.0.1:8080/callback"
 
-save_cols = ('timestamp','dac1','dac2','adc1','adc2')
+save_cols = ('timestamp','time_us','dac1','dac2','adc1','adc2') # time_us: device clock at acquisition
 
 outputFilePath = os.path.join(os.path.dirname(__file__),
                  datetime.datetime.now().strftime("log-%Y-%m-%dT%H.%M.%S") + ".csv")
@@ -0,0 +1,48 @@
+import requests
+import time
+import datetime
+import os
+from tzlocal import get_localzone # $ pip install tzlocal
+
+url = "http://127.0.0.1:8080/callback"
+samples_url = "http://127.0.0.1:8080/samples"
+
+decimation = 16 # save every n-th sample of the control loop, at least 16 at 10 kHz for the serial link to keep up
+
+save_cols = ('counter','time_us','dac1','dac2','adc1','adc2') # time_us: device clock at acquisition
+
+outputFilePath = os.path.join(os.path.dirname(__file__),
+                 datetime.datetime.now().strftime("log-%Y-%m-%dT%H.%M.%S") + ".csv")
+outputfile = open(outputFilePath, mode='wb')
+
+# write a comment line
+now = datetime.datetime.now(get_localzone())
+outdatastr = "# saved by log_data_stream.py at %s"%(now.isoformat('T'),) + "\n"
+outputfile.write(outdatastr.encode())
+
+# write CSV header
+outdatastr = ",".join(colname for colname in save_cols) + "\n"
+outputfile.write(outdatastr.encode())
+
+# discard anything left over from a previous stream
+r = requests.post(url=url, json="StopStream")
+r.raise_for_status()
+requests.get(url=samples_url).raise_for_status()
+
+r = requests.post(url=url, json={"StartStream": {"decimation": decimation}})
+r.raise_for_status()
+
+# loop until interrupted and save data
+try:
+    while True:
+        r = requests.get(url=samples_url)
+        r.raise_for_status()
+        for batch in r.json():
+            for i, sample in enumerate(batch['samples']):
+                sample['counter'] = batch['counter'] + i*batch['decimation']
+                outdatastr = ",".join(str(sample[colname]) for colname in save_cols) + "\n"
+                outputfile.write(outdatastr.encode())
+        time.sleep(0.1)
+finally:
+    r = requests.post(url=url, json="StopStream")
+    r.raise_for_status()
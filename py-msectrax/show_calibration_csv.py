@@ -16,34 +16,91 @@ matplotlib.rcParams.update({'font.size': 6})
 my_template = Template("""data = {
     "SetState": {
         "mode": {"ClosedLoop":"Proportional"},
-        "cl_period": 1, # integer range 1 (no delay) to u32 maximum
+        "loop_rate_hz": 10000, # control loop (ADC sampling) rate
+        "cl_period_us": 100, # closed loop update period, at least one loop tick
+        "adc_oversampling": 1, # ADC conversions averaged per loop tick, 1 to 64
         "dac1_initial": -11093,
         "dac2_initial": 8853,
-        "dac1_angle_func": {
-            "adc1_gain": $dac1_adc1_gain,
-            "adc2_gain": $dac1_adc2_gain,
-            "offset": $dac1_offset,
-        },
-        "dac2_angle_func": {
-            "adc1_gain": $dac2_adc1_gain,
-            "adc2_gain": $dac2_adc2_gain,
-            "offset": $dac2_offset,
-        },
+        "dac1_angle_func": $dac1_angle_func,
+        "dac2_angle_func": $dac2_angle_func,
         "dac1_angle_gain": -0.02,
         "dac2_angle_gain": -0.02,
+        "sum_normalization": None, # or scale adc1 and adc2 by the QPD sum, see SumNormalization
         "dac1_min": -32768,
         "dac1_max": 32767,
         "dac2_min": -32768,
         "dac2_max": 32767,
+        "target_loss": { # lost when |adc1|+|adc2| < threshold for lost_after_us
+            "threshold": 0, # 0 disables target loss detection
+            "lost_after_us": 10000,
+            "action": "Hold", # or "ReturnToInitial" or {"Search": {...}}
+        },
+        "output_limits": { # 0 is unlimited
+            "dac1_max_slew": 0, # counts per loop tick
+            "dac2_max_slew": 0,
+            "dac1_max_accel": 0, # counts per loop tick per tick
+            "dac2_max_accel": 0,
+        },
+        "heartbeat": { # park the galvos when the proxy stops sending
+            "timeout_ms": 1000, # 0 disables the timeout
+            "dac1_park": 0,
+            "dac2_park": 0,
+            "park_slew": 100, # counts per loop tick, 0 keeps output_limits
+        },
+        "aux": { # third DAC, e.g. laser power
+            "mode": "Disabled", # or "Manual" or "LockGated"
+            "dac3": 0,
+            "dac3_off": 0, # output in LockGated mode while not locked
+        },
+        "sync": { # camera trigger input (PA0) and sync output (PA1)
+            "trigger_edge": None, # or "Rising" or "Falling", fetch from /events
+            "pulse_on": "Never", # or "LockAcquired", "LockLost", {"EveryNTicks": n}
+            "pulse_us": 100,
+            "report_pulses": False,
+        },
     }
 }
 """)
 
+linear_template = Template("""{"Linear": {
+            "adc1_gain": $adc1_gain,
+            "adc2_gain": $adc2_gain,
+            "offset": $offset,
+        }}""")
+
+polynomial_template = Template("""{"Polynomial": {
+            "adc1_center": $adc1_center,
+            "adc2_center": $adc2_center,
+            "coeffs": $coeffs,
+        }}""")
+
+# Number of polynomial terms used by each model. The firmware evaluates
+# [1, x, y, x^2, x*y, y^2, x^3, x^2*y, x*y^2, y^3] with x and y the ADC values
+# relative to the center.
+N_POLY_TERMS = {'poly2': 6, 'poly3': 10}
+
+def design_matrix(df, model, center):
+    if model == 'linear':
+        return np.vstack( (df['adc1'].values, df['adc2'].values, np.ones_like(df['adc1'].values) ) ).T
+    x = df['adc1'].values - center[0]
+    y = df['adc2'].values - center[1]
+    terms = [np.ones_like(x), x, y, x*x, x*y, y*y, x*x*x, x*x*y, x*y*y, y*y*y]
+    return np.vstack(terms[:N_POLY_TERMS[model]]).T
+
+def angle_func(p, model, center):
+    if model == 'linear':
+        return linear_template.substitute(adc1_gain=p[0], adc2_gain=p[1], offset=p[2])
+    coeffs = list(p) + [0.0]*(10-len(p))
+    return polynomial_template.substitute(adc1_center=center[0], adc2_center=center[1],
+        coeffs='[' + ', '.join(repr(float(c)) for c in coeffs) + ']')
+
 if 1:
     parser = argparse.ArgumentParser()
     parser.add_argument("csv_filename")
     parser.add_argument("--no-cal", help="do not perform calibration", action="store_true")
     parser.add_argument("--no-plot", help="do draw plots", action="store_true")
+    parser.add_argument("--model", help="calibration model", choices=['linear', 'poly2', 'poly3'], default='linear')
+    parser.add_argument("--full-range", help="fit all data, not only the central region", action="store_true")
     args = parser.parse_args()
 
     do_cal = not args.no_cal
@@ -84,7 +141,7 @@ if 1:
     print ('# Calib file:', fname)
     print ('# Central region:', minmax) 
 
-    if 1:
+    if not args.full_range:
         for dac_name in minmax:
             this_min, this_max = minmax[dac_name]
             df = df[(df[dac_name]>this_min) & (df[dac_name]<this_max)]
@@ -92,29 +149,30 @@ if 1:
     # Perform a linear least squares fit to find A for y = Ap where p is the
     # parameter vector to be fit, A is a matrix built of the ADC values (and ones)
     # and y is the angle value vector (in DAC units).
-    # Thus, angle = [adc1, adc2, 1.0] . [p[0], p[1], p[2]]
+    # Thus, angle = [adc1, adc2, 1.0] . [p[0], p[1], p[2]] for the linear model
+    # and the sum of the polynomial terms times p for the others.
 
     if do_cal:
-        A = np.vstack( (df['adc1'].values, df['adc2'].values, np.ones_like(df['adc1'].values) ) ).T
+        center = (float(df['adc1'].mean()), float(df['adc2'].mean()))
+        A = design_matrix(df, args.model, center)
         p_dac1_result = np.linalg.lstsq(A, df['dac1'].values, rcond=None)
         p_dac2_result = np.linalg.lstsq(A, df['dac2'].values, rcond=None)
 
         p_dac1 = p_dac1_result[0]
         p_dac2 = p_dac2_result[0]
 
-        angle1_str = '# Angle1 (DAC1) = {:3g}*ADC1 + {:3g}*ADC2 + {:3g}'.format(*p_dac1)
-        angle2_str = '# Angle2 (DAC2) = {:3g}*ADC1 + {:3g}*ADC2 + {:3g}'.format(*p_dac2)
+        if args.model == 'linear':
+            angle1_str = '# Angle1 (DAC1) = {:3g}*ADC1 + {:3g}*ADC2 + {:3g}'.format(*p_dac1)
+            angle2_str = '# Angle2 (DAC2) = {:3g}*ADC1 + {:3g}*ADC2 + {:3g}'.format(*p_dac2)
+        else:
+            angle1_str = '# Angle1 (DAC1) = {} polynomial {}'.format(args.model, p_dac1)
+            angle2_str = '# Angle2 (DAC2) = {} polynomial {}'.format(args.model, p_dac2)
         print(angle1_str)
         print(angle2_str)
         print()
         formatted = my_template.substitute(
-            dac1_adc1_gain=p_dac1[0],
-            dac1_adc2_gain=p_dac1[1],
-            dac1_offset=p_dac1[2],
-
-            dac2_adc1_gain=p_dac2[0],
-            dac2_adc2_gain=p_dac2[1],
-            dac2_offset=p_dac2[2],
+            dac1_angle_func=angle_func(p_dac1, args.model, center),
+            dac2_angle_func=angle_func(p_dac2, args.model, center),
         )
         print(formatted)
 
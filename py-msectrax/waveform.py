@@ -0,0 +1,52 @@
+import argparse
+import math
+import requests
+
+url = "http://127.0.0.1:8080/callback"
+
+WAVE_TABLE_SIZE = 256 # msectrax_comms::WAVE_TABLE_SIZE
+WAVE_TABLE_CHUNK_SIZE = 32 # msectrax_comms::WAVE_TABLE_CHUNK_SIZE
+
+parser = argparse.ArgumentParser(description="Drive the galvos with a test pattern.")
+parser.add_argument("--shape", default="Sine",
+    choices=["Sine", "Triangle", "Square", "Sawtooth", "Table"])
+parser.add_argument("--amplitude", type=int, default=5000, help="in DAC counts")
+parser.add_argument("--frequency", type=float, default=10.0, help="in Hz")
+parser.add_argument("--ratio", type=float, default=1.0,
+    help="frequency of DAC2 relative to DAC1, for Lissajous patterns")
+parser.add_argument("--phase", type=float, default=90.0,
+    help="phase of DAC2 relative to DAC1 in degrees")
+args = parser.parse_args()
+
+def post(data):
+    r = requests.post(url=url, json=data)
+    r.raise_for_status()
+    return r.json()
+
+if args.shape == "Table":
+    # an example table: a sine with its third harmonic
+    n = WAVE_TABLE_SIZE
+    table = [int(32767/1.5*(math.sin(2*math.pi*i/n) + 0.5*math.sin(6*math.pi*i/n))) for i in range(n)]
+    for offset in range(0, n, WAVE_TABLE_CHUNK_SIZE):
+        values = table[offset:offset+WAVE_TABLE_CHUNK_SIZE]
+        post({"WriteWaveTable": {"offset": offset, "values": values}})
+    shape = {"Table": {"len": n}}
+else:
+    shape = args.shape
+
+def axis(frequency, phase):
+    return {
+        "shape": shape,
+        "amplitude": args.amplitude,
+        "offset": 0,
+        "frequency_hz": frequency,
+        "phase_deg": phase,
+    }
+
+# keep the rest of the current settings
+inner = post("QueryState")["EchoState"]["inner"]
+inner["mode"] = {"Waveform": {
+    "dac1": axis(args.frequency, 0.0),
+    "dac2": axis(args.frequency*args.ratio, args.phase),
+}}
+print(post({"SetState": inner}))